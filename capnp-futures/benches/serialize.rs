@@ -0,0 +1,104 @@
+// Copyright (c) 2013-2016 Sandstorm Development Group, Inc. and contributors
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Throughput of `read_message()` and `write_message()` for a few representative shapes of
+//! message. Also prints how many allocations a round trip of each shape makes.
+//!
+//! Run with `cargo bench --bench serialize`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use capnp::{message, Word};
+use capnp_futures::serialize;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+/// Counts allocations, so that changes which avoid allocating can be measured too.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Returns the segments of each message shape to benchmark, along with its name.
+fn shapes() -> Vec<(&'static str, Vec<Vec<Word>>)> {
+    let word = capnp::word(1, 2, 3, 4, 5, 6, 7, 8);
+    vec![
+        ("single_tiny_segment", vec![vec![word; 1]]),
+        ("many_small_segments", (0..256).map(|_| vec![word; 8]).collect()),
+        ("one_huge_segment", vec![vec![word; 1 << 20]]),
+    ]
+}
+
+fn reader_options() -> message::ReaderOptions {
+    let mut options = message::ReaderOptions::new();
+    options.traversal_limit_in_words(::std::u64::MAX);
+    options
+}
+
+fn write(segments: &[&[Word]], buf: &mut Vec<u8>) {
+    buf.clear();
+    futures::executor::block_on(serialize::write_message(&mut *buf, segments)).unwrap();
+}
+
+fn read(buf: &[u8]) -> message::Reader<serialize::OwnedSegments> {
+    futures::executor::block_on(serialize::read_message(buf, reader_options())).unwrap().unwrap()
+}
+
+fn round_trip(segments: &[&[Word]], buf: &mut Vec<u8>) -> message::Reader<serialize::OwnedSegments> {
+    write(segments, buf);
+    read(&buf[..])
+}
+
+fn bench_serialize(c: &mut Criterion) {
+    for (name, segments) in shapes() {
+        let segments: Vec<&[Word]> = segments.iter().map(|s| &s[..]).collect();
+        let mut buf = Vec::new();
+        write(&segments, &mut buf);
+
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        black_box(round_trip(&segments, &mut buf));
+        println!("{}: {} allocations per round trip",
+                 name, ALLOCATIONS.load(Ordering::Relaxed) - before);
+
+        let mut group = c.benchmark_group(name);
+        group.throughput(Throughput::Bytes(buf.len() as u64));
+        group.bench_function("write_message", |b| b.iter(|| write(black_box(&segments), &mut buf)));
+        let frame = buf.clone();
+        group.bench_function("read_message", |b| b.iter(|| read(black_box(&frame[..]))));
+        group.bench_function("round_trip", |b| b.iter(|| round_trip(black_box(&segments), &mut buf)));
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_serialize);
+criterion_main!(benches);
@@ -0,0 +1,176 @@
+// Copyright (c) 2013-2016 Sandstorm Development Group, Inc. and contributors
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! Framing of messages in memory, with the `Buf` and `BufMut` traits of the `bytes` crate.
+
+use std::io::IoSlice;
+
+use bytes::{Buf, BufMut};
+use capnp::{message, Error, Result, Word};
+
+use crate::serialize::{check_total_words, parse_segment_lengths, parse_segment_table_first,
+                       segment_table_bytes, segment_table_len, serialized_size,
+                       AsOutputSegments, OwnedSegments};
+
+/// Parses a message out of `buf`, advancing `buf` past it.
+///
+/// Returns `None`, leaving `buf` unchanged, if `buf` does not yet hold a complete frame. This
+/// makes the function suitable for decoders that accumulate bytes until a whole frame has
+/// arrived. Returns an error if the segment table is malformed or the message is too large
+/// under `options`.
+pub fn read_message_from_buf<B>(buf: &mut B, options: message::ReaderOptions)
+                                -> Result<Option<message::Reader<OwnedSegments>>>
+    where B: Buf
+{
+    let mut first = [0; 8];
+    if !peek(buf, &mut first)? {
+        return Ok(None)
+    }
+    let (segment_count, first_segment_length) = parse_segment_table_first(&first)?;
+    let table_len = segment_table_len(segment_count);
+    let mut table = vec![0; table_len];
+    if !peek(buf, &mut table)? {
+        return Ok(None)
+    }
+
+    let mut segment_slices = Vec::with_capacity(segment_count);
+    segment_slices.push((0, first_segment_length));
+    let total_words = parse_segment_lengths(&table[8..], segment_count - 1,
+                                            &mut segment_slices, first_segment_length)?;
+    check_total_words(total_words, options)?;
+    if buf.remaining() - table_len < total_words * 8 {
+        return Ok(None)
+    }
+
+    buf.advance(table_len);
+    let mut owned_space = Word::allocate_zeroed_vec(total_words);
+    buf.copy_to_slice(Word::words_to_bytes_mut(&mut owned_space));
+    Ok(Some(message::Reader::new(OwnedSegments::new(owned_space, segment_slices), options)))
+}
+
+/// Copies the first `out.len()` bytes of `buf` into `out` without advancing `buf`. Returns
+/// false if `buf` has fewer bytes than that.
+///
+/// The bytes may be split across any number of the chunks that `buf` reports through
+/// `Buf::bytes_vectored()`.
+fn peek<B>(buf: &B, out: &mut [u8]) -> Result<bool> where B: Buf {
+    if buf.remaining() < out.len() {
+        return Ok(false)
+    }
+    if buf.bytes().len() >= out.len() {
+        out.copy_from_slice(&buf.bytes()[..out.len()]);
+        return Ok(true)
+    }
+
+    // Every chunk holds at least one byte, so `out.len()` slices always reach far enough.
+    let mut chunks = vec![IoSlice::new(&[]); out.len()];
+    let count = buf.bytes_vectored(&mut chunks);
+    let mut filled = 0;
+    for chunk in &chunks[..count] {
+        let n = ::std::cmp::min(chunk.len(), out.len() - filled);
+        out[filled..filled + n].copy_from_slice(&chunk[..n]);
+        filled += n;
+        if filled == out.len() {
+            return Ok(true)
+        }
+    }
+    Err(Error::failed("Buffer does not report all of its chunks through bytes_vectored()".to_string()))
+}
+
+/// Writes the provided message to `buf`. Returns an error, without writing anything, if `buf`
+/// does not have room for the whole frame.
+pub fn write_message_to_buf<B, M>(buf: &mut B, message: M) -> Result<()>
+    where B: BufMut, M: AsOutputSegments
+{
+    let frame_len = serialized_size(&message);
+    if buf.remaining_mut() < frame_len {
+        return Err(Error::failed(
+            format!("Message of {} bytes does not fit in a buffer with room for {}",
+                    frame_len, buf.remaining_mut())))
+    }
+    let segments = message.as_output_segments();
+    let table = segment_table_bytes(&segments[..])?;
+    buf.put_slice(&table);
+    for segment in &segments[..] {
+        buf.put_slice(Word::words_to_bytes(segment));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Buf;
+    use bytes::buf::BufExt;
+    use capnp::message;
+    use capnp::message::ReaderSegments;
+
+    use super::{read_message_from_buf, write_message_to_buf};
+
+    #[test]
+    fn round_trip_through_buf() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2], vec![capnp::word(2,0,0,0,0,0,0,0); 1]];
+        let mut buf = Vec::new();
+        write_message_to_buf(&mut buf, &segments).unwrap();
+        write_message_to_buf(&mut buf, &segments).unwrap();
+        let frame_len = buf.len() / 2;
+
+        // An incomplete frame leaves the buffer alone.
+        for &len in &[0, 4, 8, frame_len - 1] {
+            let mut partial = &buf[..len];
+            assert!(read_message_from_buf(&mut partial, message::ReaderOptions::new()).unwrap().is_none());
+            assert_eq!(len, partial.len());
+        }
+
+        // Frames can be split across chunks.
+        let (a, b) = buf.split_at(5);
+        let mut chained = a.chain(b);
+        for _ in 0..2 {
+            let message = read_message_from_buf(&mut chained, message::ReaderOptions::new())
+                .unwrap().unwrap();
+            let message_segments = message.into_segments();
+            assert_eq!(&segments[0][..], message_segments.get_segment(0).unwrap());
+            assert_eq!(&segments[1][..], message_segments.get_segment(1).unwrap());
+        }
+        assert_eq!(0, chained.remaining());
+        assert!(read_message_from_buf(&mut chained, message::ReaderOptions::new()).unwrap().is_none());
+    }
+
+    #[test]
+    fn segment_table_split_across_many_chunks() {
+        // 40 segments need a 168-byte segment table, which arrives here in 3-byte chunks.
+        let segments: Vec<Vec<capnp::Word>> =
+            (0..40).map(|i| vec![capnp::word(i as u8,0,0,0,0,0,0,0); 1]).collect();
+        let mut frame = Vec::new();
+        write_message_to_buf(&mut frame, &segments).unwrap();
+
+        let mut chained: Box<dyn Buf> = Box::new(&frame[..0]);
+        for piece in frame.chunks(3) {
+            chained = Box::new(chained.chain(piece));
+        }
+        let message = read_message_from_buf(&mut chained, message::ReaderOptions::new())
+            .unwrap().unwrap();
+        let message_segments = message.into_segments();
+        for (i, segment) in segments.iter().enumerate() {
+            assert_eq!(&segment[..], message_segments.get_segment(i as u32).unwrap());
+        }
+        assert_eq!(0, chained.remaining());
+    }
+}
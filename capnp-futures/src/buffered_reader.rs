@@ -0,0 +1,639 @@
+// Copyright (c) 2013-2016 Sandstorm Development Group, Inc. and contributors
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use futures::AsyncRead;
+
+use capnp::{message, Error, Result, Word};
+
+use crate::serialize::{check_frame_checksum, check_total_words, parse_segment_lengths,
+                       parse_segment_table_first, read_body_part, read_retrying,
+                       segment_table_len, truncated_body, OwnedSegments};
+
+/// The default minimum number of bytes requested from the underlying reader at a time.
+const DEFAULT_READ_SIZE: usize = 8 * 1024;
+
+/// The default largest frame that `read_message_recoverable()` waits for while skipping
+/// damaged data.
+const DEFAULT_MAX_RESYNC_FRAME_BYTES: usize = 64 * 1024;
+
+/// Parameters for adapting the read size of a `BufferedMessageReader` to the sizes of the
+/// frames it reads.
+///
+/// The reader keeps an exponential moving average of frame sizes, in bytes. After each frame,
+/// the average moves toward that frame's size by the fraction `smoothing`, and the read size
+/// becomes `frames_ahead` times the average, clamped to between `min_read_size` and
+/// `max_read_size`.
+#[derive(Clone, Copy, Debug)]
+pub struct AdaptiveReadSize {
+    /// How strongly each new frame affects the average, between 0 (not at all) and 1 (the
+    /// average is just the size of the latest frame).
+    pub smoothing: f64,
+
+    /// How many average-sized frames to try to read at a time.
+    pub frames_ahead: usize,
+
+    pub min_read_size: usize,
+    pub max_read_size: usize,
+}
+
+const DEFAULT_ADAPTIVE_READ_SIZE: AdaptiveReadSize =
+    AdaptiveReadSize { smoothing: 0.25, frames_ahead: 4,
+                       min_read_size: 512, max_read_size: 1024 * 1024 };
+
+impl Default for AdaptiveReadSize {
+    fn default() -> AdaptiveReadSize {
+        DEFAULT_ADAPTIVE_READ_SIZE
+    }
+}
+
+impl AdaptiveReadSize {
+    pub fn new() -> AdaptiveReadSize { DEFAULT_ADAPTIVE_READ_SIZE }
+
+    pub fn smoothing<'a>(&'a mut self, value: f64) -> &'a mut AdaptiveReadSize {
+        self.smoothing = value;
+        self
+    }
+
+    pub fn frames_ahead<'a>(&'a mut self, value: usize) -> &'a mut AdaptiveReadSize {
+        self.frames_ahead = value;
+        self
+    }
+
+    pub fn min_read_size<'a>(&'a mut self, value: usize) -> &'a mut AdaptiveReadSize {
+        self.min_read_size = value;
+        self
+    }
+
+    pub fn max_read_size<'a>(&'a mut self, value: usize) -> &'a mut AdaptiveReadSize {
+        self.max_read_size = value;
+        self
+    }
+}
+
+/// Segments that borrow their contents from the buffer of a `BufferedMessageReader`. See
+/// `BufferedMessageReader::peek_message()`.
+pub struct BorrowedSegments<'a> {
+    words: &'a [Word],
+    segment_slices: Vec<(usize, usize)>,
+}
+
+impl <'a> message::ReaderSegments for BorrowedSegments<'a> {
+    fn get_segment<'b>(&'b self, id: u32) -> Option<&'b [Word]> {
+        if id < self.segment_slices.len() as u32 {
+            let (a, b) = self.segment_slices[id as usize];
+            Some(&self.words[a..b])
+        } else {
+            None
+        }
+    }
+}
+
+/// Reads messages from an `AsyncRead`, reading ahead into an internal buffer so that
+/// consecutive small messages can often be served without touching the underlying reader.
+///
+/// Whenever more data is needed, at least `read_size` bytes are requested. Message bodies that
+/// extend past the buffered data are read directly into the returned message's segments.
+pub struct BufferedMessageReader<R> where R: AsyncRead + Unpin {
+    reader: R,
+    options: message::ReaderOptions,
+
+    /// Holds buffered data. Frames always start at a word boundary of this buffer.
+    buf: Vec<Word>,
+
+    /// Byte offset of the first unconsumed byte in `buf`.
+    start: usize,
+
+    /// Byte offset just past the last buffered byte in `buf`.
+    end: usize,
+
+    read_size: usize,
+
+    /// If set, `read_size` is adjusted after each frame.
+    adaptive: Option<AdaptiveReadSize>,
+
+    /// Moving average of the sizes of the frames read so far, in bytes.
+    average_frame_len: Option<f64>,
+
+    /// See `set_max_resync_frame_bytes()`.
+    max_resync_frame_bytes: usize,
+
+    /// Whether the underlying reader has reported the end of the stream.
+    eof: bool,
+}
+
+impl <R> BufferedMessageReader<R> where R: AsyncRead + Unpin {
+    /// Creates a reader that requests at least 8 KiB whenever it needs more data.
+    pub fn new(reader: R, options: message::ReaderOptions) -> Self {
+        BufferedMessageReader::with_read_size(reader, options, DEFAULT_READ_SIZE)
+    }
+
+    /// Creates a reader that requests at least `read_size` bytes whenever it needs more data.
+    pub fn with_read_size(reader: R, options: message::ReaderOptions, read_size: usize) -> Self {
+        BufferedMessageReader {
+            reader: reader,
+            options: options,
+            buf: Vec::new(),
+            start: 0,
+            end: 0,
+            read_size: read_size,
+            adaptive: None,
+            average_frame_len: None,
+            max_resync_frame_bytes: DEFAULT_MAX_RESYNC_FRAME_BYTES,
+            eof: false,
+        }
+    }
+
+    /// Creates a reader whose read size adapts to the sizes of the frames it reads, as
+    /// described by `adaptive`. The read size starts at 8 KiB, clamped to the bounds of
+    /// `adaptive`.
+    pub fn with_adaptive_read_size(reader: R, options: message::ReaderOptions,
+                                   adaptive: AdaptiveReadSize) -> Self
+    {
+        let read_size = ::std::cmp::min(::std::cmp::max(DEFAULT_READ_SIZE, adaptive.min_read_size),
+                                        adaptive.max_read_size);
+        let mut result = BufferedMessageReader::with_read_size(reader, options, read_size);
+        result.adaptive = Some(adaptive);
+        result
+    }
+
+    /// The minimum number of bytes currently requested from the underlying reader whenever
+    /// more data is needed.
+    pub fn read_size(&self) -> usize {
+        self.read_size
+    }
+
+    /// Sets the size of the largest frame, in bytes, that `read_message_recoverable()` waits
+    /// to read while skipping damaged data. Larger candidate frames are only accepted if they
+    /// are already fully buffered. Defaults to 64 KiB.
+    pub fn set_max_resync_frame_bytes(&mut self, value: usize) {
+        self.max_resync_frame_bytes = value;
+    }
+
+    /// The number of bytes that have been read from the underlying reader but not yet consumed.
+    pub fn buffered_len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Returns the underlying reader along with the bytes that have been read from it but
+    /// not yet consumed, which come before anything still left in the reader. This allows a
+    /// connection to be handed off without losing data, e.g. after a handshake message.
+    pub fn into_parts(self) -> (R, Vec<u8>) {
+        let buffered = self.buffered().to_vec();
+        (self.reader, buffered)
+    }
+
+    /// Reads the next message. Returns `None` if the stream ended cleanly at a frame boundary.
+    pub async fn read_message(&mut self) -> Result<Option<message::Reader<OwnedSegments>>> {
+        if !self.fill(8).await? {
+            if self.buffered_len() == 0 {
+                return Ok(None)
+            }
+            return Err(premature_end())
+        }
+        let (segment_count, first_segment_length) = parse_segment_table_first(self.buffered())?;
+        let table_len = segment_table_len(segment_count);
+        if !self.fill(table_len).await? {
+            return Err(premature_end())
+        }
+
+        let mut segment_slices = Vec::with_capacity(segment_count);
+        segment_slices.push((0, first_segment_length));
+        let total_words = parse_segment_lengths(&self.buffered()[8..table_len], segment_count - 1,
+                                                &mut segment_slices, first_segment_length)?;
+        check_total_words(total_words, self.options)?;
+        self.start += table_len;
+
+        // Copy whatever part of the body is buffered, and read the rest directly.
+        let mut owned_space = Word::allocate_zeroed_vec(total_words);
+        {
+            let body = Word::words_to_bytes_mut(&mut owned_space[..]);
+            let buffered = ::std::cmp::min(self.buffered_len(), body.len());
+            body[..buffered].copy_from_slice(&self.buffered()[..buffered]);
+            self.start += buffered;
+            if buffered < body.len() {
+                let len = body.len();
+                read_body_part(&mut self.reader, &mut body[buffered..], buffered, len).await?;
+            }
+        }
+
+        self.record_frame(table_len + total_words * 8);
+        let segments = OwnedSegments::new(owned_space, segment_slices);
+        Ok(Some(message::Reader::new(segments, self.options)))
+    }
+
+    /// Returns the next message without consuming it, so that the next call to
+    /// `read_message()` returns the same message. Returns `None` if the stream ended cleanly
+    /// at a frame boundary.
+    ///
+    /// The returned message borrows the internal buffer, so the whole frame is buffered first,
+    /// however large it is, and the message must be dropped before this reader can be used
+    /// again.
+    pub async fn peek_message<'a>(&'a mut self) -> Result<Option<message::Reader<BorrowedSegments<'a>>>> {
+        if !self.fill(8).await? {
+            if self.buffered_len() == 0 {
+                return Ok(None)
+            }
+            return Err(premature_end())
+        }
+        let (segment_count, first_segment_length) = parse_segment_table_first(self.buffered())?;
+        let table_len = segment_table_len(segment_count);
+        if !self.fill(table_len).await? {
+            return Err(premature_end())
+        }
+
+        let mut segment_slices = Vec::with_capacity(segment_count);
+        segment_slices.push((0, first_segment_length));
+        let total_words = parse_segment_lengths(&self.buffered()[8..table_len], segment_count - 1,
+                                                &mut segment_slices, first_segment_length)?;
+        check_total_words(total_words, self.options)?;
+        if !self.fill(table_len + total_words * 8).await? {
+            return Err(truncated_body(self.buffered_len() - table_len, total_words * 8))
+        }
+
+        // Skipping bytes in read_message_recoverable() can leave the frame misaligned.
+        if !self.start.is_multiple_of(8) {
+            self.compact();
+        }
+        let body_start = (self.start + table_len) / 8;
+        let segments = BorrowedSegments { words: &self.buf[body_start..body_start + total_words],
+                                          segment_slices: segment_slices };
+        Ok(Some(message::Reader::new(segments, self.options)))
+    }
+
+    /// Like `read_message()`, but instead of failing on a malformed frame, skips forward one
+    /// byte at a time until it finds something that looks like a valid frame. Returns the
+    /// message, if any, along with the number of bytes that were skipped before it.
+    ///
+    /// A frame looks valid if its segment table parses, its size is within the traversal
+    /// limit, its whole body is available before the end of the stream, and, if it ends with
+    /// a checksum segment (see `write_message_with_checksum()`), the checksum matches. Once
+    /// bytes have been skipped, a frame larger than `set_max_resync_frame_bytes()` is only
+    /// accepted if it is already fully buffered, so that a damaged header can't make the
+    /// reader wait for a huge body. This is intended for best-effort recovery of damaged logs:
+    /// a damaged region can occasionally be mistaken for a valid frame that has no checksum.
+    pub async fn read_message_recoverable(&mut self)
+                                          -> Result<(Option<message::Reader<OwnedSegments>>, usize)>
+    {
+        let mut skipped = 0;
+        loop {
+            if !self.fill(8).await? {
+                skipped += self.buffered_len();
+                self.start = self.end;
+                return Ok((None, skipped))
+            }
+            if let Some((table_len, total_words, segment_slices)) =
+                self.buffer_plausible_frame(skipped > 0).await?
+            {
+                let mut owned_space = Word::allocate_zeroed_vec(total_words);
+                Word::words_to_bytes_mut(&mut owned_space[..])
+                    .copy_from_slice(&self.buffered()[table_len..table_len + total_words * 8]);
+                let checksum_ok = {
+                    let segments: Vec<&[Word]> =
+                        segment_slices.iter().map(|&(a, b)| &owned_space[a..b]).collect();
+                    check_frame_checksum(&segments) != Some(false)
+                };
+                if checksum_ok {
+                    self.start += table_len + total_words * 8;
+                    self.record_frame(table_len + total_words * 8);
+                    let segments = OwnedSegments::new(owned_space, segment_slices);
+                    return Ok((Some(message::Reader::new(segments, self.options)), skipped))
+                }
+            }
+            self.start += 1;
+            skipped += 1;
+        }
+    }
+
+    /// If the buffered data starts with a plausible frame, buffers the whole frame and returns
+    /// the length of its segment table, its total number of words, and its segment slices.
+    /// If `resyncing` is true, gives up on frames larger than `max_resync_frame_bytes` that
+    /// aren't already buffered.
+    async fn buffer_plausible_frame(&mut self, resyncing: bool)
+                                    -> Result<Option<(usize, usize, Vec<(usize, usize)>)>>
+    {
+        let (segment_count, first_segment_length) = match parse_segment_table_first(self.buffered()) {
+            Ok(s) => s,
+            Err(_) => return Ok(None),
+        };
+        let table_len = segment_table_len(segment_count);
+        if !self.fill(table_len).await? {
+            return Ok(None)
+        }
+
+        let mut segment_slices = Vec::with_capacity(segment_count);
+        segment_slices.push((0, first_segment_length));
+        let total_words = match parse_segment_lengths(&self.buffered()[8..table_len], segment_count - 1,
+                                                      &mut segment_slices, first_segment_length) {
+            Ok(n) => n,
+            Err(_) => return Ok(None),
+        };
+        if check_total_words(total_words, self.options).is_err() {
+            return Ok(None)
+        }
+        let frame_len = table_len + total_words * 8;
+        if resyncing && frame_len > self.max_resync_frame_bytes && frame_len > self.buffered_len() {
+            return Ok(None)
+        }
+        if !self.fill(frame_len).await? {
+            return Ok(None)
+        }
+        Ok(Some((table_len, total_words, segment_slices)))
+    }
+
+    /// Updates the average frame size and, if the read size is adaptive, the read size.
+    fn record_frame(&mut self, frame_len: usize) {
+        let adaptive = match self.adaptive {
+            Some(adaptive) => adaptive,
+            None => return,
+        };
+        let average = match self.average_frame_len {
+            Some(average) => average + adaptive.smoothing * (frame_len as f64 - average),
+            None => frame_len as f64,
+        };
+        self.average_frame_len = Some(average);
+        let target = (average * adaptive.frames_ahead as f64) as usize;
+        self.read_size = ::std::cmp::min(::std::cmp::max(target, adaptive.min_read_size),
+                                         adaptive.max_read_size);
+    }
+
+    fn buffered(&self) -> &[u8] {
+        &Word::words_to_bytes(&self.buf)[self.start..self.end]
+    }
+
+    /// Moves the buffered data to the start of `buf`.
+    fn compact(&mut self) {
+        let buffered = self.buffered_len();
+        Word::words_to_bytes_mut(&mut self.buf).copy_within(self.start..self.end, 0);
+        self.start = 0;
+        self.end = buffered;
+    }
+
+    /// Reads until at least `len` bytes are buffered. Returns false if the stream ended first.
+    async fn fill(&mut self, len: usize) -> Result<bool> {
+        if self.buffered_len() >= len {
+            return Ok(true)
+        }
+        if self.eof {
+            return Ok(false)
+        }
+
+        // Move the buffered data to the front, keeping it word-aligned, and make room for
+        // at least `read_size` more bytes.
+        self.compact();
+        let buffered = self.buffered_len();
+        let wanted = ::std::cmp::max(len, buffered + self.read_size);
+        let wanted_words = wanted.div_ceil(8);
+        if self.buf.len() < wanted_words {
+            self.buf.resize(wanted_words, capnp::word(0, 0, 0, 0, 0, 0, 0, 0));
+        }
+
+        while self.end < len {
+            let n = read_retrying(&mut self.reader,
+                                  &mut Word::words_to_bytes_mut(&mut self.buf)[self.end..]).await?;
+            if n == 0 {
+                self.eof = true;
+                return Ok(false)
+            }
+            self.end += n;
+        }
+        Ok(true)
+    }
+}
+
+fn premature_end() -> Error {
+    Error::failed("Premature end of stream in the middle of a message".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use futures::io::Cursor;
+
+    use capnp::{message, Word};
+    use capnp::message::ReaderSegments;
+
+    use crate::serialize::{write_message, write_message_with_checksum};
+    use super::{AdaptiveReadSize, BufferedMessageReader};
+
+    /// Yields `data`, then fails every read as a live socket with nothing more to say would.
+    struct Stalled<'a> {
+        data: &'a [u8],
+    }
+
+    impl <'a> futures::AsyncRead for Stalled<'a> {
+        fn poll_read(mut self: ::std::pin::Pin<&mut Self>, _cx: &mut ::std::task::Context, buf: &mut [u8])
+                     -> ::std::task::Poll<::std::io::Result<usize>>
+        {
+            if self.data.is_empty() {
+                return ::std::task::Poll::Ready(Err(::std::io::Error::new(
+                    ::std::io::ErrorKind::WouldBlock, "stalled")))
+            }
+            let n = ::std::cmp::min(buf.len(), self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            ::std::task::Poll::Ready(Ok(n))
+        }
+    }
+
+    #[test]
+    fn reads_consecutive_messages() {
+        let messages = vec![
+            vec![vec![capnp::word(1,0,0,0,0,0,0,0); 1]],
+            vec![vec![capnp::word(2,0,0,0,0,0,0,0); 3], vec![capnp::word(3,0,0,0,0,0,0,0); 1]],
+            vec![vec![capnp::word(4,0,0,0,0,0,0,0); 100]],
+        ];
+        let mut buf = Vec::new();
+        for segments in &messages {
+            futures::executor::block_on(write_message(&mut buf, segments)).unwrap();
+        }
+
+        for &read_size in &[1, 7, 16, 4096] {
+            let mut reader = BufferedMessageReader::with_read_size(
+                Cursor::new(&buf[..]), message::ReaderOptions::new(), read_size);
+            for segments in &messages {
+                let message = futures::executor::block_on(reader.read_message()).unwrap().unwrap();
+                let message_segments = message.into_segments();
+                for (idx, segment) in segments.iter().enumerate() {
+                    let segment: &[Word] = &segment[..];
+                    assert_eq!(Some(segment), message_segments.get_segment(idx as u32));
+                }
+            }
+            assert!(futures::executor::block_on(reader.read_message()).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn truncated_message_is_an_error() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+
+        for len in &[3, 12, buf.len() - 1] {
+            let mut reader = BufferedMessageReader::new(Cursor::new(&buf[..*len]),
+                                                        message::ReaderOptions::new());
+            assert!(futures::executor::block_on(reader.read_message()).is_err());
+        }
+    }
+
+    #[test]
+    fn recover_from_garbage_between_frames() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        buf.extend_from_slice(&[0xff; 5]);
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        buf.extend_from_slice(&[7, 0, 0]);
+
+        let mut reader = BufferedMessageReader::new(Cursor::new(&buf[..]),
+                                                    message::ReaderOptions::new());
+        for &expected_skip in &[0, 5] {
+            let (message, skipped) =
+                futures::executor::block_on(reader.read_message_recoverable()).unwrap();
+            assert_eq!(expected_skip, skipped);
+            let message_segments = message.unwrap().into_segments();
+            assert_eq!(&segments[0][..], message_segments.get_segment(0).unwrap());
+        }
+        let (message, skipped) = futures::executor::block_on(reader.read_message_recoverable()).unwrap();
+        assert!(message.is_none());
+        assert_eq!(3, skipped);
+    }
+
+    #[test]
+    fn recovery_does_not_wait_for_large_garbage_frames() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 300]];
+        let mut buf = vec![0xff; 3];
+        // A header claiming a 1M-word segment, which never arrives.
+        buf.extend_from_slice(&[0, 0, 0, 0, 0x40, 0, 0x10, 0]);
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+
+        let mut reader = BufferedMessageReader::new(Stalled { data: &buf[..] },
+                                                    message::ReaderOptions::new());
+        let (message, skipped) = futures::executor::block_on(reader.read_message_recoverable()).unwrap();
+        assert_eq!(11, skipped);
+        assert_eq!(&segments[0][..], message.unwrap().into_segments().get_segment(0).unwrap());
+
+        // With a larger limit, the reader waits for the claimed body.
+        let mut reader = BufferedMessageReader::new(Stalled { data: &buf[..] },
+                                                    message::ReaderOptions::new());
+        reader.set_max_resync_frame_bytes(16 * 1024 * 1024);
+        assert!(futures::executor::block_on(reader.read_message_recoverable()).is_err());
+    }
+
+    #[test]
+    fn recovery_skips_frames_with_bad_checksums() {
+        let damaged = vec![vec![capnp::word(1,1,1,1,1,1,1,1); 2]];
+        let segments = vec![vec![capnp::word(2,0,0,0,0,0,0,0); 2]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message_with_checksum(&mut buf, &damaged)).unwrap();
+        let damaged_len = buf.len();
+        buf[16] ^= 0x10;
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+
+        let mut reader = BufferedMessageReader::new(Cursor::new(&buf[..]),
+                                                    message::ReaderOptions::new());
+        let (message, skipped) = futures::executor::block_on(reader.read_message_recoverable()).unwrap();
+        assert_eq!(damaged_len, skipped);
+        assert_eq!(&segments[0][..], message.unwrap().into_segments().get_segment(0).unwrap());
+    }
+
+    #[test]
+    fn into_parts_returns_unconsumed_bytes() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        let frame_len = buf.len();
+        buf.extend_from_slice(b"after the handshake");
+
+        let mut reader = BufferedMessageReader::with_read_size(Cursor::new(&buf[..]),
+                                                               message::ReaderOptions::new(), 32);
+        assert!(futures::executor::block_on(reader.read_message()).unwrap().is_some());
+        let (mut inner, mut rest) = reader.into_parts();
+        assert!(!rest.is_empty());
+        futures::executor::block_on(futures::AsyncReadExt::read_to_end(&mut inner, &mut rest)).unwrap();
+        assert_eq!(&buf[frame_len..], &rest[..]);
+    }
+
+    #[test]
+    fn read_size_adapts_to_frame_sizes() {
+        let mut buf = Vec::new();
+        for _ in 0..20 {
+            let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 99]];
+            futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        }
+        for _ in 0..20 {
+            let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 9]];
+            futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        }
+
+        let mut adaptive = AdaptiveReadSize::new();
+        adaptive.smoothing(0.5).frames_ahead(2).min_read_size(100).max_read_size(1000);
+        let mut reader = BufferedMessageReader::with_adaptive_read_size(
+            Cursor::new(&buf[..]), message::ReaderOptions::new(), adaptive);
+        assert_eq!(1000, reader.read_size());
+
+        // Frames of 800 bytes.
+        assert!(futures::executor::block_on(reader.read_message()).unwrap().is_some());
+        assert_eq!(1000, reader.read_size());
+
+        for _ in 1..20 {
+            assert!(futures::executor::block_on(reader.read_message()).unwrap().is_some());
+        }
+
+        // Frames of 80 bytes. The average converges toward 80, but the read size is clamped.
+        assert!(futures::executor::block_on(reader.read_message()).unwrap().is_some());
+        assert_eq!(880, reader.read_size());
+        for _ in 1..20 {
+            assert!(futures::executor::block_on(reader.read_message()).unwrap().is_some());
+        }
+        assert_eq!(160, reader.read_size());
+        assert!(futures::executor::block_on(reader.read_message()).unwrap().is_none());
+
+        // A fixed read size stays put.
+        let mut reader = BufferedMessageReader::with_read_size(
+            Cursor::new(&buf[..]), message::ReaderOptions::new(), 64);
+        assert!(futures::executor::block_on(reader.read_message()).unwrap().is_some());
+        assert_eq!(64, reader.read_size());
+    }
+
+    #[test]
+    fn peek_does_not_consume() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 3],
+                            vec![capnp::word(2,0,0,0,0,0,0,0); 1]];
+        let other = vec![vec![capnp::word(3,0,0,0,0,0,0,0); 2]];
+        let mut buf = vec![0xff; 3];
+        futures::executor::block_on(write_message(&mut buf, &other)).unwrap();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+
+        let mut reader = BufferedMessageReader::new(Cursor::new(&buf[..]), message::ReaderOptions::new());
+        let (message, skipped) = futures::executor::block_on(reader.read_message_recoverable()).unwrap();
+        assert_eq!(3, skipped);
+        assert_eq!(&other[0][..], message.unwrap().into_segments().get_segment(0).unwrap());
+
+        for _ in 0..2 {
+            let peeked = futures::executor::block_on(reader.peek_message()).unwrap().unwrap();
+            let peeked = peeked.into_segments();
+            assert_eq!(2, peeked.len());
+            assert_eq!(&segments[0][..], peeked.get_segment(0).unwrap());
+            assert_eq!(&segments[1][..], peeked.get_segment(1).unwrap());
+        }
+        let message = futures::executor::block_on(reader.read_message()).unwrap().unwrap();
+        assert_eq!(&segments[1][..], message.into_segments().get_segment(1).unwrap());
+        assert!(futures::executor::block_on(reader.peek_message()).unwrap().is_none());
+    }
+}
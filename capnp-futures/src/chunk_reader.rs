@@ -0,0 +1,120 @@
+// Copyright (c) 2013-2016 Sandstorm Development Group, Inc. and contributors
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::stream::Stream;
+use futures::AsyncRead;
+
+/// Adapts a stream of byte chunks, such as the receiving end of a channel of `Vec<u8>`, into
+/// an `AsyncRead`, so that messages can be read from it with `serialize::read_message()` or
+/// any of the other readers in this crate. Frames may be split across chunks arbitrarily.
+pub struct ChunkReader<S> where S: Stream + Unpin, S::Item: AsRef<[u8]> {
+    chunks: S,
+
+    /// The chunk currently being read, if any.
+    current: Option<S::Item>,
+
+    /// Number of bytes of `current` that have been read.
+    pos: usize,
+}
+
+impl <S> ChunkReader<S> where S: Stream + Unpin, S::Item: AsRef<[u8]> {
+    pub fn new(chunks: S) -> Self {
+        ChunkReader { chunks: chunks, current: None, pos: 0 }
+    }
+
+    /// Returns the wrapped stream. Any unread part of the current chunk is discarded.
+    pub fn into_inner(self) -> S {
+        self.chunks
+    }
+}
+
+impl <S> Unpin for ChunkReader<S> where S: Stream + Unpin, S::Item: AsRef<[u8]> {}
+
+impl <S> AsyncRead for ChunkReader<S> where S: Stream + Unpin, S::Item: AsRef<[u8]> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+                 -> Poll<::std::io::Result<usize>>
+    {
+        let this = &mut *self;
+        loop {
+            if let Some(ref chunk) = this.current {
+                let remaining = &chunk.as_ref()[this.pos..];
+                if !remaining.is_empty() {
+                    let n = ::std::cmp::min(remaining.len(), buf.len());
+                    buf[..n].copy_from_slice(&remaining[..n]);
+                    this.pos += n;
+                    return Poll::Ready(Ok(n))
+                }
+            }
+            match Pin::new(&mut this.chunks).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => {
+                    this.current = None;
+                    return Poll::Ready(Ok(0))
+                }
+                Poll::Ready(Some(chunk)) => {
+                    this.current = Some(chunk);
+                    this.pos = 0;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use capnp::{message, Word};
+    use capnp::message::ReaderSegments;
+
+    use crate::serialize::{read_message, write_message};
+    use super::ChunkReader;
+
+    #[test]
+    fn read_messages_split_across_chunks() {
+        let messages = vec![
+            vec![vec![capnp::word(1,0,0,0,0,0,0,0); 1]],
+            vec![vec![capnp::word(2,0,0,0,0,0,0,0); 3], vec![capnp::word(3,0,0,0,0,0,0,0); 1]],
+        ];
+        let mut buf = Vec::new();
+        for segments in &messages {
+            futures::executor::block_on(write_message(&mut buf, segments)).unwrap();
+        }
+
+        for &chunk_size in &[1, 3, 8, 1000] {
+            let mut chunks: Vec<Vec<u8>> = buf.chunks(chunk_size).map(|c| c.to_vec()).collect();
+            chunks.insert(1, Vec::new());
+            let mut reader = ChunkReader::new(futures::stream::iter(chunks));
+            for segments in &messages {
+                let message = futures::executor::block_on(
+                    read_message(&mut reader, message::ReaderOptions::new())).unwrap().unwrap();
+                let message_segments = message.into_segments();
+                for (idx, segment) in segments.iter().enumerate() {
+                    let segment: &[Word] = &segment[..];
+                    assert_eq!(Some(segment), message_segments.get_segment(idx as u32));
+                }
+            }
+            assert!(futures::executor::block_on(
+                read_message(&mut reader, message::ReaderOptions::new())).unwrap().is_none());
+        }
+    }
+}
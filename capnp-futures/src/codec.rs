@@ -0,0 +1,103 @@
+// Copyright (c) 2013-2016 Sandstorm Development Group, Inc. and contributors
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+//! A `tokio_util` codec for the standard stream framing, for use with `Framed`.
+
+use bytes::BytesMut;
+use capnp::{message, Error, Result};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::buf::{read_message_from_buf, write_message_to_buf};
+use crate::serialize::{serialized_size, AsOutputSegments, OwnedSegments};
+
+/// Decodes and encodes messages with the standard stream framing. Partial frames are left in
+/// the read buffer until the rest of their bytes arrive.
+#[derive(Clone, Copy, Debug)]
+pub struct CapnpCodec {
+    options: message::ReaderOptions,
+}
+
+impl CapnpCodec {
+    /// Creates a codec that decodes messages with the given options.
+    pub fn new(options: message::ReaderOptions) -> Self {
+        CapnpCodec { options: options }
+    }
+}
+
+impl Default for CapnpCodec {
+    fn default() -> Self {
+        CapnpCodec::new(message::ReaderOptions::new())
+    }
+}
+
+impl Decoder for CapnpCodec {
+    type Item = message::Reader<OwnedSegments>;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        read_message_from_buf(src, self.options)
+    }
+}
+
+impl <M> Encoder<M> for CapnpCodec where M: AsOutputSegments {
+    type Error = Error;
+
+    fn encode(&mut self, item: M, dst: &mut BytesMut) -> Result<()> {
+        dst.reserve(serialized_size(&item));
+        write_message_to_buf(dst, item)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+    use capnp::message::ReaderSegments;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use super::CapnpCodec;
+
+    #[test]
+    fn decodes_partial_frames() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2], vec![capnp::word(2,0,0,0,0,0,0,0); 1]];
+        let mut codec = CapnpCodec::default();
+        let mut encoded = BytesMut::new();
+        codec.encode(&segments, &mut encoded).unwrap();
+        codec.encode(&segments, &mut encoded).unwrap();
+
+        // Feed the bytes in three-byte pieces, as they might arrive from a socket.
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for piece in encoded.chunks(3) {
+            src.extend_from_slice(piece);
+            while let Some(message) = codec.decode(&mut src).unwrap() {
+                decoded.push(message);
+            }
+        }
+        assert_eq!(2, decoded.len());
+        assert!(src.is_empty());
+        for message in decoded {
+            let message_segments = message.into_segments();
+            assert_eq!(&segments[0][..], message_segments.get_segment(0).unwrap());
+            assert_eq!(&segments[1][..], message_segments.get_segment(1).unwrap());
+        }
+        assert!(codec.decode_eof(&mut src).unwrap().is_none());
+    }
+}
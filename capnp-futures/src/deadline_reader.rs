@@ -0,0 +1,160 @@
+// Copyright (c) 2013-2016 Sandstorm Development Group, Inc. and contributors
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::time::{Duration, Instant};
+
+use futures::future::Future;
+use futures::AsyncRead;
+
+use capnp::{message, Error, Result};
+
+use crate::serialize::OwnedSegments;
+use crate::MessageReader;
+
+/// Reads messages until a fixed deadline, which bounds a whole exchange of several messages,
+/// such as a handshake, rather than each message individually.
+///
+/// Once the deadline has passed, every read fails with an `ErrorKind::Overloaded` error,
+/// including a read that is still in progress at that moment. The current time is taken from
+/// `clock`, and `sleep` is called with the time remaining and should return a future that
+/// completes once that much time has passed, e.g. `tokio::time::sleep`. Because the messages
+/// are read with a `MessageReader`, the stream stays consistent if a read is cut short.
+pub struct DeadlineMessageReader<R, C, S, F>
+    where R: AsyncRead + Unpin,
+          C: FnMut() -> Instant,
+          S: FnMut(Duration) -> F,
+          F: Future<Output=()>
+{
+    reader: MessageReader<R>,
+    deadline: Instant,
+    clock: C,
+    sleep: S,
+}
+
+impl <R, C, S, F> DeadlineMessageReader<R, C, S, F>
+    where R: AsyncRead + Unpin,
+          C: FnMut() -> Instant,
+          S: FnMut(Duration) -> F,
+          F: Future<Output=()>
+{
+    pub fn new(reader: R, options: message::ReaderOptions, deadline: Instant, clock: C, sleep: S)
+               -> Self
+    {
+        DeadlineMessageReader {
+            reader: MessageReader::new(reader, options),
+            deadline: deadline,
+            clock: clock,
+            sleep: sleep,
+        }
+    }
+
+    /// The time left until the deadline, or zero if it has passed.
+    pub fn remaining(&mut self) -> Duration {
+        self.deadline.checked_duration_since((self.clock)()).unwrap_or_default()
+    }
+
+    /// Returns the underlying `MessageReader`, which has no deadline.
+    pub fn into_inner(self) -> MessageReader<R> {
+        self.reader
+    }
+
+    /// Reads the next message. Returns `None` if the stream ended cleanly at a frame boundary.
+    pub async fn read_message(&mut self) -> Result<Option<message::Reader<OwnedSegments>>> {
+        let remaining = self.remaining();
+        if remaining == Duration::default() {
+            return Err(deadline_exceeded())
+        }
+        let read = self.reader.read_message();
+        let timeout = (self.sleep)(remaining);
+        futures::pin_mut!(read);
+        futures::pin_mut!(timeout);
+        match futures::future::select(read, timeout).await {
+            futures::future::Either::Left((result, _)) => result,
+            futures::future::Either::Right(_) => Err(deadline_exceeded()),
+        }
+    }
+}
+
+fn deadline_exceeded() -> Error {
+    Error::overloaded("Deadline exceeded while reading messages".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::{Duration, Instant};
+
+    use futures::io::Cursor;
+    use futures::AsyncRead;
+
+    use capnp::message;
+    use capnp::message::ReaderSegments;
+
+    use crate::serialize::write_message;
+    use super::DeadlineMessageReader;
+
+    #[test]
+    fn fails_once_deadline_passes() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2]];
+        let mut buf = Vec::new();
+        for _ in 0..3 {
+            futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        }
+
+        let base = Instant::now();
+        let ticks = Cell::new(0);
+        let sleeps = Cell::new(0);
+        let mut reader = DeadlineMessageReader::new(
+            Cursor::new(&buf[..]), message::ReaderOptions::new(), base + Duration::from_millis(25),
+            || { ticks.set(ticks.get() + 1); base + Duration::from_millis(10 * ticks.get()) },
+            |remaining| { sleeps.set(sleeps.get() + 1); assert!(remaining > Duration::default());
+                          futures::future::pending() });
+
+        for _ in 0..2 {
+            let message = futures::executor::block_on(reader.read_message()).unwrap().unwrap();
+            assert_eq!(&segments[0][..], message.into_segments().get_segment(0).unwrap());
+        }
+        let error = futures::executor::block_on(reader.read_message()).err().unwrap();
+        assert_eq!(capnp::ErrorKind::Overloaded, error.kind);
+        assert_eq!(2, sleeps.get());
+    }
+
+    /// Never produces any bytes.
+    struct StalledRead;
+
+    impl AsyncRead for StalledRead {
+        fn poll_read(self: Pin<&mut Self>, _cx: &mut Context, _buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn cuts_off_stalled_read() {
+        let mut reader = DeadlineMessageReader::new(
+            StalledRead, message::ReaderOptions::new(),
+            Instant::now() + Duration::from_secs(5), Instant::now,
+            |_| futures::future::ready(()));
+        let error = futures::executor::block_on(reader.read_message()).err().unwrap();
+        assert_eq!(capnp::ErrorKind::Overloaded, error.kind);
+    }
+}
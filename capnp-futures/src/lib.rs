@@ -20,9 +20,33 @@
 
 extern crate futures;
 
-pub use read_stream::ReadStream;
+pub use buffered_reader::{AdaptiveReadSize, BorrowedSegments, BufferedMessageReader};
+pub use chunk_reader::ChunkReader;
+pub use deadline_reader::DeadlineMessageReader;
+pub use message_log::VecMessageLog;
+pub use message_reader::{MessageReader, ScratchSegments};
+pub use rate_limit::RateLimitedWriter;
+pub use read_stream::{BudgetedMessage, BudgetedReadStream, ReadStream};
+pub use sequenced_reader::SequencedMessageReader;
+pub use slab_pool::SlabSegmentPool;
+pub use sync_io::{sync_to_async, AsyncCursor};
 pub use write_queue::{write_queue, Sender};
 
 pub mod serialize;
+#[cfg(feature = "memmap")]
+pub mod mmap;
+#[cfg(feature = "bytes")]
+pub mod buf;
+#[cfg(feature = "codec")]
+pub mod codec;
+mod buffered_reader;
+mod chunk_reader;
+mod deadline_reader;
+mod message_log;
+mod message_reader;
+mod rate_limit;
 mod read_stream;
+mod sequenced_reader;
+mod slab_pool;
+mod sync_io;
 mod write_queue;
@@ -0,0 +1,107 @@
+// Copyright (c) 2013-2016 Sandstorm Development Group, Inc. and contributors
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+use capnp::Result;
+
+use crate::serialize::{write_message_sync, AsOutputSegments};
+
+/// An in-memory log of framed messages, which records where each frame starts.
+///
+/// Frames are appended to a single growing buffer with the same framing as
+/// `serialize::write_message()`, so any run of frames can be handed out as a byte slice.
+#[derive(Clone, Debug, Default)]
+pub struct VecMessageLog {
+    bytes: Vec<u8>,
+
+    /// Byte offset of the start of each frame in `bytes`, in increasing order.
+    offsets: Vec<usize>,
+}
+
+impl VecMessageLog {
+    pub fn new() -> Self {
+        VecMessageLog { bytes: Vec::new(), offsets: Vec::new() }
+    }
+
+    /// Appends the provided message to the log, returning the byte offset at which its
+    /// frame starts. Returns an error, leaving the log unchanged, if the message can't be
+    /// framed.
+    pub fn append<M>(&mut self, message: M) -> Result<usize> where M: AsOutputSegments {
+        let offset = self.bytes.len();
+        write_message_sync(&mut self.bytes, message)?;
+        self.offsets.push(offset);
+        Ok(offset)
+    }
+
+    /// Returns the bytes of the frame that starts at `offset`, or `None` if no frame starts
+    /// there.
+    pub fn frame_bytes<'a>(&'a self, offset: usize) -> Option<&'a [u8]> {
+        let idx = self.offsets.binary_search(&offset).ok()?;
+        let end = match self.offsets.get(idx + 1) {
+            Some(&next) => next,
+            None => self.bytes.len(),
+        };
+        Some(&self.bytes[offset..end])
+    }
+
+    /// The offsets at which the frames in the log start, in order.
+    pub fn offsets<'a>(&'a self) -> &'a [usize] {
+        &self.offsets
+    }
+
+    /// All of the frames in the log, back to back.
+    pub fn as_bytes<'a>(&'a self) -> &'a [u8] {
+        &self.bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::io::Cursor;
+
+    use capnp::message;
+    use capnp::message::ReaderSegments;
+
+    use crate::serialize::{read_message, write_message};
+    use super::VecMessageLog;
+
+    #[test]
+    fn append_and_retrieve_frames() {
+        let first = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2], vec![capnp::word(2,0,0,0,0,0,0,0); 1]];
+        let second = vec![vec![capnp::word(3,0,0,0,0,0,0,0); 4]];
+
+        let mut log = VecMessageLog::new();
+        assert_eq!(0, log.append(&first).unwrap());
+        let second_offset = log.append(&second).unwrap();
+        assert_eq!(vec![0, second_offset], log.offsets());
+
+        let mut expected = Vec::new();
+        futures::executor::block_on(write_message(&mut expected, &first)).unwrap();
+        assert_eq!(Some(&expected[..]), log.frame_bytes(0));
+        assert_eq!(expected.len(), second_offset);
+        assert_eq!(None, log.frame_bytes(1));
+
+        let frame = log.frame_bytes(second_offset).unwrap();
+        let message = futures::executor::block_on(read_message(Cursor::new(frame), message::ReaderOptions::new()))
+            .unwrap().unwrap();
+        assert_eq!(&second[0][..], message.into_segments().get_segment(0).unwrap());
+        assert_eq!(log.as_bytes().len(), second_offset + frame.len());
+    }
+}
@@ -0,0 +1,518 @@
+// Copyright (c) 2013-2016 Sandstorm Development Group, Inc. and contributors
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+use futures::AsyncRead;
+
+use capnp::{message, Error, Result, Word};
+
+use crate::serialize::{check_total_words, parse_segment_lengths, parse_segment_table_first,
+                       read_retrying, segment_table_len, truncated_body, OwnedSegments};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    /// Reading the first word of the segment table.
+    Header,
+
+    /// Reading the rest of the segment table.
+    Table,
+
+    /// Reading the segments.
+    Body,
+
+    /// A segment table was malformed or rejected, so the stream can't be read any further.
+    Failed,
+}
+
+/// Segments that borrow their contents from the scratch buffer of a `MessageReader`. See
+/// `MessageReader::read_into_scratch()`.
+pub struct ScratchSegments<'a> {
+    words: &'a [Word],
+    segment_slices: &'a [(usize, usize)],
+}
+
+impl <'a> message::ReaderSegments for ScratchSegments<'a> {
+    fn get_segment<'b>(&'b self, id: u32) -> Option<&'b [Word]> {
+        if id < self.segment_slices.len() as u32 {
+            let (a, b) = self.segment_slices[id as usize];
+            Some(&self.words[a..b])
+        } else {
+            None
+        }
+    }
+}
+
+/// Reads a sequence of messages from an `AsyncRead`, keeping the progress of the current frame
+/// as explicit state.
+///
+/// Because every byte read is recorded before the next await point, dropping the future
+/// returned by `read_message()` never loses data: the next call resumes the same frame.
+/// The reader also counts the bytes it has consumed, and errors report the stream offset at
+/// which they occurred. IO errors can be retried, but after a malformed or rejected segment
+/// table every later read fails, since the next frame can't be found.
+pub struct MessageReader<R> where R: AsyncRead + Unpin {
+    reader: R,
+    options: message::ReaderOptions,
+    phase: Phase,
+
+    /// Number of bytes consumed from `reader`.
+    offset: u64,
+
+    /// Stream offset of the first byte of the current frame.
+    frame_start: u64,
+
+    header: [u8; 8],
+
+    segment_count: usize,
+
+    /// The segment table, minus its first word.
+    table: Vec<u8>,
+
+    segment_slices: Vec<(usize, usize)>,
+    body: Vec<Word>,
+
+    /// Number of words of `body` that belong to the current frame.
+    body_words: usize,
+
+    /// Buffer reused by `read_into_scratch()`, holding the most recent message it returned.
+    scratch: Vec<Word>,
+    scratch_slices: Vec<(usize, usize)>,
+
+    /// Number of bytes of the current phase that have been read.
+    filled: usize,
+
+    /// Minimum capacity, in words, with which to allocate the buffer for each message.
+    expected_words: usize,
+
+    /// Number of segments that the remaining frames of the stream may contain in total.
+    total_segment_budget: u64,
+}
+
+impl <R> MessageReader<R> where R: AsyncRead + Unpin {
+    pub fn new(reader: R, options: message::ReaderOptions) -> Self {
+        MessageReader::with_expected_words(reader, options, 0)
+    }
+
+    /// Creates a reader that allocates the buffer for each message with capacity for at least
+    /// `expected_words` words. When messages are usually about the same size, this lets
+    /// callers that go on to grow or reuse those buffers avoid reallocating. A message larger
+    /// than `expected_words` is still read correctly.
+    pub fn with_expected_words(reader: R, options: message::ReaderOptions, expected_words: usize)
+                               -> Self
+    {
+        MessageReader {
+            reader: reader,
+            options: options,
+            phase: Phase::Header,
+            offset: 0,
+            frame_start: 0,
+            header: [0; 8],
+            segment_count: 0,
+            table: Vec::new(),
+            segment_slices: Vec::new(),
+            body: Vec::new(),
+            body_words: 0,
+            scratch: Vec::new(),
+            scratch_slices: Vec::new(),
+            filled: 0,
+            expected_words: expected_words,
+            total_segment_budget: ::std::u64::MAX,
+        }
+    }
+
+    /// Limits the total number of segments in all of the frames that this reader will accept
+    /// from now on. Once a frame would exceed the budget, reading fails. By default the budget
+    /// is `u64::MAX`, i.e. effectively unlimited.
+    ///
+    /// This is a defense against abuse: each segment costs the reader some work and allocation,
+    /// so a peer that keeps sending frames with hundreds of tiny segments can make a connection
+    /// expensive without sending much data. Setting a budget caps that work per connection.
+    pub fn set_total_segment_budget(&mut self, budget: u64) {
+        self.total_segment_budget = budget;
+    }
+
+    /// The number of segments that frames may still contain before the budget set by
+    /// `set_total_segment_budget()` is exhausted.
+    pub fn total_segment_budget(&self) -> u64 {
+        self.total_segment_budget
+    }
+
+    /// The number of bytes that have been consumed from the underlying reader.
+    pub fn stream_offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns true if some bytes of a frame have been consumed, but the frame is not yet
+    /// complete. This is the case after `read_message()` is cancelled or fails partway
+    /// through a frame. A connection can be closed cleanly once this returns false.
+    pub fn has_partial_frame(&self) -> bool {
+        self.partial_bytes() > 0
+    }
+
+    /// The number of bytes of the current, incomplete frame that have been consumed.
+    pub fn partial_bytes(&self) -> u64 {
+        self.offset - self.frame_start
+    }
+
+    /// Gets a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Gets a mutable reference to the underlying reader. Reading from it directly while a
+    /// frame is partially read will desynchronize this `MessageReader`.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Returns the underlying reader. Any partially read frame is discarded, so this should
+    /// only be called at a frame boundary, i.e. after `read_message()` has returned.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Reads the next message. Returns `None` if the stream ended cleanly at a frame boundary.
+    pub async fn read_message(&mut self) -> Result<Option<message::Reader<OwnedSegments>>> {
+        if !self.read_frame(false).await? {
+            return Ok(None)
+        }
+        let mut owned_space = ::std::mem::take(&mut self.body);
+        owned_space.truncate(self.body_words);
+        let segment_slices = ::std::mem::take(&mut self.segment_slices);
+        let segments = OwnedSegments::new(owned_space, segment_slices);
+        Ok(Some(message::Reader::new(segments, self.options)))
+    }
+
+    /// Like `read_message()`, but reads the message into a scratch buffer held by this reader,
+    /// and returns a message that borrows it. The buffer is reused by the next call, so after
+    /// the first few messages it is large enough that reading needs no allocation and no
+    /// zeroing of memory. Because the returned message borrows this reader, it must be dropped
+    /// before the next message can be read; two such messages can never be held at once.
+    pub async fn read_into_scratch<'a>(&'a mut self)
+                                       -> Result<Option<message::Reader<ScratchSegments<'a>>>>
+    {
+        if !self.read_frame(true).await? {
+            return Ok(None)
+        }
+        self.scratch = ::std::mem::take(&mut self.body);
+        ::std::mem::swap(&mut self.scratch_slices, &mut self.segment_slices);
+        let segments = ScratchSegments { words: &self.scratch[..self.body_words],
+                                         segment_slices: &self.scratch_slices[..] };
+        Ok(Some(message::Reader::new(segments, self.options)))
+    }
+
+    /// Reads the next frame, leaving its body in `body` and its segment slices in
+    /// `segment_slices`. Returns false if the stream ended cleanly at a frame boundary. If
+    /// `use_scratch` is true and the body has not been allocated yet, it reuses `scratch`.
+    async fn read_frame(&mut self, use_scratch: bool) -> Result<bool> {
+        loop {
+            match self.phase {
+                Phase::Header => {
+                    if !self.fill_header().await? {
+                        return Ok(false)
+                    }
+                    let (segment_count, first_segment_length) =
+                        parse_segment_table_first(&self.header).map_err(|e| self.fail(e))?;
+                    if segment_count as u64 > self.total_segment_budget {
+                        return Err(self.fail(Error::failed(
+                            format!("Frame has {} segments, but the stream's total segment budget \
+                                     has only {} left", segment_count, self.total_segment_budget))))
+                    }
+                    self.total_segment_budget -= segment_count as u64;
+                    self.segment_count = segment_count;
+                    self.table.clear();
+                    self.table.resize(segment_table_len(segment_count) - 8, 0);
+                    self.segment_slices.clear();
+                    self.segment_slices.reserve(segment_count);
+                    self.segment_slices.push((0, first_segment_length));
+                    self.phase = Phase::Table;
+                    self.filled = 0;
+                }
+                Phase::Table => {
+                    if !fill(&mut self.reader, &mut self.table, &mut self.filled, &mut self.offset).await? {
+                        return Err(premature_end(self.offset))
+                    }
+                    let first_segment_length = self.segment_slices[0].1;
+                    let total_words = parse_segment_lengths(&self.table, self.segment_count - 1,
+                                                            &mut self.segment_slices,
+                                                            first_segment_length)
+                        .map_err(|e| self.fail(e))?;
+                    check_total_words(total_words, self.options).map_err(|e| self.fail(e))?;
+                    if use_scratch {
+                        // Only grow the scratch buffer, so that its contents never need zeroing
+                        // again.
+                        self.body = ::std::mem::take(&mut self.scratch);
+                        if self.body.len() < total_words {
+                            self.body.resize(total_words, capnp::word(0, 0, 0, 0, 0, 0, 0, 0));
+                        }
+                    } else {
+                        self.body = Vec::with_capacity(::std::cmp::max(total_words, self.expected_words));
+                        self.body.resize(total_words, capnp::word(0, 0, 0, 0, 0, 0, 0, 0));
+                    }
+                    self.body_words = total_words;
+                    self.phase = Phase::Body;
+                    self.filled = 0;
+                }
+                Phase::Body => {
+                    if !fill(&mut self.reader, Word::words_to_bytes_mut(&mut self.body[..self.body_words]),
+                             &mut self.filled, &mut self.offset).await? {
+                        return Err(at_offset(truncated_body(self.filled, self.body_words * 8), self.offset))
+                    }
+                    self.phase = Phase::Header;
+                    self.filled = 0;
+                    self.frame_start = self.offset;
+                    return Ok(true)
+                }
+                Phase::Failed => {
+                    return Err(at_offset(Error::failed(
+                        "Cannot read past a malformed segment table".to_string()), self.frame_start))
+                }
+            }
+        }
+    }
+
+    /// Marks the stream as unreadable after `error` in the segment table of the current frame.
+    fn fail(&mut self, error: Error) -> Error {
+        self.phase = Phase::Failed;
+        at_offset(error, self.frame_start)
+    }
+
+    /// Reads the first word of a segment table. Returns false if the stream ended before
+    /// the frame started.
+    async fn fill_header(&mut self) -> Result<bool> {
+        while self.filled < 8 {
+            let n = read_retrying(&mut self.reader, &mut self.header[self.filled..]).await
+                .map_err(|e| at_offset(e.into(), self.offset))?;
+            if n == 0 {
+                if self.filled == 0 {
+                    return Ok(false)
+                }
+                return Err(premature_end(self.offset))
+            }
+            self.filled += n;
+            self.offset += n as u64;
+        }
+        Ok(true)
+    }
+}
+
+/// Reads into `buf` until it is full, starting at `filled` and updating `filled` and `offset`
+/// after every read. Returns false if the stream ended first.
+async fn fill<R>(reader: &mut R, buf: &mut [u8], filled: &mut usize, offset: &mut u64) -> Result<bool>
+    where R: AsyncRead + Unpin
+{
+    while *filled < buf.len() {
+        let n = read_retrying(reader, &mut buf[*filled..]).await.map_err(|e| at_offset(e.into(), *offset))?;
+        if n == 0 {
+            return Ok(false)
+        }
+        *filled += n;
+        *offset += n as u64;
+    }
+    Ok(true)
+}
+
+fn premature_end(offset: u64) -> Error {
+    at_offset(Error::failed("Premature end of stream in the middle of a message".to_string()), offset)
+}
+
+fn at_offset(error: Error, offset: u64) -> Error {
+    Error { kind: error.kind, description: format!("{} (at stream offset {})", error.description, offset) }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::io::Cursor;
+
+    use capnp::{message, Word};
+    use capnp::message::ReaderSegments;
+
+    use crate::serialize::write_message;
+    use super::MessageReader;
+
+    #[test]
+    fn reads_consecutive_messages() {
+        let messages = vec![
+            vec![vec![capnp::word(1,0,0,0,0,0,0,0); 1]],
+            vec![vec![capnp::word(2,0,0,0,0,0,0,0); 3], vec![capnp::word(3,0,0,0,0,0,0,0); 1]],
+        ];
+        let mut buf = Vec::new();
+        for segments in &messages {
+            futures::executor::block_on(write_message(&mut buf, segments)).unwrap();
+        }
+
+        let mut reader = MessageReader::new(Cursor::new(&buf[..]), message::ReaderOptions::new());
+        for segments in &messages {
+            let message = futures::executor::block_on(reader.read_message()).unwrap().unwrap();
+            let message_segments = message.into_segments();
+            for (idx, segment) in segments.iter().enumerate() {
+                let segment: &[Word] = &segment[..];
+                assert_eq!(Some(segment), message_segments.get_segment(idx as u32));
+            }
+        }
+        assert!(futures::executor::block_on(reader.read_message()).unwrap().is_none());
+        assert_eq!(buf.len() as u64, reader.stream_offset());
+    }
+
+    #[test]
+    fn into_inner_returns_reader_at_frame_boundary() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        let frame_len = buf.len();
+        buf.extend_from_slice(b"trailer");
+
+        let mut reader = MessageReader::new(Cursor::new(&buf[..]), message::ReaderOptions::new());
+        assert_eq!(0, reader.get_ref().position());
+        assert!(futures::executor::block_on(reader.read_message()).unwrap().is_some());
+        assert_eq!(frame_len as u64, reader.get_mut().position());
+        let inner = reader.into_inner();
+        assert_eq!(b"trailer", &inner.into_inner()[frame_len..]);
+    }
+
+    #[test]
+    fn reports_partial_frames() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        let frame_len = buf.len();
+        buf.extend_from_slice(&buf.clone()[..12]);
+
+        let mut reader = MessageReader::new(Cursor::new(&buf[..]), message::ReaderOptions::new());
+        assert!(!reader.has_partial_frame());
+        assert!(futures::executor::block_on(reader.read_message()).unwrap().is_some());
+        assert!(!reader.has_partial_frame());
+        assert_eq!(0, reader.partial_bytes());
+
+        assert!(futures::executor::block_on(reader.read_message()).is_err());
+        assert!(reader.has_partial_frame());
+        assert_eq!((buf.len() - frame_len) as u64, reader.partial_bytes());
+    }
+
+    #[test]
+    fn errors_report_stream_offset() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        let frame_len = buf.len();
+
+        // A second frame with too many segments.
+        let mut bad = buf.clone();
+        bad.extend_from_slice(&[0, 2, 0, 0, 0, 0, 0, 0]);
+        let mut reader = MessageReader::new(Cursor::new(&bad[..]), message::ReaderOptions::new());
+        assert!(futures::executor::block_on(reader.read_message()).unwrap().is_some());
+        let error = futures::executor::block_on(reader.read_message()).err().unwrap();
+        assert!(error.description.ends_with(&format!("(at stream offset {})", frame_len)),
+                "{}", error.description);
+
+        // A second frame whose body is truncated.
+        let mut truncated = buf.clone();
+        truncated.extend_from_slice(&buf[..frame_len - 3]);
+        let mut reader = MessageReader::new(Cursor::new(&truncated[..]), message::ReaderOptions::new());
+        assert!(futures::executor::block_on(reader.read_message()).unwrap().is_some());
+        let error = futures::executor::block_on(reader.read_message()).err().unwrap();
+        assert!(error.description.ends_with(&format!("(at stream offset {})", truncated.len())),
+                "{}", error.description);
+    }
+
+    #[test]
+    fn expected_words_is_only_a_hint() {
+        let messages = vec![vec![vec![capnp::word(1,0,0,0,0,0,0,0); 3]],
+                            vec![vec![capnp::word(2,0,0,0,0,0,0,0); 40]]];
+        let mut buf = Vec::new();
+        for segments in &messages {
+            futures::executor::block_on(write_message(&mut buf, segments)).unwrap();
+        }
+
+        let mut reader = MessageReader::with_expected_words(Cursor::new(&buf[..]),
+                                                            message::ReaderOptions::new(), 16);
+        for segments in &messages {
+            let message = futures::executor::block_on(reader.read_message()).unwrap().unwrap();
+            let message_segments = message.into_segments();
+            assert_eq!(&segments[0][..], message_segments.get_segment(0).unwrap());
+        }
+        assert!(futures::executor::block_on(reader.read_message()).unwrap().is_none());
+    }
+
+    #[test]
+    fn read_into_reused_scratch() {
+        let messages = vec![
+            vec![vec![capnp::word(1,0,0,0,0,0,0,0); 5], vec![capnp::word(2,0,0,0,0,0,0,0); 2]],
+            vec![vec![capnp::word(3,0,0,0,0,0,0,0); 1]],
+            vec![vec![capnp::word(4,0,0,0,0,0,0,0); 9]],
+        ];
+        let mut buf = Vec::new();
+        for segments in &messages {
+            futures::executor::block_on(write_message(&mut buf, segments)).unwrap();
+        }
+
+        let mut reader = MessageReader::new(Cursor::new(&buf[..]), message::ReaderOptions::new());
+        for segments in &messages {
+            let message = futures::executor::block_on(reader.read_into_scratch()).unwrap().unwrap();
+            let message_segments = message.into_segments();
+            assert_eq!(segments.len(), message_segments.len());
+            for (idx, segment) in segments.iter().enumerate() {
+                let segment: &[Word] = &segment[..];
+                assert_eq!(Some(segment), message_segments.get_segment(idx as u32));
+            }
+        }
+        assert!(futures::executor::block_on(reader.read_into_scratch()).unwrap().is_none());
+        assert_eq!(9, reader.scratch.len());
+    }
+
+    #[test]
+    fn total_segment_budget() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 1],
+                            vec![capnp::word(2,0,0,0,0,0,0,0); 1]];
+        let mut buf = Vec::new();
+        for _ in 0..3 {
+            futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        }
+
+        let mut reader = MessageReader::new(Cursor::new(&buf[..]), message::ReaderOptions::new());
+        reader.set_total_segment_budget(5);
+        assert!(futures::executor::block_on(reader.read_message()).unwrap().is_some());
+        assert!(futures::executor::block_on(reader.read_message()).unwrap().is_some());
+        assert_eq!(1, reader.total_segment_budget());
+        let error = futures::executor::block_on(reader.read_message()).err().unwrap();
+        assert!(error.description.contains("segment budget"), "{}", error.description);
+        assert_eq!(1, reader.total_segment_budget());
+    }
+
+    #[test]
+    fn retry_after_malformed_table_fails_again() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 1],
+                            vec![capnp::word(2,0,0,0,0,0,0,0); 1]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        // Make the second segment too large for the traversal limit.
+        buf[8..12].copy_from_slice(&[0xff, 0xff, 0xff, 0x0f]);
+
+        let mut reader = MessageReader::new(Cursor::new(&buf[..]), message::ReaderOptions::new());
+        let error = futures::executor::block_on(reader.read_message()).err().unwrap();
+        assert!(error.description.contains("too large"), "{}", error.description);
+        for _ in 0..3 {
+            let error = futures::executor::block_on(reader.read_message()).err().unwrap();
+            assert!(error.description.starts_with("Cannot read past a malformed segment table"),
+                    "{}", error.description);
+            assert_eq!(2, reader.segment_slices.len());
+        }
+    }
+}
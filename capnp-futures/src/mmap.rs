@@ -0,0 +1,143 @@
+// Copyright (c) 2013-2016 Sandstorm Development Group, Inc. and contributors
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Reading of messages directly out of memory-mapped files, without copying.
+
+use capnp::{message, Error, Result, Word};
+
+use crate::serialize::{check_total_words, parse_segment_lengths, parse_segment_table_first,
+                       segment_table_len};
+
+/// Segments that borrow their contents from a memory map.
+pub struct MmapSegments<'a> {
+    segment_slices: Vec<(usize, usize)>,
+    words: &'a [Word],
+}
+
+impl <'a> message::ReaderSegments for MmapSegments<'a> {
+    fn get_segment<'b>(&'b self, id: u32) -> Option<&'b [Word]> {
+        if id < self.segment_slices.len() as u32 {
+            let (a, b) = self.segment_slices[id as usize];
+            Some(&self.words[a..b])
+        } else {
+            None
+        }
+    }
+}
+
+/// Parses the message whose segment table starts at byte `offset` of `map`, borrowing its
+/// segments from the map.
+///
+/// Returns `None` if `offset` is at the end of the map. Otherwise, returns the message and
+/// the offset just past its end, where the next message (if any) begins. Returns an error if
+/// the message body does not start on an eight-byte boundary in memory.
+pub fn read_message_from_mmap<'a>(map: &'a memmap::Mmap,
+                                  offset: usize,
+                                  options: message::ReaderOptions)
+                                  -> Result<Option<(message::Reader<MmapSegments<'a>>, usize)>>
+{
+    read_message_from_bytes(&map[..], offset, options)
+}
+
+fn read_message_from_bytes<'a>(bytes: &'a [u8],
+                               offset: usize,
+                               options: message::ReaderOptions)
+                               -> Result<Option<(message::Reader<MmapSegments<'a>>, usize)>>
+{
+    if offset == bytes.len() {
+        return Ok(None)
+    } else if offset > bytes.len() {
+        return Err(Error::failed(
+            format!("Offset {} is past the end of the map, which has {} bytes", offset, bytes.len())))
+    }
+
+    let bytes = &bytes[offset..];
+    if bytes.len() < 8 {
+        return Err(Error::failed(format!("Truncated segment table at offset {}", offset)))
+    }
+    let (segment_count, first_segment_length) = parse_segment_table_first(bytes)?;
+    let table_len = segment_table_len(segment_count);
+    if bytes.len() < table_len {
+        return Err(Error::failed(format!("Truncated segment table at offset {}", offset)))
+    }
+
+    let mut segment_slices = Vec::with_capacity(segment_count);
+    segment_slices.push((0, first_segment_length));
+    let total_words = parse_segment_lengths(&bytes[8..table_len], segment_count - 1,
+                                            &mut segment_slices, first_segment_length)?;
+    check_total_words(total_words, options)?;
+
+    let body = &bytes[table_len..];
+    if body.len() < total_words * 8 {
+        return Err(Error::failed(
+            format!("Message at offset {} has {} words, but only {} bytes remain",
+                    offset, total_words, body.len())))
+    }
+    let body = &body[..total_words * 8];
+    if !(body.as_ptr() as usize).is_multiple_of(8) {
+        return Err(Error::failed(
+            format!("Message body at offset {} is not aligned to an eight-byte boundary",
+                    offset + table_len)))
+    }
+
+    // Safe because we've checked the alignment above.
+    let words = unsafe { Word::bytes_to_words(body) };
+    let segments = MmapSegments { segment_slices: segment_slices, words: words };
+    Ok(Some((message::Reader::new(segments, options), offset + table_len + total_words * 8)))
+}
+
+#[cfg(test)]
+mod test {
+    use capnp::{message, Word};
+    use capnp::message::ReaderSegments;
+
+    use super::read_message_from_bytes;
+
+    #[test]
+    fn read_consecutive_messages() {
+        let segment_0 = vec![capnp::word(1,0,0,0,0,0,0,0); 2];
+        let segment_1 = vec![capnp::word(2,0,0,0,0,0,0,0); 1];
+        let segments = vec![segment_0.clone(), segment_1.clone()];
+        let mut buf = Vec::new();
+        futures::executor::block_on(crate::serialize::write_message(&mut buf, &segments)).unwrap();
+        let first_len = buf.len();
+        futures::executor::block_on(crate::serialize::write_message(&mut buf, &segments[1..].to_vec())).unwrap();
+
+        // Copy into a Word buffer so that the bytes are aligned.
+        let mut words = Word::allocate_zeroed_vec(buf.len() / 8);
+        Word::words_to_bytes_mut(&mut words).copy_from_slice(&buf);
+        let bytes = Word::words_to_bytes(&words);
+
+        let (message, next) =
+            read_message_from_bytes(bytes, 0, message::ReaderOptions::new()).unwrap().unwrap();
+        assert_eq!(first_len, next);
+        let message_segments = message.into_segments();
+        assert_eq!(&segment_0[..], message_segments.get_segment(0).unwrap());
+        assert_eq!(&segment_1[..], message_segments.get_segment(1).unwrap());
+
+        let (message, next) =
+            read_message_from_bytes(bytes, next, message::ReaderOptions::new()).unwrap().unwrap();
+        assert_eq!(bytes.len(), next);
+        assert_eq!(&segment_1[..], message.into_segments().get_segment(0).unwrap());
+
+        assert!(read_message_from_bytes(bytes, next, message::ReaderOptions::new()).unwrap().is_none());
+        assert!(read_message_from_bytes(&bytes[..first_len - 1], 0, message::ReaderOptions::new()).is_err());
+    }
+}
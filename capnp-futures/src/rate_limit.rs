@@ -0,0 +1,171 @@
+// Copyright (c) 2013-2016 Sandstorm Development Group, Inc. and contributors
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::future::Future;
+use futures::AsyncWrite;
+
+/// Wraps an `AsyncWrite`, pacing writes to at most `rate` bytes per second on average, with
+/// bursts of up to `burst` bytes, using a token bucket. Messages written through it with
+/// `serialize::write_message()` are paced accordingly.
+///
+/// The bucket starts full. Each write waits until the bucket holds enough tokens for the
+/// write, or for `burst` bytes if the write is larger, and then writes that many bytes. The
+/// current time is taken from `clock`, and `sleep` is called with the time to wait and should
+/// return a future that completes once that much time has passed, e.g. `tokio::time::sleep`.
+pub struct RateLimitedWriter<W, C, S, F>
+    where W: AsyncWrite + Unpin,
+          C: FnMut() -> Instant,
+          S: FnMut(Duration) -> F,
+          F: Future<Output=()>
+{
+    writer: W,
+    rate: u64,
+    burst: usize,
+    clock: C,
+    sleep: S,
+
+    /// The number of bytes that may be written right away.
+    tokens: f64,
+
+    /// When `tokens` was last brought up to date.
+    updated: Instant,
+
+    /// The wait for enough tokens to write, if one is in progress.
+    delay: Option<Pin<Box<F>>>,
+}
+
+impl <W, C, S, F> Unpin for RateLimitedWriter<W, C, S, F>
+    where W: AsyncWrite + Unpin,
+          C: FnMut() -> Instant,
+          S: FnMut(Duration) -> F,
+          F: Future<Output=()> {}
+
+impl <W, C, S, F> RateLimitedWriter<W, C, S, F>
+    where W: AsyncWrite + Unpin,
+          C: FnMut() -> Instant,
+          S: FnMut(Duration) -> F,
+          F: Future<Output=()>
+{
+    /// Panics if `rate` or `burst` is zero.
+    pub fn new(writer: W, rate: u64, burst: usize, mut clock: C, sleep: S) -> Self {
+        assert!(rate > 0, "rate must be positive");
+        assert!(burst > 0, "burst must be positive");
+        let now = clock();
+        RateLimitedWriter {
+            writer: writer,
+            rate: rate,
+            burst: burst,
+            clock: clock,
+            sleep: sleep,
+            tokens: burst as f64,
+            updated: now,
+            delay: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Adds the tokens that have accumulated since the last update.
+    fn refill(&mut self) {
+        let now = (self.clock)();
+        let elapsed = now.checked_duration_since(self.updated).unwrap_or_default();
+        self.updated = now;
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate as f64).min(self.burst as f64);
+    }
+}
+
+impl <W, C, S, F> AsyncWrite for RateLimitedWriter<W, C, S, F>
+    where W: AsyncWrite + Unpin,
+          C: FnMut() -> Instant,
+          S: FnMut(Duration) -> F,
+          F: Future<Output=()>
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+                  -> Poll<::std::io::Result<usize>>
+    {
+        let this = &mut *self;
+        loop {
+            if let Some(delay) = this.delay.as_mut() {
+                match delay.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(()) => this.delay = None,
+                }
+            }
+            this.refill();
+            let wanted = ::std::cmp::min(buf.len(), this.burst);
+            if this.tokens >= wanted as f64 {
+                let result = Pin::new(&mut this.writer).poll_write(cx, &buf[..wanted]);
+                if let Poll::Ready(Ok(n)) = result {
+                    this.tokens -= n as f64;
+                }
+                return result
+            }
+            // Round up, so that enough tokens have accumulated once the delay is over.
+            let nanos = ((wanted as f64 - this.tokens) * 1e9 / this.rate as f64).ceil();
+            this.delay = Some(Box::pin((this.sleep)(Duration::from_nanos(nanos as u64))));
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<::std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<::std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::time::{Duration, Instant};
+
+    use capnp::Word;
+
+    use crate::serialize::write_message;
+    use super::RateLimitedWriter;
+
+    #[test]
+    fn paces_writes() {
+        let segments: &[&[Word]] = &[&[capnp::word(1,0,0,0,0,0,0,0); 19]];
+        let base = Instant::now();
+        let now = Cell::new(Duration::default());
+        let mut buf = Vec::new();
+        {
+            let mut writer = RateLimitedWriter::new(
+                &mut buf, 100, 40, || base + now.get(),
+                |d| { now.set(now.get() + d); futures::future::ready(()) });
+            futures::executor::block_on(write_message(&mut writer, segments)).unwrap();
+        }
+        // 160 bytes in all: the first 40 go out right away, and the rest at 100 bytes per second.
+        assert_eq!(160, buf.len());
+        assert_eq!(Duration::from_millis(1200), now.get());
+    }
+}
@@ -18,8 +18,10 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
+use std::ops::Deref;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
 use futures::future::Future;
 use futures::stream::Stream;
 use futures::{AsyncRead};
@@ -34,6 +36,11 @@ async fn read_next_message<R>(mut reader: R, options: message::ReaderOptions)
     Ok((reader, m))
 }
 
+/// A stream of messages read from an `AsyncRead`.
+///
+/// The in-progress read is held as a boxed trait object without a `Send` bound, so a
+/// `ReadStream` is not `Send`. To read messages from another thread, drive
+/// `serialize::read_message()` directly; its future is `Send` whenever the reader is.
 #[must_use = "streams do nothing unless polled"]
 pub struct ReadStream<R> where R: AsyncRead + Unpin + 'static {
     options: message::ReaderOptions,
@@ -68,3 +75,208 @@ impl <R> Stream for ReadStream<R> where R: AsyncRead + Unpin + 'static {
         }
     }
 }
+
+/// The state shared between a `BudgetedReadStream` and the messages it has yielded.
+struct Budget {
+    /// Total size, in bytes, of the messages that have been yielded but not yet dropped.
+    in_flight_bytes: usize,
+
+    /// The task to wake once the stream is back under budget.
+    waker: Option<Waker>,
+}
+
+/// A message yielded by a `BudgetedReadStream`. It counts against the stream's budget until
+/// it is dropped.
+pub struct BudgetedMessage {
+    message: message::Reader<crate::serialize::OwnedSegments>,
+    bytes: usize,
+    budget: Arc<Mutex<Budget>>,
+}
+
+impl Deref for BudgetedMessage {
+    type Target = message::Reader<crate::serialize::OwnedSegments>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.message
+    }
+}
+
+impl Drop for BudgetedMessage {
+    fn drop(&mut self) {
+        let mut budget = self.budget.lock().unwrap();
+        budget.in_flight_bytes -= self.bytes;
+        if let Some(waker) = budget.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Like `ReadStream`, but limits how much memory the messages it has yielded can hold at once.
+///
+/// Each message stays in flight until it is dropped, e.g. while it waits in a `.buffered(n)`
+/// pipeline. While the in-flight messages total `budget_bytes` or more, the stream stops
+/// reading and returns `Poll::Pending`, resuming once enough of them are dropped. Since the
+/// size of a message is only known once it has been read, the total can exceed the budget by
+/// at most one message. A message is always read if none are in flight, so a message larger
+/// than the budget still gets through, and a budget of 0 yields one message at a time.
+#[must_use = "streams do nothing unless polled"]
+pub struct BudgetedReadStream<R> where R: AsyncRead + Unpin + 'static {
+    options: message::ReaderOptions,
+    budget_bytes: usize,
+    budget: Arc<Mutex<Budget>>,
+    reader: Option<R>,
+    read: Option<Pin<Box<dyn Future<Output=Result<(R, Option<message::Reader<crate::serialize::OwnedSegments>>), Error>> + 'static>>>,
+}
+
+impl <R> Unpin for BudgetedReadStream<R> where R: AsyncRead + Unpin + 'static {}
+
+impl <R> BudgetedReadStream<R> where R: AsyncRead + Unpin + 'static {
+    pub fn new(reader: R, options: message::ReaderOptions, budget_bytes: usize) -> Self {
+        BudgetedReadStream {
+            options: options,
+            budget_bytes: budget_bytes,
+            budget: Arc::new(Mutex::new(Budget { in_flight_bytes: 0, waker: None })),
+            reader: Some(reader),
+            read: None,
+        }
+    }
+
+    /// The total size, in bytes, of the messages that have been yielded but not yet dropped.
+    pub fn in_flight_bytes(&self) -> usize {
+        self.budget.lock().unwrap().in_flight_bytes
+    }
+}
+
+impl <R> Stream for BudgetedReadStream<R> where R: AsyncRead + Unpin + 'static {
+    type Item = Result<BudgetedMessage, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if self.read.is_none() {
+            let reader = match self.reader.take() {
+                Some(reader) => reader,
+                None => return Poll::Ready(None), // The stream has ended or failed.
+            };
+            {
+                let mut budget = self.budget.lock().unwrap();
+                if budget.in_flight_bytes > 0 && budget.in_flight_bytes >= self.budget_bytes {
+                    budget.waker = Some(cx.waker().clone());
+                    drop(budget);
+                    self.reader = Some(reader);
+                    return Poll::Pending
+                }
+            }
+            self.read = Some(Box::pin(read_next_message(reader, self.options)));
+        }
+        let result = match self.read.as_mut() {
+            Some(read) => match Future::poll(read.as_mut(), cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(result) => result,
+            },
+            None => unreachable!(),
+        };
+        self.read = None;
+        let message = match result {
+            Err(e) => return Poll::Ready(Some(Err(e))),
+            Ok((_, None)) => return Poll::Ready(None),
+            Ok((r, Some(message))) => {
+                self.reader = Some(r);
+                message
+            }
+        };
+        let segments = message.into_segments();
+        let bytes = segments.total_words() * 8;
+        self.budget.lock().unwrap().in_flight_bytes += bytes;
+        Poll::Ready(Some(Ok(BudgetedMessage {
+            message: message::Reader::new(segments, self.options),
+            bytes: bytes,
+            budget: self.budget.clone(),
+        })))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures::io::Cursor;
+    use futures::stream::Stream;
+
+    use capnp::message;
+
+    use crate::serialize::write_message;
+    use super::BudgetedReadStream;
+
+    #[test]
+    fn pauses_when_over_budget() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 4]];
+        let mut buf = Vec::new();
+        for _ in 0..3 {
+            futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        }
+
+        let mut stream = BudgetedReadStream::new(Cursor::new(buf), message::ReaderOptions::new(), 64);
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let mut poll = |stream: &mut BudgetedReadStream<_>| Pin::new(stream).poll_next(&mut cx);
+
+        let first = match poll(&mut stream) {
+            Poll::Ready(Some(Ok(message))) => message,
+            _ => panic!("expected a message"),
+        };
+        let second = match poll(&mut stream) {
+            Poll::Ready(Some(Ok(message))) => message,
+            _ => panic!("expected a message"),
+        };
+        assert_eq!(64, stream.in_flight_bytes());
+        assert!(poll(&mut stream).is_pending());
+
+        drop(first);
+        assert_eq!(32, stream.in_flight_bytes());
+        match poll(&mut stream) {
+            Poll::Ready(Some(Ok(_))) => (),
+            _ => panic!("expected a message"),
+        }
+        drop(second);
+        match poll(&mut stream) {
+            Poll::Ready(None) => (),
+            _ => panic!("expected end of stream"),
+        }
+        match poll(&mut stream) {
+            Poll::Ready(None) => (),
+            _ => panic!("expected end of stream"),
+        }
+    }
+
+    #[test]
+    fn messages_larger_than_budget_get_through_one_at_a_time() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 4]];
+        let mut buf = Vec::new();
+        for _ in 0..2 {
+            futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        }
+
+        for &budget_bytes in &[0, 16] {
+            let mut stream = BudgetedReadStream::new(Cursor::new(buf.clone()),
+                                                     message::ReaderOptions::new(), budget_bytes);
+            let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+            let mut poll = |stream: &mut BudgetedReadStream<_>| Pin::new(stream).poll_next(&mut cx);
+
+            let first = match poll(&mut stream) {
+                Poll::Ready(Some(Ok(message))) => message,
+                _ => panic!("expected a message"),
+            };
+            assert_eq!(32, stream.in_flight_bytes());
+            assert!(poll(&mut stream).is_pending());
+
+            drop(first);
+            match poll(&mut stream) {
+                Poll::Ready(Some(Ok(_))) => (),
+                _ => panic!("expected a message"),
+            }
+            match poll(&mut stream) {
+                Poll::Ready(None) => (),
+                _ => panic!("expected end of stream"),
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright (c) 2013-2016 Sandstorm Development Group, Inc. and contributors
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+use std::marker::PhantomData;
+
+use futures::AsyncRead;
+
+use capnp::{message, Error, Result};
+
+use crate::serialize::{read_typed_message, OwnedSegments};
+
+/// Reads messages of schema type `T`, checking that each one carries the sequence number
+/// following that of the previous message.
+///
+/// The sequence number of a message is extracted by the `sequence` closure, so that any
+/// schema can be used. The first message may have any sequence number. A message that
+/// repeats or goes back (out of order) or skips ahead (a gap) is an error.
+pub struct SequencedMessageReader<R, T, F>
+    where R: AsyncRead + Unpin,
+          T: for<'a> capnp::traits::Owned<'a>,
+          F: FnMut(&message::TypedReader<OwnedSegments, T>) -> Result<u64>
+{
+    reader: R,
+    options: message::ReaderOptions,
+    sequence: F,
+
+    /// The sequence number that the next message must have, if known.
+    next: Option<u64>,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl <R, T, F> SequencedMessageReader<R, T, F>
+    where R: AsyncRead + Unpin,
+          T: for<'a> capnp::traits::Owned<'a>,
+          F: FnMut(&message::TypedReader<OwnedSegments, T>) -> Result<u64>
+{
+    pub fn new(reader: R, options: message::ReaderOptions, sequence: F) -> Self {
+        SequencedMessageReader {
+            reader: reader,
+            options: options,
+            sequence: sequence,
+            next: None,
+            marker: PhantomData,
+        }
+    }
+
+    /// The sequence number that the next message must have, or `None` if no message has
+    /// been read yet.
+    pub fn next_sequence(&self) -> Option<u64> {
+        self.next
+    }
+
+    /// Reads the next message. Returns `None` if the stream ended cleanly at a frame boundary.
+    pub async fn read_message(&mut self) -> Result<Option<message::TypedReader<OwnedSegments, T>>> {
+        let message = match read_typed_message(&mut self.reader, self.options).await? {
+            Some(m) => m,
+            None => return Ok(None),
+        };
+        let sequence = (self.sequence)(&message)?;
+        if let Some(expected) = self.next {
+            if sequence < expected {
+                return Err(Error::failed(
+                    format!("Message out of order: expected sequence number {}, but got {}",
+                            expected, sequence)))
+            } else if sequence > expected {
+                return Err(Error::failed(
+                    format!("Gap in messages: expected sequence number {}, but got {}",
+                            expected, sequence)))
+            }
+        }
+        self.next = Some(sequence.wrapping_add(1));
+        Ok(Some(message))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::io::Cursor;
+
+    use capnp::message;
+
+    use crate::serialize::write_message;
+    use super::SequencedMessageReader;
+
+    fn write_sequence(sequence: &[u64]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for n in sequence {
+            let mut builder = message::Builder::new_default();
+            builder.init_root::<capnp::any_pointer::Builder>().set_as(&n.to_string()[..]).unwrap();
+            futures::executor::block_on(write_message(&mut buf, &builder)).unwrap();
+        }
+        buf
+    }
+
+    fn read_sequence(buf: &[u8]) -> capnp::Result<Vec<u64>> {
+        let mut reader = SequencedMessageReader::new(
+            Cursor::new(buf), message::ReaderOptions::new(),
+            |m: &message::TypedReader<_, capnp::text::Owned>| {
+                m.get()?.parse::<u64>().map_err(|e| capnp::Error::failed(e.to_string()))
+            });
+        let mut result = Vec::new();
+        while let Some(m) = futures::executor::block_on(reader.read_message())? {
+            result.push(m.get()?.parse().unwrap());
+        }
+        Ok(result)
+    }
+
+    #[test]
+    fn enforce_sequence() {
+        assert_eq!(vec![5, 6, 7], read_sequence(&write_sequence(&[5, 6, 7])).unwrap());
+        assert!(read_sequence(&write_sequence(&[5, 7])).is_err());
+        assert!(read_sequence(&write_sequence(&[5, 6, 6])).is_err());
+        assert!(read_sequence(&write_sequence(&[5, 4])).is_err());
+    }
+}
@@ -20,18 +20,83 @@
 
 //! Asynchronous reading and writing of messages using the
 //! [standard stream framing](https://capnproto.org/encoding.html#serialization-over-a-stream).
+//!
+//! All functions here return `capnp::Error`. IO errors are converted at the point where they
+//! occur, using `capnp::Error`'s `From<std::io::Error>` impl: a timeout becomes
+//! `ErrorKind::Overloaded`, a broken or reset connection becomes `ErrorKind::Disconnected`, and
+//! anything else becomes `ErrorKind::Failed`, with the IO error's message as the description.
 
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use capnp::{message, Error, Result, Word, OutputSegments};
 
-use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::future::Future;
+use futures::stream::Stream;
+use futures::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 
+#[derive(Default)]
 pub struct OwnedSegments {
     segment_slices: Vec<(usize, usize)>,
     owned_space: Vec<Word>,
 }
 
+impl OwnedSegments {
+    pub(crate) fn new(owned_space: Vec<Word>, segment_slices: Vec<(usize, usize)>) -> OwnedSegments {
+        OwnedSegments { segment_slices: segment_slices, owned_space: owned_space }
+    }
+
+    /// Copies `bytes` into a newly allocated, word-aligned buffer. Each element of
+    /// `segment_slices` gives the start and end, in words, of a segment within `bytes`.
+    /// Returns an error if the length of `bytes` does not match the words covered by
+    /// `segment_slices`.
+    pub fn from_bytes(bytes: &[u8], segment_slices: Vec<(usize, usize)>) -> Result<OwnedSegments> {
+        let mut total_words = 0;
+        for &(start, end) in &segment_slices {
+            if start > end {
+                return Err(Error::failed(
+                    format!("Invalid segment slice: start {} is past end {}", start, end)))
+            }
+            total_words = ::std::cmp::max(total_words, end);
+        }
+        if bytes.len() != total_words * 8 {
+            return Err(Error::failed(
+                format!("Expected {} bytes for {} words, but got {} bytes",
+                        total_words * 8, total_words, bytes.len())))
+        }
+
+        let mut owned_space: Vec<Word> = Word::allocate_zeroed_vec(total_words);
+        Word::words_to_bytes_mut(&mut owned_space[..]).copy_from_slice(bytes);
+        Ok(OwnedSegments { segment_slices: segment_slices, owned_space: owned_space })
+    }
+
+    pub(crate) fn into_owned_space(self) -> Vec<Word> {
+        self.owned_space
+    }
+
+    /// Resizes the buffer to hold `total_words` words and replaces the segment slices. The
+    /// contents of the buffer are left unspecified, so they must be overwritten.
+    fn reset(&mut self, total_words: usize, segment_slices: Vec<(usize, usize)>) {
+        self.owned_space.resize(total_words, capnp::word(0, 0, 0, 0, 0, 0, 0, 0));
+        self.segment_slices = segment_slices;
+    }
+
+    /// The sum of the lengths of the segments, in words. This does not count any padding
+    /// between them in the underlying buffer, such as that added by `read_message_aligned()`.
+    pub fn total_words(&self) -> usize {
+        self.segment_slices.iter().map(|&(start, end)| end - start).sum()
+    }
+
+    /// Borrows each of the segments. The result can be wrapped in a `message::SegmentArray`
+    /// to read the message again, possibly with different `message::ReaderOptions`, without
+    /// moving or copying the underlying buffer.
+    pub fn segment_slices<'a>(&'a self) -> Vec<&'a [Word]> {
+        self.segment_slices.iter().map(|&(a, b)| &self.owned_space[a..b]).collect()
+    }
+}
+
 impl message::ReaderSegments for OwnedSegments {
     fn get_segment<'a>(&'a self, id: u32) -> Option<&'a [Word]> {
         if id < self.segment_slices.len() as u32 {
@@ -43,489 +108,4825 @@ impl message::ReaderSegments for OwnedSegments {
     }
 }
 
-/// Begins an asynchronous read of a message from `reader`.
-pub async fn read_message<R>(mut reader: R, options: message::ReaderOptions) -> Result<Option<message::Reader<OwnedSegments>>>
-    where R: AsyncRead + Unpin
+/// Options controlling which frames are accepted when reading from a stream, in addition to
+/// the checks implied by `message::ReaderOptions`.
+///
+/// Only `read_message_with_framing()` takes these options. Every other reader in this crate,
+/// including `MessageReader` and `BufferedMessageReader`, reads with `DEFAULT_READ_FRAMING`,
+/// so e.g. a lowered `max_segments` has no effect on them.
+#[derive(Clone, Copy, Debug)]
+pub struct ReadFraming {
+    /// If true, messages whose first segment is empty are rejected. Such a message cannot hold
+    /// a root pointer, so in many protocols it can only be the result of corruption.
+    pub reject_empty_first_segment: bool,
+
+    /// Messages with more segments than this are rejected as soon as the first word of their
+    /// segment table has been read, before any space is allocated for the rest of the table.
+    /// The stream framing itself never allows more than 511 segments, which is the default.
+    pub max_segments: usize,
+
+    /// Messages with fewer words than this in total are rejected.
+    pub min_total_words: usize,
+
+    /// Messages with more words than this in total are rejected, in addition to those that
+    /// exceed the traversal limit of the `message::ReaderOptions`. To cap the size of messages
+    /// read by readers that don't take a `ReadFraming`, lower that traversal limit instead,
+    /// which every reader checks against the total before allocating the body.
+    pub max_total_words: usize,
+
+    /// If true, messages with no words at all are rejected. Such a message is validly framed,
+    /// but has no room even for a root pointer.
+    pub reject_empty_message: bool,
+
+    /// If true, a run of zero bytes at a frame boundary that continues to the end of the
+    /// stream is treated as a clean end of stream, as for files padded out to a block size.
+    /// An all-zero first word is otherwise a valid frame holding a single empty segment, so
+    /// with this option such frames can't be read: they are taken to be the start of the
+    /// padding, and any nonzero byte after them is an error.
+    pub trailing_zero_is_eof: bool,
+}
+
+pub const DEFAULT_READ_FRAMING: ReadFraming =
+    ReadFraming { reject_empty_first_segment: false, max_segments: 511,
+                  min_total_words: 0, max_total_words: ::std::usize::MAX,
+                  reject_empty_message: false, trailing_zero_is_eof: false };
+
+impl Default for ReadFraming {
+    fn default() -> ReadFraming {
+        DEFAULT_READ_FRAMING
+    }
+}
+
+impl ReadFraming {
+    pub fn new() -> ReadFraming { DEFAULT_READ_FRAMING }
+
+    pub fn reject_empty_first_segment<'a>(&'a mut self, value: bool) -> &'a mut ReadFraming {
+        self.reject_empty_first_segment = value;
+        self
+    }
+
+    pub fn max_segments<'a>(&'a mut self, value: usize) -> &'a mut ReadFraming {
+        self.max_segments = value;
+        self
+    }
+
+    pub fn min_total_words<'a>(&'a mut self, value: usize) -> &'a mut ReadFraming {
+        self.min_total_words = value;
+        self
+    }
+
+    pub fn max_total_words<'a>(&'a mut self, value: usize) -> &'a mut ReadFraming {
+        self.max_total_words = value;
+        self
+    }
+
+    pub fn reject_empty_message<'a>(&'a mut self, value: bool) -> &'a mut ReadFraming {
+        self.reject_empty_message = value;
+        self
+    }
+
+    pub fn trailing_zero_is_eof<'a>(&'a mut self, value: bool) -> &'a mut ReadFraming {
+        self.trailing_zero_is_eof = value;
+        self
+    }
+}
+
+/// Writes `magic` to `writer`. Call this once at the start of a stream, before writing any
+/// messages, to make the stream self-identifying. Does not call `flush()`.
+pub async fn write_stream_header<W>(mut writer: W, magic: &[u8]) -> Result<()>
+    where W: AsyncWrite + Unpin
 {
-    let (total_words, segment_slices) = match read_segment_table(&mut reader, options).await? {
-        Some(s) => s,
-        None => return Ok(None),
-    };
-    Ok(Some(read_segments(reader, total_words, segment_slices, options).await?))
+    writer.write_all(magic).await?;
+    Ok(())
 }
 
-async fn read_segment_table<R>(mut reader: R,
-                               options: message::ReaderOptions)
-                               -> Result<Option<(usize, Vec<(usize, usize)>)>>
+/// Reads the header written by `write_stream_header()` from `reader`, returning an error if
+/// it does not match `expected_magic`. Call this once at the start of a stream, before
+/// reading any messages.
+pub async fn read_stream_header<R>(mut reader: R, expected_magic: &[u8]) -> Result<()>
     where R: AsyncRead + Unpin
 {
-    let mut buf: [u8; 8] = [0; 8];
-    {
-        let n = reader.read(&mut buf[..]).await?;
+    let mut magic = vec![0; expected_magic.len()];
+    let mut filled = 0;
+    while filled < magic.len() {
+        let n = read_retrying(&mut reader, &mut magic[filled..]).await?;
         if n == 0 {
-            return Ok(None)
-        } else if n < 8 {
-            reader.read_exact(&mut buf[n..]).await?;
+            return Err(Error::failed(
+                format!("Premature end of stream after {} bytes of a {}-byte stream header",
+                        filled, magic.len())))
         }
+        filled += n;
     }
-    let (segment_count, first_segment_length) = parse_segment_table_first(&buf[..])?;
-
-    let mut segment_slices: Vec<(usize, usize)> = Vec::with_capacity(segment_count);
-    segment_slices.push((0,first_segment_length));
-    let mut total_words = first_segment_length;
+    if magic != expected_magic {
+        return Err(Error::failed(
+            format!("Bad stream header: expected {:?}, but got {:?}", expected_magic, magic)))
+    }
+    Ok(())
+}
 
-    if segment_count > 1 {
-        if segment_count < 4 {
-            // small enough that we can reuse our existing buffer
-            reader.read_exact(&mut buf).await?;
-            for idx in 0..(segment_count - 1) {
-                let segment_len =
-                    u32::from_le_bytes(buf[(idx * 4)..(idx + 1) * 4].try_into().unwrap()) as usize;
+/// Begins an asynchronous read of a message from `reader`.
+///
+/// The returned future is `Send` if `R` is `Send`.
+pub async fn read_message<R>(reader: R, options: message::ReaderOptions) -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin
+{
+    read_message_with_framing(reader, options, DEFAULT_READ_FRAMING).await
+}
 
-                segment_slices.push((total_words, total_words + segment_len));
-                total_words += segment_len;
+/// Like `read_message()`, but converts any error into the caller's error type `E`, saving a
+/// `map_err()` at every call site.
+pub async fn read_message_mapped<R, E>(reader: R, options: message::ReaderOptions)
+    -> ::std::result::Result<Option<message::Reader<OwnedSegments>>, E>
+    where R: AsyncRead + Unpin, E: From<Error>
+{
+    read_message(reader, options).await.map_err(E::from)
+}
 
-            }
-        } else {
-            let mut segment_sizes = vec![0u8; (segment_count & !1) * 4];
-            reader.read_exact(&mut segment_sizes[..]).await?;
-            for idx in 0..(segment_count - 1) {
-                let segment_len =
-                    u32::from_le_bytes(segment_sizes[(idx * 4)..(idx + 1) * 4].try_into().unwrap()) as usize;
+/// Like `read_message()`, but additionally rejects frames that are not allowed by `framing`.
+pub async fn read_message_with_framing<R>(mut reader: R,
+                                          options: message::ReaderOptions,
+                                          framing: ReadFraming)
+                                          -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin
+{
+    let (total_words, segment_slices) =
+        match read_framed_segment_table(&mut reader, options, framing).await? {
+            Some(table) => table.into_parts(),
+            None => return Ok(None),
+        };
+    Ok(Some(read_segments(reader, total_words, segment_slices, options).await?))
+}
 
-                segment_slices.push((total_words, total_words + segment_len));
-                total_words += segment_len;
-            }
-        }
-    }
+/// Like `read_message()`, but returns the message's segments without wrapping them in a
+/// `message::Reader`, for callers that only inspect raw segment bytes. `options` is still used
+/// to reject messages that are too large. To traverse the segments later, pass them to
+/// `message::Reader::new()`.
+pub async fn read_segments_only<R>(reader: R, options: message::ReaderOptions) -> Result<Option<OwnedSegments>>
+    where R: AsyncRead + Unpin
+{
+    Ok(read_message(reader, options).await?.map(|message| message.into_segments()))
+}
 
-    // Don't accept a message which the receiver couldn't possibly traverse without hitting the
-    // traversal limit. Without this check, a malicious client could transmit a very large segment
-    // size to make the receiver allocate excessive space and possibly crash.
-    if total_words as u64 > options.traversal_limit_in_words  {
+/// Like `read_message()`, but returns a message whose data is in a single segment.
+///
+/// A message that arrives with a single segment is returned as is. Otherwise, if `flatten`
+/// is false, returns an error. If `flatten` is true, returns the
+/// [canonical form](https://capnproto.org/encoding.html#canonicalization) of the message.
+/// Note that the segments can't simply be concatenated, because far pointers refer to their
+/// targets by segment id; canonicalization instead rewrites every pointer, which costs an
+/// extra copy of the message and drops any data that is unreachable from the root.
+pub async fn read_message_canonical<R>(mut reader: R, options: message::ReaderOptions, flatten: bool)
+                                       -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin
+{
+    let (total_words, segment_slices) = match read_segment_table(&mut reader, options).await? {
+        Some(table) => table.into_parts(),
+        None => return Ok(None),
+    };
+    let segment_count = segment_slices.len();
+    let message = read_segments(reader, total_words, segment_slices, options).await?;
+    if segment_count == 1 {
+        return Ok(Some(message))
+    } else if !flatten {
         return Err(Error::failed(
-            format!("Message has {} words, which is too large. To increase the limit on the \
-             receiving end, see capnp::message::ReaderOptions.", total_words)))
+            format!("Expected a single segment, but the message has {}", segment_count)))
     }
+    let words = reframe_single_segment(&message)?;
+    let len = words.len();
+    Ok(Some(message::Reader::new(OwnedSegments::new(words, vec![(0, len)]), options)))
+}
 
-    Ok(Some((total_words, segment_slices)))
+/// Copies `message` into a single segment, in
+/// [canonical form](https://capnproto.org/encoding.html#canonicalization), e.g. to store a
+/// message that arrived with several segments as one. Write the result with
+/// `write_message()` as a one-element slice of segments.
+///
+/// Returns an error if the message can't be canonicalized, for example because it contains
+/// an invalid pointer or a capability, or because traversing it exceeds the limits of the
+/// `message::ReaderOptions` that it was read with.
+pub fn reframe_single_segment<S>(message: &message::Reader<S>) -> Result<Vec<Word>>
+    where S: message::ReaderSegments
+{
+    message.canonicalize()
 }
 
-/// Reads segments from `read`.
-async fn read_segments<R>(mut read: R,
-                    total_words: usize,
-                    segment_slices: Vec<(usize, usize)>,
-                    options: message::ReaderOptions)
-                    -> Result<message::Reader<OwnedSegments>>
-    where R: AsyncRead + Unpin
+/// Like `read_message()`, but passes the bytes of the message's segments to `transform`
+/// before they are interpreted, e.g. to decrypt them. The segment table is not transformed.
+/// `transform` works in place, so it must preserve the length of the data.
+pub async fn read_message_with_transform<R, F>(mut reader: R,
+                                               options: message::ReaderOptions,
+                                               transform: F)
+                                               -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin, F: FnOnce(&mut [u8]) -> Result<()>
 {
+    let (total_words, segment_slices) = match read_segment_table(&mut reader, options).await? {
+        Some(table) => table.into_parts(),
+        None => return Ok(None),
+    };
     let mut owned_space: Vec<Word> = Word::allocate_zeroed_vec(total_words);
-    read.read_exact(Word::words_to_bytes_mut(&mut owned_space[..])).await?;
-    let segments = OwnedSegments {segment_slices: segment_slices, owned_space: owned_space};
-    Ok(message::Reader::new(segments, options))
+    read_body(&mut reader, &mut owned_space[..]).await?;
+    transform(Word::words_to_bytes_mut(&mut owned_space[..]))?;
+    let segments = OwnedSegments::new(owned_space, segment_slices);
+    Ok(Some(message::Reader::new(segments, options)))
 }
 
-/// Parses the first word of the segment table.
-///
-/// The segment table format for streams is defined in the Cap'n Proto
-/// [encoding spec](https://capnproto.org/encoding.html#serialization-over-a-stream)
-///
-/// Returns the segment count and first segment length, or a state if the
-/// read would block.
-fn parse_segment_table_first(buf: &[u8]) -> Result<(usize, usize)>
+/// Reads messages from `reader` until it reaches end of stream.
+pub async fn read_all_messages<R>(mut reader: R, options: message::ReaderOptions)
+                                  -> Result<Vec<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin
 {
-    let segment_count = u32::from_le_bytes(buf[0..4].try_into().unwrap()).wrapping_add(1);
-    if segment_count >= 512 {
-        return Err(Error::failed(format!("Too many segments: {}", segment_count)))
-    } else if segment_count == 0 {
-        return Err(Error::failed(format!("Too few segments: {}", segment_count)))
+    let mut messages = Vec::new();
+    while let Some(m) = read_message(&mut reader, options).await? {
+        messages.push(m);
     }
-
-    let first_segment_len = u32::from_le_bytes(buf[4..8].try_into().unwrap());
-    Ok((segment_count as usize, first_segment_len as usize))
+    Ok(messages)
 }
 
-/// Something that contains segments ready to be written out.
-pub trait AsOutputSegments {
-    fn as_output_segments<'a>(&'a self) -> OutputSegments<'a>;
+/// Returns a stream of the messages in the first `max_bytes` bytes of `reader`.
+///
+/// The stream ends once exactly `max_bytes` bytes have been consumed. No bytes beyond the
+/// first `max_bytes` are ever read, so a frame that extends past the limit yields an error,
+/// after which the stream ends. If the segment table of such a frame fits within the limit,
+/// the error comes as soon as the table has been read, without reading the body.
+pub fn read_messages_within<R>(reader: R, options: message::ReaderOptions, max_bytes: u64)
+                               -> impl Stream<Item=Result<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin
+{
+    futures::stream::unfold(Some((reader, max_bytes)), move |state| async move {
+        let (mut reader, remaining) = state?;
+        if remaining == 0 {
+            return None
+        }
+        let result = {
+            let mut limited = (&mut reader).take(remaining);
+            match read_segment_table(&mut limited, options).await {
+                Ok(Some(table)) => {
+                    let (total_words, segment_slices) = table.into_parts();
+                    let frame_len =
+                        (segment_table_len(segment_slices.len()) + total_words * 8) as u64;
+                    if frame_len > remaining {
+                        Err(Error::failed(
+                            format!("Frame of {} bytes extends past the remaining {} bytes of the limit",
+                                    frame_len, remaining)))
+                    } else {
+                        read_segments(&mut limited, total_words, segment_slices, options).await
+                            .map(|message| (message, frame_len))
+                    }
+                }
+                Ok(None) => return None,
+                Err(e) => Err(e),
+            }
+        };
+        match result {
+            Ok((message, frame_len)) => Some((Ok(message), Some((reader, remaining - frame_len)))),
+            Err(e) => Some((Err(e), None)),
+        }
+    })
 }
 
+/// Checks the structure of every frame in `reader`, from its current position to its end,
+/// and returns the number of frames. Reads only the segment tables, checking them against
+/// `options`, and seeks past the bodies without reading them. Returns an error, naming the
+/// index of the frame, at the first segment table that is malformed or whose body extends
+/// past the end of `reader`.
+pub async fn validate_frames<R>(mut reader: R, options: message::ReaderOptions) -> Result<usize>
+    where R: AsyncRead + AsyncSeek + Unpin
+{
+    let start = reader.seek(::std::io::SeekFrom::Current(0)).await?;
+    let end = reader.seek(::std::io::SeekFrom::End(0)).await?;
+    reader.seek(::std::io::SeekFrom::Start(start)).await?;
 
-impl <'a, M> AsOutputSegments for &'a M where M: AsOutputSegments {
-    fn as_output_segments<'b>(&'b self) -> OutputSegments<'b> {
-        (*self).as_output_segments()
+    let mut frames = 0;
+    loop {
+        let total_words = match read_segment_table(&mut reader, options).await {
+            Ok(Some(table)) => table.total_words(),
+            Ok(None) => return Ok(frames),
+            Err(e) => {
+                return Err(Error { kind: e.kind,
+                                   description: format!("Message {}: {}", frames, e.description) })
+            }
+        };
+        let body_end = reader.seek(::std::io::SeekFrom::Current(total_words as i64 * 8)).await?;
+        if body_end > end {
+            return Err(Error::failed(
+                format!("Message {}: body of {} words extends past the end of the stream",
+                        frames, total_words)))
+        }
+        frames += 1;
     }
 }
 
-impl <A> AsOutputSegments for message::Builder<A> where A: message::Allocator {
-    fn as_output_segments<'a>(&'a self) -> OutputSegments<'a> {
-        self.get_segments_for_output()
-    }
+/// Remembers whether the wrapped reader has reported the end of the stream.
+struct EofWatch<R> {
+    inner: R,
+    saw_eof: bool,
 }
 
-/*impl <'a, A> AsOutputSegments for &'a message::Builder<A> where A: message::Allocator {
-    fn as_output_segments<'b>(&'b self) -> OutputSegments<'b> {
-        self.get_segments_for_output()
+impl <R> AsyncRead for EofWatch<R> where R: AsyncRead + Unpin {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+                 -> Poll<::std::io::Result<usize>>
+    {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(0)) = result {
+            if !buf.is_empty() { self.saw_eof = true; }
+        }
+        result
     }
-}*/
+}
 
-impl <A> AsOutputSegments for ::std::rc::Rc<message::Builder<A>> where A: message::Allocator {
-    fn as_output_segments<'a>(&'a self) -> OutputSegments<'a> {
-        self.get_segments_for_output()
+/// Like `read_message()`, but reads at most `max_bytes` bytes from `reader`. If the limit is
+/// reached before the frame is complete, returns an error saying so, rather than the generic
+/// premature-end-of-stream error. Since no frame fits in zero bytes, a `max_bytes` of zero
+/// always yields that error. Other errors, such as a malformed segment table that happens to
+/// end exactly at the limit, are returned as they are.
+pub async fn read_message_limited_reader<R>(reader: R, options: message::ReaderOptions, max_bytes: u64)
+                                            -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin
+{
+    let mut limited = EofWatch { inner: reader.take(max_bytes), saw_eof: false };
+    match read_message(&mut limited, options).await {
+        Ok(Some(message)) => Ok(Some(message)),
+        Err(_) | Ok(None) if limited.saw_eof && limited.inner.limit() == 0 => {
+            Err(Error::failed(
+                format!("Reached the limit of {} bytes before the frame was complete", max_bytes)))
+        }
+        result => result,
     }
 }
 
-/// Writes the provided message to `writer`. Does not call `flush()`.
-pub async fn write_message<W,M>(mut writer: W, message: M) -> Result<()>
-    where W: AsyncWrite + Unpin, M: AsOutputSegments
+/// Returns a stream of the messages in `reader`, each paired with its zero-based index in the
+/// stream. The stream ends when `reader` ends cleanly at a frame boundary, or after the first
+/// error.
+pub fn read_message_stream_indexed<R>(reader: R, options: message::ReaderOptions)
+                                      -> impl Stream<Item=Result<(usize, message::Reader<OwnedSegments>)>>
+    where R: AsyncRead + Unpin
 {
-    let segments = message.as_output_segments();
-    write_segment_table(&mut writer, &segments[..]).await?;
-    write_segments(writer, &segments[..]).await?;
-    Ok(())
+    futures::stream::unfold(Some((reader, 0)), move |state| async move {
+        let (mut reader, idx) = state?;
+        match read_message(&mut reader, options).await {
+            Ok(Some(message)) => Some((Ok((idx, message)), Some((reader, idx + 1)))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), None)),
+        }
+    })
 }
 
-async fn write_segment_table<W>(mut write: W, segments: &[&[Word]]) -> ::std::io::Result<()>
-    where W: AsyncWrite + Unpin
+/// Like `read_message()`, but binds the message to the schema type `T`. Returns an error if
+/// the root of the message cannot be read as a `T`.
+pub async fn read_typed_message<R, T>(reader: R, options: message::ReaderOptions)
+                                      -> Result<Option<message::TypedReader<OwnedSegments, T>>>
+    where R: AsyncRead + Unpin, T: for<'a> capnp::traits::Owned<'a>
 {
-    let mut buf: [u8; 8] = [0; 8];
-    let segment_count = segments.len();
-
-    // write the first Word, which contains segment_count and the 1st segment length
-    buf[0..4].copy_from_slice(&(segment_count as u32 - 1).to_le_bytes());
-    buf[4..8].copy_from_slice(&(segments[0].len() as u32).to_le_bytes());
-    write.write_all(&buf).await?;
+    let message = match read_message(reader, options).await? {
+        Some(m) => m,
+        None => return Ok(None),
+    };
+    let typed = message::TypedReader::new(message);
+    typed.get()?;
+    Ok(Some(typed))
+}
 
-    if segment_count > 1 {
-        if segment_count < 4 {
-            for idx in 1..segment_count {
-                buf[(idx - 1) * 4..idx * 4].copy_from_slice(
-                    &(segments[idx].len() as u32).to_le_bytes());
-            }
-            if segment_count == 2 {
-                for idx in 4..8 { buf[idx] = 0 }
-            }
-            write.write_all(&buf).await?;
-        } else {
-            let mut buf = vec![0; (segment_count & !1) * 4];
-            for idx in 1..segment_count {
-                buf[(idx - 1) * 4..idx * 4].copy_from_slice(
-                    &(segments[idx].len() as u32).to_le_bytes());
-            }
-            if segment_count % 2 == 0 {
-                for idx in (buf.len() - 4)..(buf.len()) { buf[idx] = 0 }
+/// Reads messages from `reader` until it reaches end of stream, binding each one to the
+/// schema type `T`. Aborts at the first message that fails to be read or bound.
+pub async fn read_all_typed<R, T>(mut reader: R, options: message::ReaderOptions)
+                                  -> Result<Vec<message::TypedReader<OwnedSegments, T>>>
+    where R: AsyncRead + Unpin, T: for<'a> capnp::traits::Owned<'a>
+{
+    let mut messages = Vec::new();
+    loop {
+        match read_typed_message(&mut reader, options).await {
+            Ok(Some(m)) => messages.push(m),
+            Ok(None) => return Ok(messages),
+            Err(e) => {
+                return Err(Error { kind: e.kind,
+                                   description: format!("Message {}: {}", messages.len(), e.description) })
             }
-            write.write_all(&buf).await?;
         }
     }
-    Ok(())
 }
 
-/// Writes segments to `write`.
-async fn write_segments<W>(mut write: W, segments: &[&[Word]]) -> Result<()>
-    where W: AsyncWrite + Unpin
+/// Reads messages from `reader` until it reaches end of stream, passing each one to `f`.
+/// Returns the number of messages read.
+///
+/// This is a performance mode for streams of many similarly-sized messages: the buffer that
+/// holds each message is recycled for the next one, so that once it has grown to fit the
+/// largest message no further allocations are needed. Because of this, `f` only borrows each
+/// message and must copy out anything that it needs to keep.
+pub async fn read_all_messages_recycling<R, F>(mut reader: R,
+                                               options: message::ReaderOptions,
+                                               mut f: F) -> Result<usize>
+    where R: AsyncRead + Unpin, F: FnMut(&message::Reader<OwnedSegments>) -> Result<()>
 {
-    for i in 0..segments.len() {
-        write.write_all(Word::words_to_bytes(segments[i])).await?;
+    let mut count = 0;
+    let mut segments = OwnedSegments::default();
+    while let Some(message) = read_message_reusing(&mut reader, options, segments).await? {
+        f(&message)?;
+        segments = message.into_segments();
+        count += 1;
     }
-    Ok(())
+    Ok(count)
+}
+
+/// Like `read_message()`, but reuses the buffer of `recycled`, which typically comes from
+/// a message that is no longer needed (via `message::Reader::into_segments()`), instead of
+/// allocating a new one. The buffer only needs to be reallocated when the new message is
+/// larger than any message it has held before. Use `OwnedSegments::default()` to start with
+/// an empty buffer.
+pub async fn read_message_reusing<R>(mut reader: R,
+                                     options: message::ReaderOptions,
+                                     recycled: OwnedSegments)
+                                     -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin
+{
+    let mut segments = recycled;
+    let (total_words, segment_slices) = match read_segment_table(&mut reader, options).await? {
+        Some(table) => table.into_parts(),
+        None => return Ok(None),
+    };
+    segments.reset(total_words, segment_slices);
+    read_body(&mut reader, &mut segments.owned_space[..]).await?;
+    Ok(Some(message::Reader::new(segments, options)))
 }
 
+/// Like `read_message()`, but draws the buffer for the message body from `pool`. Hand the
+/// segments back with `pool.release()` (via `message::Reader::into_segments()`) once the
+/// message is no longer needed, so that the buffer can be reused. If the body fails to
+/// arrive, the buffer is released automatically.
+pub async fn read_message_from_pool<R>(mut reader: R,
+                                       options: message::ReaderOptions,
+                                       pool: &crate::SlabSegmentPool)
+                                       -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin
+{
+    let (total_words, segment_slices) = match read_segment_table(&mut reader, options).await? {
+        Some(table) => table.into_parts(),
+        None => return Ok(None),
+    };
+    let mut segments = pool.acquire(total_words);
+    segments.reset(total_words, segment_slices);
+    if let Err(e) = read_body(&mut reader, &mut segments.owned_space[..]).await {
+        pool.release(segments);
+        return Err(e)
+    }
+    Ok(Some(message::Reader::new(segments, options)))
+}
 
+/// Reads a message whose segments must have the same layout as `layout`, reading its body
+/// directly into the buffer of `layout` instead of allocating a new one.
+///
+/// This is intended for streams in which every message has an identical layout. Pass the
+/// segments of the previous message (via `message::Reader::into_segments()`) to reuse its
+/// buffer, or use `OwnedSegments::from_bytes()` to construct the first layout. The segment
+/// table of the incoming message is still read and checked, and a mismatch is an error.
+pub async fn read_message_fixed_layout<R>(mut reader: R,
+                                          layout: OwnedSegments,
+                                          options: message::ReaderOptions)
+                                          -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin
+{
+    let mut segments = layout;
+    let (total_words, segment_slices) = match read_segment_table(&mut reader, options).await? {
+        Some(table) => table.into_parts(),
+        None => return Ok(None),
+    };
+    if segment_slices != segments.segment_slices || total_words != segments.owned_space.len() {
+        return Err(Error::failed(
+            format!("Expected segments {:?}, but the segment table gives {:?}",
+                    segments.segment_slices, segment_slices)))
+    }
+    read_body(&mut reader, &mut segments.owned_space[..]).await?;
+    Ok(Some(message::Reader::new(segments, options)))
+}
 
-#[cfg(test)]
-pub mod test {
-    use std::cmp;
-    use std::io::{self, Read, Write};
-    use std::pin::Pin;
-    use std::task::{Context, Poll};
+/// Reads a message from `reader` one segment at a time, calling `per_segment_callback` with
+/// the index and contents of each segment as soon as it has been read. At most one segment
+/// is held in memory at any given time, so the whole message is never materialized.
+///
+/// Returns `false` if `reader` was already at end of stream, and `true` otherwise. An error
+/// returned by the callback aborts the read.
+pub async fn read_message_streaming<R, F>(mut reader: R,
+                                          options: message::ReaderOptions,
+                                          mut per_segment_callback: F) -> Result<bool>
+    where R: AsyncRead + Unpin, F: FnMut(u32, &[Word]) -> Result<()>
+{
+    let (total_words, segment_slices) = match read_segment_table(&mut reader, options).await? {
+        Some(table) => table.into_parts(),
+        None => return Ok(false),
+    };
 
-    use futures::{AsyncRead, AsyncWrite};
-    use futures::io::Cursor;
+    let mut buf: Vec<Word> = Vec::new();
+    for (idx, &(start, end)) in segment_slices.iter().enumerate() {
+        buf.clear();
+        buf.resize(end - start, capnp::word(0, 0, 0, 0, 0, 0, 0, 0));
+        read_body_part(&mut reader, Word::words_to_bytes_mut(&mut buf[..]), start * 8, total_words * 8).await?;
+        per_segment_callback(idx as u32, &buf[..])?;
+    }
+    Ok(true)
+}
 
-    use quickcheck::{quickcheck, TestResult};
+/// How many bytes of a message body are read at a time by readers that do something between
+/// pieces of the body: `read_message_with_progress()` and `read_message_fair()`.
+const BODY_CHUNK_BYTES: usize = 64 * 1024;
 
-    use capnp::{Word, message, OutputSegments};
-    use capnp::message::ReaderSegments;
+/// The ranges of a body of `len` bytes that such readers read one at a time.
+fn body_chunks(len: usize) -> impl Iterator<Item=::std::ops::Range<usize>> {
+    (0..len).step_by(BODY_CHUNK_BYTES)
+        .map(move |start| start..::std::cmp::min(start + BODY_CHUNK_BYTES, len))
+}
 
-    use super::{
-        AsOutputSegments,
-        read_message,
-        read_segment_table,
-        write_message,
+/// Like `read_message()`, but calls `progress` with the number of bytes of the message body
+/// read so far and the total size of the body in bytes: once when the segment table has been
+/// read, and then after every 64 KiB of the body and at its end.
+pub async fn read_message_with_progress<R, F>(mut reader: R, options: message::ReaderOptions, mut progress: F)
+                                              -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin, F: FnMut(usize, usize)
+{
+    let (total_words, segment_slices) = match read_segment_table(&mut reader, options).await? {
+        Some(table) => table.into_parts(),
+        None => return Ok(None),
     };
+    let mut owned_space: Vec<Word> = Word::allocate_zeroed_vec(total_words);
+    let total_bytes = total_words * 8;
+    progress(0, total_bytes);
+    {
+        let body = Word::words_to_bytes_mut(&mut owned_space[..]);
+        for chunk in body_chunks(total_bytes) {
+            read_body_part(&mut reader, &mut body[chunk.clone()], chunk.start, total_bytes).await?;
+            progress(chunk.end, total_bytes);
+        }
+    }
+    let segments = OwnedSegments { segment_slices: segment_slices, owned_space: owned_space };
+    Ok(Some(message::Reader::new(segments, options)))
+}
 
-    #[test]
-    fn test_read_segment_table() {
-        let mut exec = futures::executor::LocalPool::new();
-        let mut buf = vec![];
+/// Like `read_message()`, but reads the message body in pieces of at most 64 KiB, yielding to
+/// the executor between pieces. When the underlying reader can deliver a very large body all
+/// at once, this lets other tasks on the same thread make progress while it is being read, at
+/// the cost of some extra wakeups.
+pub async fn read_message_fair<R>(mut reader: R, options: message::ReaderOptions)
+                                  -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin
+{
+    let (total_words, segment_slices) = match read_segment_table(&mut reader, options).await? {
+        Some(table) => table.into_parts(),
+        None => return Ok(None),
+    };
+    let mut owned_space: Vec<Word> = Word::allocate_zeroed_vec(total_words);
+    let total_bytes = total_words * 8;
+    {
+        let body = Word::words_to_bytes_mut(&mut owned_space[..]);
+        for chunk in body_chunks(total_bytes) {
+            if chunk.start > 0 {
+                YieldNow { yielded: false }.await;
+            }
+            read_body_part(&mut reader, &mut body[chunk.clone()], chunk.start, total_bytes).await?;
+        }
+    }
+    let segments = OwnedSegments { segment_slices: segment_slices, owned_space: owned_space };
+    Ok(Some(message::Reader::new(segments, options)))
+}
 
-        buf.extend([0,0,0,0, // 1 segments
-                    0,0,0,0] // 0 length
-                    .iter().cloned());
-        let (words, segment_slices) = exec.run_until(read_segment_table(Cursor::new(&buf[..]),
-                                                                        message::ReaderOptions::new())).unwrap().unwrap();
-        assert_eq!(0, words);
-        assert_eq!(vec![(0,0)], segment_slices);
-        buf.clear();
+/// The largest alignment accepted by `read_message_aligned()`: one page on most platforms.
+const MAX_SEGMENT_ALIGN: usize = 4096;
+
+/// Like `read_message()`, but places the start of every segment at an address that is a
+/// multiple of `align` bytes, e.g. for SIMD processing of segment contents. `align` must be a
+/// power of two no larger than 4096; values of eight or less need no adjustment, since words
+/// are already eight-byte aligned. The buffer is over-allocated by up to `align` bytes per
+/// segment.
+pub async fn read_message_aligned<R>(mut reader: R, options: message::ReaderOptions, align: usize)
+                                     -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin
+{
+    if !align.is_power_of_two() || align > MAX_SEGMENT_ALIGN {
+        return Err(Error::failed(
+            format!("Alignment must be a power of two no larger than {}, but is {}",
+                    MAX_SEGMENT_ALIGN, align)))
+    }
+    let (total_words, segment_slices) = match read_segment_table(&mut reader, options).await? {
+        Some(table) => table.into_parts(),
+        None => return Ok(None),
+    };
+    let align_words = ::std::cmp::max(align / 8, 1);
+    let buffer_words = (segment_slices.len() + 1).checked_mul(align_words - 1)
+        .and_then(|padding| padding.checked_add(total_words))
+        .ok_or_else(|| Error::failed(
+            format!("Message of {} words is too large to align to {} bytes", total_words, align)))?;
+    let mut owned_space: Vec<Word> = Word::allocate_zeroed_vec(buffer_words);
+
+    // Offset, in words, of the first aligned address in `owned_space`.
+    let base = {
+        let misalignment = owned_space.as_ptr() as usize % (align_words * 8);
+        if misalignment == 0 { 0 } else { (align_words * 8 - misalignment) / 8 }
+    };
+    let round_up = |words: usize| words.next_multiple_of(align_words);
+    let mut aligned_slices = Vec::with_capacity(segment_slices.len());
+    let mut next = base;
+    for &(start, end) in &segment_slices {
+        let len = end - start;
+        aligned_slices.push((next, next + len));
+        next = base + round_up(next + len - base);
+    }
+
+    for (&(start, end), &(body_start, _)) in aligned_slices.iter().zip(segment_slices.iter()) {
+        read_body_part(&mut reader, Word::words_to_bytes_mut(&mut owned_space[start..end]),
+                       body_start * 8, total_words * 8).await?;
+    }
+    let segments = OwnedSegments { segment_slices: aligned_slices, owned_space: owned_space };
+    Ok(Some(message::Reader::new(segments, options)))
+}
+
+/// A shared handle to the number of times each segment of an `InstrumentedSegments` has been
+/// accessed. It stays valid after the segments themselves are gone.
+#[derive(Clone, Debug)]
+pub struct SegmentAccessCounts {
+    counts: ::std::rc::Rc<Vec<::std::cell::Cell<u64>>>,
+}
+
+impl SegmentAccessCounts {
+    /// The number of times segment `id` has been accessed. Zero if there is no such segment.
+    pub fn get(&self, id: u32) -> u64 {
+        self.counts.get(id as usize).map(|count| count.get()).unwrap_or(0)
+    }
+
+    /// The number of times each segment has been accessed, indexed by segment id.
+    pub fn to_vec(&self) -> Vec<u64> {
+        self.counts.iter().map(|count| count.get()).collect()
+    }
+}
+
+/// Wraps `ReaderSegments`, counting how many times each segment is accessed while a message
+/// is traversed. Useful for finding out which parts of a pointer-heavy message count toward
+/// its traversal limit.
+pub struct InstrumentedSegments<S> where S: message::ReaderSegments {
+    inner: S,
+    counts: SegmentAccessCounts,
+}
+
+impl <S> InstrumentedSegments<S> where S: message::ReaderSegments {
+    pub fn new(inner: S) -> Self {
+        let counts = (0..inner.len()).map(|_| ::std::cell::Cell::new(0)).collect();
+        InstrumentedSegments { inner: inner,
+                               counts: SegmentAccessCounts { counts: ::std::rc::Rc::new(counts) } }
+    }
+
+    /// Returns a handle to the access counts.
+    pub fn counts(&self) -> SegmentAccessCounts {
+        self.counts.clone()
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl <S> message::ReaderSegments for InstrumentedSegments<S> where S: message::ReaderSegments {
+    fn get_segment<'a>(&'a self, id: u32) -> Option<&'a [Word]> {
+        if let Some(count) = self.counts.counts.get(id as usize) {
+            count.set(count.get() + 1);
+        }
+        self.inner.get_segment(id)
+    }
+
+    fn len(&self) -> usize {
+        self.counts.counts.len()
+    }
+}
+
+/// Like `read_message()`, but wraps the message's segments in `InstrumentedSegments`, and also
+/// returns a handle to their access counts.
+pub async fn read_message_instrumented<R>(reader: R, options: message::ReaderOptions)
+    -> Result<Option<(message::Reader<InstrumentedSegments<OwnedSegments>>, SegmentAccessCounts)>>
+    where R: AsyncRead + Unpin
+{
+    let segments = match read_message(reader, options).await? {
+        Some(message) => InstrumentedSegments::new(message.into_segments()),
+        None => return Ok(None),
+    };
+    let counts = segments.counts();
+    Ok(Some((message::Reader::new(segments, options), counts)))
+}
+
+/// A reader that may know how many bytes remain before the end of its data.
+pub trait LengthHint {
+    /// Returns the number of bytes remaining, or `None` if that is not known.
+    fn remaining_bytes(&self) -> Option<u64>;
+}
+
+impl <'a, R> LengthHint for &'a mut R where R: LengthHint {
+    fn remaining_bytes(&self) -> Option<u64> {
+        (**self).remaining_bytes()
+    }
+}
+
+impl <T> LengthHint for futures::io::Cursor<T> where T: AsRef<[u8]> {
+    fn remaining_bytes(&self) -> Option<u64> {
+        Some((self.get_ref().as_ref().len() as u64).saturating_sub(self.position()))
+    }
+}
+
+/// Like `read_message()`, but once the segment table has been read, checks the declared
+/// size of the message against `reader.remaining_bytes()`, failing without attempting to
+/// read the body if the reader does not have enough data left.
+pub async fn read_message_with_length_hint<R>(mut reader: R, options: message::ReaderOptions)
+                                              -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + LengthHint + Unpin
+{
+    let (total_words, segment_slices) = match read_segment_table(&mut reader, options).await? {
+        Some(table) => table.into_parts(),
+        None => return Ok(None),
+    };
+    if let Some(remaining) = reader.remaining_bytes() {
+        if total_words as u64 * 8 > remaining {
+            return Err(Error::failed(
+                format!("Message declares {} bytes, which exceeds the {} bytes of available data",
+                        total_words * 8, remaining)))
+        }
+    }
+    Ok(Some(read_segments(reader, total_words, segment_slices, options).await?))
+}
+
+const READ_CANCELLED: &str = "message read was cancelled";
+
+/// Like `read_message()`, but gives up as soon as `cancel` completes, returning an error for
+/// which `is_cancelled()` is true.
+///
+/// If the read is cancelled after some of the message has been consumed, `reader` is left
+/// positioned in the middle of a frame, and cannot be used to read further messages.
+pub async fn read_message_cancellable<R, F>(reader: R,
+                                            options: message::ReaderOptions,
+                                            cancel: F)
+                                            -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin, F: Future
+{
+    let read = read_message(reader, options);
+    futures::pin_mut!(read);
+    futures::pin_mut!(cancel);
+    match futures::future::select(read, cancel).await {
+        futures::future::Either::Left((result, _)) => result,
+        futures::future::Either::Right(_) => Err(Error::disconnected(READ_CANCELLED.to_string())),
+    }
+}
+
+/// Like `read_message()`, but returns an error if the first byte of the message does not
+/// arrive within `idle`. Once the message has started, the rest of it is read without any
+/// time limit. This suits servers that close idle connections but must tolerate slow
+/// transfers of large messages.
+///
+/// The timer is provided by `sleep`, which is called with `idle` and should return a future
+/// that completes once that much time has passed, e.g. `tokio::time::sleep`.
+pub async fn read_message_idle_timeout<R, S, F>(mut reader: R,
+                                                options: message::ReaderOptions,
+                                                idle: Duration,
+                                                sleep: S)
+                                                -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin, S: FnOnce(Duration) -> F, F: Future<Output=()>
+{
+    let mut first: [u8; 8] = [0; 8];
+    let n = {
+        let read = read_retrying(&mut reader, &mut first[..]);
+        let timeout = sleep(idle);
+        futures::pin_mut!(read);
+        futures::pin_mut!(timeout);
+        match futures::future::select(read, timeout).await {
+            futures::future::Either::Left((n, _)) => n?,
+            futures::future::Either::Right(_) => {
+                return Err(Error::overloaded(
+                    format!("No message started arriving within {:?}", idle)))
+            }
+        }
+    };
+    if n == 0 {
+        return Ok(None)
+    }
+    match read_message(futures::io::Cursor::new(&first[..n]).chain(reader), options).await? {
+        Some(m) => Ok(Some(m)),
+        None => unreachable!("at least one byte of the message has been read"),
+    }
+}
+
+/// Returns true if `error` was returned because a read was cancelled by
+/// `read_message_cancellable()`.
+pub fn is_cancelled(error: &Error) -> bool {
+    error.kind == capnp::ErrorKind::Disconnected && error.description == READ_CANCELLED
+}
+
+/// Decides which IO errors `read_message_with_retry()` retries, and how long it waits first.
+pub trait RetryPolicy {
+    /// Called when a read fails with `error`, where `attempt` counts the retries made so far
+    /// for this message. Returns how long to wait before retrying, or `None` to give up.
+    fn retry_after(&mut self, error: &::std::io::Error, attempt: u32) -> Option<Duration>;
+}
+
+impl <F> RetryPolicy for F where F: FnMut(&::std::io::Error, u32) -> Option<Duration> {
+    fn retry_after(&mut self, error: &::std::io::Error, attempt: u32) -> Option<Duration> {
+        self(error, attempt)
+    }
+}
+
+/// Remembers the last error returned by the wrapped reader, so that its original
+/// `std::io::Error` can be inspected after it has been converted to a `capnp::Error`.
+struct LastIoError<R> {
+    inner: R,
+    error: Option<::std::io::Error>,
+}
+
+impl <R> AsyncRead for LastIoError<R> where R: AsyncRead + Unpin {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+                 -> Poll<::std::io::Result<usize>>
+    {
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Err(e)) => {
+                let copy = ::std::io::Error::new(e.kind(), format!("{}", e));
+                self.error = Some(e);
+                Poll::Ready(Err(copy))
+            }
+            poll => poll,
+        }
+    }
+}
+
+/// Like `read_message()`, but retries reads that fail with an IO error that `policy` deems
+/// transient, e.g. `WouldBlock`. Before each retry it awaits `sleep` called with the delay
+/// returned by the policy. The bytes already read are kept, so a retry resumes the frame
+/// rather than restarting it. Errors that the policy declines to retry, and errors that do not
+/// come from the reader, such as a malformed segment table, are returned immediately.
+pub async fn read_message_with_retry<R, P, S, F>(reader: R,
+                                                 options: message::ReaderOptions,
+                                                 mut policy: P,
+                                                 mut sleep: S)
+                                                 -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin, P: RetryPolicy, S: FnMut(Duration) -> F, F: Future<Output=()>
+{
+    let mut reader = crate::MessageReader::new(LastIoError { inner: reader, error: None }, options);
+    let mut attempt = 0;
+    loop {
+        let e = match reader.read_message().await {
+            Ok(m) => return Ok(m),
+            Err(e) => e,
+        };
+        let delay = match reader.get_mut().error.take() {
+            Some(io_error) => policy.retry_after(&io_error, attempt),
+            None => None,
+        };
+        match delay {
+            Some(delay) => sleep(delay).await,
+            None => return Err(e),
+        }
+        attempt += 1;
+    }
+}
+
+/// The segment table of a message: the number of segments and the length of each.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SegmentTable {
+    total_words: usize,
+
+    /// The range of words occupied by each segment in the message body.
+    segment_slices: Vec<(usize, usize)>,
+}
+
+impl SegmentTable {
+    /// The number of segments in the message.
+    pub fn segment_count(&self) -> usize {
+        self.segment_slices.len()
+    }
+
+    /// The total number of words in all of the segments.
+    pub fn total_words(&self) -> usize {
+        self.total_words
+    }
+
+    /// The length in words of segment `idx`, or `None` if there is no such segment.
+    pub fn segment_len(&self, idx: usize) -> Option<usize> {
+        self.segment_slices.get(idx).map(|&(start, end)| end - start)
+    }
+
+    /// Iterates over the range of words occupied by each segment in the message body, in
+    /// order.
+    pub fn iter_ranges<'a>(&'a self) -> impl Iterator<Item=::std::ops::Range<usize>> + 'a {
+        self.segment_slices.iter().map(|&(start, end)| start..end)
+    }
+
+    pub(crate) fn into_parts(self) -> (usize, Vec<(usize, usize)>) {
+        (self.total_words, self.segment_slices)
+    }
+}
+
+/// Reads the segment table of the next message from `reader`, leaving `reader` positioned
+/// at the start of the message body. Returns `None` if the stream ends cleanly before the
+/// table. Returns an error if the table is malformed, or if the message would be too large
+/// to traverse under `options`.
+pub async fn read_segment_table<R>(reader: R,
+                                   options: message::ReaderOptions)
+                                   -> Result<Option<SegmentTable>>
+    where R: AsyncRead + Unpin
+{
+    read_framed_segment_table(reader, options, DEFAULT_READ_FRAMING).await
+}
+
+/// The limits that governed a read by `read_message_verbose()`, and how close the message
+/// came to them.
+#[derive(Clone, Debug)]
+pub struct EffectiveReadOptions {
+    /// The options that the message was read with.
+    pub options: message::ReaderOptions,
+
+    /// The segment table of the message that was read.
+    pub segment_table: SegmentTable,
+}
+
+impl EffectiveReadOptions {
+    /// The total number of words in the message's segments. The reader rejects a message
+    /// whose total exceeds `options.traversal_limit_in_words`, but traversing the message can
+    /// use up more than this, e.g. if a struct is read more than once.
+    pub fn total_words(&self) -> u64 {
+        self.segment_table.total_words() as u64
+    }
+
+    /// How many words of traversal are left under `options.traversal_limit_in_words` after
+    /// visiting each word of the message once.
+    pub fn traversal_headroom(&self) -> u64 {
+        self.options.traversal_limit_in_words - self.total_words()
+    }
+}
+
+/// Like `read_message()`, but also returns the options that the read was checked against,
+/// along with the message's segment table, to help with tuning limits against real traffic.
+/// A message that exceeds a limit is still rejected with an error, whose description ends
+/// with the limits that were in effect.
+pub async fn read_message_verbose<R>(mut reader: R, options: message::ReaderOptions)
+    -> Result<Option<(message::Reader<OwnedSegments>, EffectiveReadOptions)>>
+    where R: AsyncRead + Unpin
+{
+    let segment_table = match read_segment_table(&mut reader, options).await {
+        Ok(Some(table)) => table,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            return Err(Error {
+                kind: e.kind,
+                description: format!("{} (traversal_limit_in_words: {}, nesting_limit: {})",
+                                     e.description, options.traversal_limit_in_words,
+                                     options.nesting_limit),
+            })
+        }
+    };
+    let (total_words, segment_slices) = segment_table.clone().into_parts();
+    let message = read_segments(reader, total_words, segment_slices, options).await?;
+    Ok(Some((message, EffectiveReadOptions { options: options, segment_table: segment_table })))
+}
+
+async fn read_framed_segment_table<R>(mut reader: R,
+                                      options: message::ReaderOptions,
+                                      framing: ReadFraming)
+                                      -> Result<Option<SegmentTable>>
+    where R: AsyncRead + Unpin
+{
+    let mut buf: [u8; 8] = [0; 8];
+    {
+        let n = read_retrying(&mut reader, &mut buf[..]).await?;
+        if n == 0 {
+            return Ok(None)
+        } else if framing.trailing_zero_is_eof {
+            if !fill_first_word_or_zero_eof(&mut reader, &mut buf, n).await? {
+                return Ok(None)
+            }
+        } else if n < 8 {
+            read_exact_retrying(&mut reader, &mut buf[n..]).await?;
+        }
+    }
+    let (segment_count, first_segment_length) = parse_segment_table_first(&buf[..])?;
+    if framing.reject_empty_first_segment && first_segment_length == 0 {
+        return Err(Error::failed("First segment of message is empty".to_string()))
+    } else if segment_count > framing.max_segments {
+        return Err(Error::failed(
+            format!("Message has {} segments, but at most {} are allowed",
+                    segment_count, framing.max_segments)))
+    }
+
+    let mut segment_slices: Vec<(usize, usize)> = Vec::with_capacity(segment_count);
+    segment_slices.push((0,first_segment_length));
+    let mut total_words = first_segment_length;
+
+    // Now that the segment count is known, read the rest of the table, including padding, with
+    // a single `read_exact`, so that a reader which returns everything asked of it sees at most
+    // two reads per table. A table of up to three segments fits in `buf`, which is reused.
+    let rest_len = segment_table_len(segment_count) - 8;
+    if rest_len > 0 {
+        let mut large_buf = Vec::new();
+        let rest = if rest_len <= buf.len() {
+            &mut buf[..rest_len]
+        } else {
+            large_buf.resize(rest_len, 0u8);
+            &mut large_buf[..]
+        };
+        read_exact_retrying(&mut reader, rest).await?;
+        total_words = parse_segment_lengths(rest, segment_count - 1,
+                                            &mut segment_slices, total_words)?;
+    }
+
+    check_total_words(total_words, options)?;
+    if framing.reject_empty_message && total_words == 0 {
+        return Err(Error::failed("Message is empty".to_string()))
+    } else if total_words < framing.min_total_words || total_words > framing.max_total_words {
+        return Err(Error::failed(
+            format!("Message has {} words, but must have between {} and {}",
+                    total_words, framing.min_total_words, framing.max_total_words)))
+    }
+
+    Ok(Some(SegmentTable { total_words: total_words, segment_slices: segment_slices }))
+}
+
+/// Fills the rest of `buf`, the first word of a segment table, of which `filled` bytes have
+/// been read. Returns false if the stream turns out to end with nothing but zero bytes from
+/// the start of `buf`, consuming them. Returns an error if the stream ends otherwise before
+/// `buf` is full, or if nonzero bytes follow an all-zero `buf`.
+async fn fill_first_word_or_zero_eof<R>(mut reader: R, buf: &mut [u8; 8], mut filled: usize) -> Result<bool>
+    where R: AsyncRead + Unpin
+{
+    let all_zero = |bytes: &[u8]| bytes.iter().all(|&b| b == 0);
+    while filled < 8 {
+        let n = read_retrying(&mut reader, &mut buf[filled..]).await?;
+        if n == 0 {
+            if all_zero(&buf[..filled]) {
+                return Ok(false)
+            }
+            return Err(Error::failed("Premature end of stream in a segment table".to_string()))
+        }
+        filled += n;
+    }
+    if !all_zero(&buf[..]) {
+        return Ok(true)
+    }
+
+    let mut padding = [0u8; 64];
+    loop {
+        let n = read_retrying(&mut reader, &mut padding[..]).await?;
+        if n == 0 {
+            return Ok(false)
+        } else if !all_zero(&padding[..n]) {
+            return Err(Error::failed("Nonzero data after trailing zero padding".to_string()))
+        }
+    }
+}
+
+/// Parses `count` segment lengths from `buf`, which holds the part of a segment table after
+/// its first word. Appends the slice of each segment to `segment_slices`, and returns the
+/// updated total number of words.
+pub(crate) fn parse_segment_lengths(buf: &[u8],
+                                    count: usize,
+                                    segment_slices: &mut Vec<(usize, usize)>,
+                                    mut total_words: usize) -> Result<usize>
+{
+    for idx in 0..count {
+        let segment_len: usize =
+            segment_len_from_u32(u32::from_le_bytes(buf[(idx * 4)..(idx + 1) * 4].try_into().unwrap()))?;
+        let end = total_words.checked_add(segment_len).ok_or_else(|| {
+            Error::failed(format!("Message size overflows usize after segment {}", idx + 1))
+        })?;
+
+        segment_slices.push((total_words, end));
+        total_words = end;
+    }
+    Ok(total_words)
+}
+
+/// Converts a segment length read from a segment table, returning an error instead of
+/// truncating if it does not fit in `T` (as with `usize` on 16-bit targets).
+fn segment_len_from_u32<T>(len: u32) -> Result<T> where T: TryFrom<u32> {
+    len.try_into().map_err(|_| {
+        Error::failed(format!("Segment length {} does not fit in a {}-byte integer",
+                              len, ::std::mem::size_of::<T>()))
+    })
+}
+
+/// Returns an error if a message of `total_words` words could not be traversed under `options`.
+pub(crate) fn check_total_words(total_words: usize, options: message::ReaderOptions) -> Result<()> {
+    // Don't accept a message which the receiver couldn't possibly traverse without hitting the
+    // traversal limit. Without this check, a malicious client could transmit a very large segment
+    // size to make the receiver allocate excessive space and possibly crash.
+    if total_words as u64 > options.traversal_limit_in_words  {
+        return Err(Error::failed(
+            format!("Message has {} words, which is too large. To increase the limit on the \
+             receiving end, see capnp::message::ReaderOptions.", total_words)))
+    }
+    Ok(())
+}
+
+/// The number of bytes in the segment table of a message with `segment_count` segments,
+/// including padding.
+pub(crate) fn segment_table_len(segment_count: usize) -> usize {
+    ((segment_count + 2) & !1) * 4
+}
+
+/// Reads segments from `read`.
+async fn read_segments<R>(mut read: R,
+                    total_words: usize,
+                    segment_slices: Vec<(usize, usize)>,
+                    options: message::ReaderOptions)
+                    -> Result<message::Reader<OwnedSegments>>
+    where R: AsyncRead + Unpin
+{
+    let mut owned_space: Vec<Word> = Word::allocate_zeroed_vec(total_words);
+    read_body(&mut read, &mut owned_space[..]).await?;
+    let segments = OwnedSegments {segment_slices: segment_slices, owned_space: owned_space};
+    Ok(message::Reader::new(segments, options))
+}
+
+const TRUNCATED_BODY: &str = "Premature end of stream in the message body";
+
+/// The error for a stream that ends after `filled` of the `len` bytes of a message body, for
+/// which `is_truncated()` returns true.
+pub(crate) fn truncated_body(filled: usize, len: usize) -> Error {
+    Error::failed(format!("{}: got {} of {} bytes ({} of {} words)",
+                          TRUNCATED_BODY, filled, len, filled / 8, len / 8))
+}
+
+/// Fills `body` from `reader`. If the stream ends first, returns an error that says how much
+/// of the body arrived, and for which `is_truncated()` returns true.
+async fn read_body<R>(reader: &mut R, body: &mut [Word]) -> Result<()>
+    where R: AsyncRead + Unpin
+{
+    let len = body.len() * 8;
+    read_body_part(reader, Word::words_to_bytes_mut(body), 0, len).await
+}
+
+/// Like `read_body()`, but fills just `part` of a body of `len` bytes, of which `before` bytes
+/// have already been read.
+pub(crate) async fn read_body_part<R>(reader: &mut R, part: &mut [u8], before: usize, len: usize)
+                                      -> Result<()>
+    where R: AsyncRead + Unpin
+{
+    let mut filled = 0;
+    while filled < part.len() {
+        let n = read_retrying(reader, &mut part[filled..]).await?;
+        if n == 0 {
+            return Err(truncated_body(before + filled, len))
+        }
+        filled += n;
+    }
+    Ok(())
+}
+
+/// Returns true if `error` was returned because the stream ended partway through the body of
+/// a message, as opposed to any other failure.
+pub fn is_truncated(error: &Error) -> bool {
+    error.kind == capnp::ErrorKind::Failed && error.description.starts_with(TRUNCATED_BODY)
+}
+
+/// Like `AsyncReadExt::read()`, but retries the read if it is interrupted.
+pub(crate) async fn read_retrying<R>(reader: &mut R, buf: &mut [u8]) -> ::std::io::Result<usize>
+    where R: AsyncRead + Unpin
+{
+    loop {
+        match reader.read(buf).await {
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Like `AsyncReadExt::read_exact()`, but retries any read that is interrupted, as
+/// `std::io::Read::read_exact()` does.
+pub(crate) async fn read_exact_retrying<R>(reader: &mut R, mut buf: &mut [u8]) -> ::std::io::Result<()>
+    where R: AsyncRead + Unpin
+{
+    while !buf.is_empty() {
+        let n = read_retrying(reader, buf).await?;
+        if n == 0 {
+            return Err(::std::io::Error::new(::std::io::ErrorKind::UnexpectedEof,
+                                             "failed to fill whole buffer"))
+        }
+        buf = &mut buf[n..];
+    }
+    Ok(())
+}
+
+/// Parses the first word of the segment table.
+///
+/// The segment table format for streams is defined in the Cap'n Proto
+/// [encoding spec](https://capnproto.org/encoding.html#serialization-over-a-stream)
+///
+/// Returns the segment count and first segment length, or a state if the
+/// read would block.
+pub(crate) fn parse_segment_table_first(buf: &[u8]) -> Result<(usize, usize)>
+{
+    let segment_count = u32::from_le_bytes(buf[0..4].try_into().unwrap()).wrapping_add(1);
+    if segment_count >= 512 {
+        return Err(Error::failed(format!("Too many segments: {}", segment_count)))
+    } else if segment_count == 0 {
+        return Err(Error::failed(format!("Too few segments: {}", segment_count)))
+    }
+
+    let first_segment_len = segment_len_from_u32(u32::from_le_bytes(buf[4..8].try_into().unwrap()))?;
+    Ok((segment_count as usize, first_segment_len))
+}
+
+/// Something that contains segments ready to be written out.
+///
+/// If there are no segments, the message is written as a single empty segment.
+pub trait AsOutputSegments {
+    fn as_output_segments<'a>(&'a self) -> OutputSegments<'a>;
+}
+
+
+impl <'a, M> AsOutputSegments for &'a M where M: AsOutputSegments {
+    fn as_output_segments<'b>(&'b self) -> OutputSegments<'b> {
+        (*self).as_output_segments()
+    }
+}
+
+impl <A> AsOutputSegments for message::Builder<A> where A: message::Allocator {
+    fn as_output_segments<'a>(&'a self) -> OutputSegments<'a> {
+        self.get_segments_for_output()
+    }
+}
+
+impl <'a> AsOutputSegments for &'a [&'a [Word]] {
+    fn as_output_segments<'b>(&'b self) -> OutputSegments<'b> {
+        if self.len() == 1 {
+            OutputSegments::SingleSegment([self[0]])
+        } else {
+            OutputSegments::MultiSegment(self.to_vec())
+        }
+    }
+}
+
+// There is no separate impl for `&message::Builder<A>` or `&mut message::Builder<A>`: it
+// would conflict with the impls for `&M` and `&mut M`, which already cover those types.
+impl <'a, M> AsOutputSegments for &'a mut M where M: AsOutputSegments {
+    fn as_output_segments<'b>(&'b self) -> OutputSegments<'b> {
+        (**self).as_output_segments()
+    }
+}
+
+impl <A> AsOutputSegments for ::std::rc::Rc<message::Builder<A>> where A: message::Allocator {
+    fn as_output_segments<'a>(&'a self) -> OutputSegments<'a> {
+        self.get_segments_for_output()
+    }
+}
+
+/// The segments that an empty message is written as.
+const EMPTY_MESSAGE: &[&[Word]] = &[&[]];
+
+/// Returns true if `a` and `b` would be written as identical bytes, i.e. if they have the same
+/// number of segments and each pair of corresponding segments has the same contents.
+pub fn output_segments_equal<A, B>(a: A, b: B) -> bool
+    where A: AsOutputSegments, B: AsOutputSegments
+{
+    let a = a.as_output_segments();
+    let b = b.as_output_segments();
+    let a = if a.is_empty() { EMPTY_MESSAGE } else { &a[..] };
+    let b = if b.is_empty() { EMPTY_MESSAGE } else { &b[..] };
+    a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| a == b)
+}
+
+/// Writes the provided message to `writer`, preceded by the length in bytes of its frame
+/// (segment table plus segments) as a little-endian u32. Does not call `flush()`.
+pub async fn write_message_u32_prefixed<W, M>(mut writer: W, message: M) -> Result<()>
+    where W: AsyncWrite + Unpin, M: AsOutputSegments
+{
+    let frame_len = {
+        let segments = message.as_output_segments();
+        segment_table_len(segments.len()) as u64 +
+            segments.iter().map(|segment| segment.len() as u64 * 8).sum::<u64>()
+    };
+    if frame_len > ::std::u32::MAX as u64 {
+        return Err(Error::failed(
+            format!("Message of {} bytes is too large for a u32 length prefix", frame_len)))
+    }
+    writer.write_all(&(frame_len as u32).to_le_bytes()).await?;
+    write_message(writer, message).await
+}
+
+/// Reads a message written by `write_message_u32_prefixed()`. Returns an error if the length
+/// prefix does not match the size of the frame that follows it.
+pub async fn read_message_u32_prefixed<R>(mut reader: R, options: message::ReaderOptions)
+                                          -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin
+{
+    let mut prefix: [u8; 4] = [0; 4];
+    let n = read_retrying(&mut reader, &mut prefix[..]).await?;
+    if n == 0 {
+        return Ok(None)
+    }
+    read_exact_retrying(&mut reader, &mut prefix[n..]).await?;
+    let prefix = u32::from_le_bytes(prefix) as u64;
+
+    let (total_words, segment_slices) = match read_segment_table(&mut reader, options).await? {
+        Some(table) => table.into_parts(),
+        None => return Err(Error::failed("Premature end of stream after a length prefix".to_string())),
+    };
+    let frame_len = (segment_table_len(segment_slices.len()) + total_words * 8) as u64;
+    if prefix != frame_len {
+        return Err(Error::failed(
+            format!("Length prefix says {} bytes, but the frame has {}", prefix, frame_len)))
+    }
+    Ok(Some(read_segments(reader, total_words, segment_slices, options).await?))
+}
+
+/// The number of bytes that `write_message()` writes for `message`, including its segment
+/// table.
+pub fn serialized_size<M>(message: &M) -> usize where M: AsOutputSegments {
+    let segments = message.as_output_segments();
+    segment_table_len(::std::cmp::max(segments.len(), 1)) +
+        segments.iter().map(|segment| segment.len() * 8).sum::<usize>()
+}
+
+/// Serializes the provided message into `buf`, replacing its contents, and then writes it to
+/// `writer` in pieces of at most `chunk_size` bytes, yielding to the executor between pieces.
+/// Does not call `flush()`.
+///
+/// Yielding lets other tasks on the same thread make progress while a large message is being
+/// written to a slow writer, at the cost of extra wakeups and smaller writes. Prefer
+/// `write_message()` when throughput matters more than fairness. Reusing `buf` across calls
+/// avoids reallocating it for every message.
+pub async fn write_message_chunked<W, M>(mut writer: W, message: M, chunk_size: usize, buf: &mut Vec<u8>)
+                                         -> Result<()>
+    where W: AsyncWrite + Unpin, M: AsOutputSegments
+{
+    if chunk_size == 0 {
+        return Err(Error::failed("Chunk size must be nonzero".to_string()))
+    }
+    buf.clear();
+    buf.reserve(serialized_size(&message));
+    write_message(&mut *buf, message).await?;
+    for (idx, chunk) in buf.chunks(chunk_size).enumerate() {
+        if idx > 0 {
+            YieldNow { yielded: false }.await;
+        }
+        writer.write_all(chunk).await?;
+    }
+    Ok(())
+}
+
+/// A future that returns `Pending` once, waking itself, before completing.
+struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(())
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// Writes the provided message across several sinks: the segment table to `sinks[0]`, and
+/// each segment `i` to `sinks[stripe(i)]`, in segment order. Returns the number of bytes
+/// written to each sink. The message can be read back with `read_message_striped()` and the
+/// same `stripe` function. Returns an error if `sinks` is empty or `stripe` returns an
+/// out-of-range index. Does not call `flush()`.
+pub async fn write_message_striped<W, M, F>(sinks: &mut [W], message: M, mut stripe: F) -> Result<Vec<usize>>
+    where W: AsyncWrite + Unpin, M: AsOutputSegments, F: FnMut(usize) -> usize
+{
+    if sinks.is_empty() {
+        return Err(Error::failed("Cannot stripe a message across zero sinks".to_string()))
+    }
+    let segments = message.as_output_segments();
+    let mut written = vec![0; sinks.len()];
+    write_segment_table(&mut sinks[0], &segments[..]).await?;
+    written[0] += segment_table_len(::std::cmp::max(segments.len(), 1));
+    for (idx, segment) in segments.iter().enumerate() {
+        let sink = stripe(idx);
+        if sink >= sinks.len() {
+            return Err(Error::failed(
+                format!("Segment {} was assigned to sink {}, but there are only {} sinks",
+                        idx, sink, sinks.len())))
+        }
+        sinks[sink].write_all(Word::words_to_bytes(segment)).await?;
+        written[sink] += segment.len() * 8;
+    }
+    Ok(written)
+}
+
+/// Reads a message written by `write_message_striped()` with the same `stripe` function:
+/// the segment table from `sources[0]`, and each segment `i` from `sources[stripe(i)]`.
+/// Returns `None` if `sources[0]` ends cleanly before the segment table.
+pub async fn read_message_striped<R, F>(sources: &mut [R], options: message::ReaderOptions, mut stripe: F)
+                                        -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin, F: FnMut(usize) -> usize
+{
+    if sources.is_empty() {
+        return Err(Error::failed("Cannot read a striped message from zero sources".to_string()))
+    }
+    let (total_words, segment_slices) = match read_segment_table(&mut sources[0], options).await? {
+        Some(table) => table.into_parts(),
+        None => return Ok(None),
+    };
+    let mut owned_space: Vec<Word> = Word::allocate_zeroed_vec(total_words);
+    for (idx, &(start, end)) in segment_slices.iter().enumerate() {
+        let source = stripe(idx);
+        if source >= sources.len() {
+            return Err(Error::failed(
+                format!("Segment {} was assigned to source {}, but there are only {} sources",
+                        idx, source, sources.len())))
+        }
+        read_body_part(&mut sources[source], Word::words_to_bytes_mut(&mut owned_space[start..end]),
+                       start * 8, total_words * 8).await?;
+    }
+    let segments = OwnedSegments { segment_slices: segment_slices, owned_space: owned_space };
+    Ok(Some(message::Reader::new(segments, options)))
+}
+
+/// Writes the provided message to `writer`, preceded by a one-byte protocol `version`.
+/// Does not call `flush()`.
+pub async fn write_versioned_message<W, M>(mut writer: W, version: u8, message: M) -> Result<()>
+    where W: AsyncWrite + Unpin, M: AsOutputSegments
+{
+    writer.write_all(&[version]).await?;
+    write_message(writer, message).await
+}
+
+/// Reads a message written by `write_versioned_message()`, returning its version byte along
+/// with the message. Returns `None` if the stream ends cleanly before the version byte.
+pub async fn read_versioned_message<R>(mut reader: R, options: message::ReaderOptions)
+                                       -> Result<Option<(u8, message::Reader<OwnedSegments>)>>
+    where R: AsyncRead + Unpin
+{
+    let mut version: [u8; 1] = [0];
+    if read_retrying(&mut reader, &mut version[..]).await? == 0 {
+        return Ok(None)
+    }
+    match read_message(reader, options).await? {
+        Some(message) => Ok(Some((version[0], message))),
+        None => Err(Error::failed("Premature end of stream after a version byte".to_string())),
+    }
+}
+
+/// Metadata written before a message by `write_message_with_metadata()`.
+///
+/// On the wire, the header is a format version byte (currently 1), followed by `timestamp`
+/// as a little-endian u64, the length of `content_type` in bytes as a little-endian u16, and
+/// then the UTF-8 bytes of `content_type`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetaHeader {
+    /// A description of the message's contents, such as a MIME type. At most 65535 bytes.
+    pub content_type: String,
+
+    /// A caller-defined timestamp, such as milliseconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// The format version of the `MetaHeader` encoding.
+const META_HEADER_VERSION: u8 = 1;
+
+/// Writes `metadata` followed by the provided message to `writer`. Does not call `flush()`.
+pub async fn write_message_with_metadata<W, M>(mut writer: W, metadata: &MetaHeader, message: M)
+                                               -> Result<()>
+    where W: AsyncWrite + Unpin, M: AsOutputSegments
+{
+    let content_type = metadata.content_type.as_bytes();
+    if content_type.len() > ::std::u16::MAX as usize {
+        return Err(Error::failed(
+            format!("Content type of {} bytes is too long for a metadata header", content_type.len())))
+    }
+    let mut header = Vec::with_capacity(11 + content_type.len());
+    header.push(META_HEADER_VERSION);
+    header.extend_from_slice(&metadata.timestamp.to_le_bytes());
+    header.extend_from_slice(&(content_type.len() as u16).to_le_bytes());
+    header.extend_from_slice(content_type);
+    writer.write_all(&header).await?;
+    write_message(writer, message).await
+}
+
+/// Reads a message written by `write_message_with_metadata()`, returning its metadata along
+/// with the message. Returns `None` if the stream ends cleanly before the metadata header.
+/// Returns an error if the header has an unknown format version.
+pub async fn read_message_with_metadata<R>(mut reader: R, options: message::ReaderOptions)
+                                           -> Result<Option<(MetaHeader, message::Reader<OwnedSegments>)>>
+    where R: AsyncRead + Unpin
+{
+    let mut fixed: [u8; 11] = [0; 11];
+    let n = read_retrying(&mut reader, &mut fixed[..]).await?;
+    if n == 0 {
+        return Ok(None)
+    }
+    read_exact_retrying(&mut reader, &mut fixed[n..]).await?;
+    if fixed[0] != META_HEADER_VERSION {
+        return Err(Error::failed(format!("Unknown metadata header version: {}", fixed[0])))
+    }
+    let timestamp = u64::from_le_bytes(fixed[1..9].try_into().unwrap());
+    let content_type_len = u16::from_le_bytes(fixed[9..11].try_into().unwrap()) as usize;
+    let mut content_type = vec![0; content_type_len];
+    read_exact_retrying(&mut reader, &mut content_type[..]).await?;
+    let content_type = String::from_utf8(content_type).map_err(|_| {
+        Error::failed("Content type in metadata header is not valid UTF-8".to_string())
+    })?;
+
+    match read_message(reader, options).await? {
+        Some(message) => {
+            Ok(Some((MetaHeader { content_type: content_type, timestamp: timestamp }, message)))
+        }
+        None => Err(Error::failed("Premature end of stream after a metadata header".to_string())),
+    }
+}
+
+/// Writes the provided message to `writer`, followed by enough zero bytes to make the total
+/// `target_size` bytes. Useful for constant-bitrate channels, or to hide message sizes from
+/// an observer. Returns an error, without writing anything, if the message alone is larger
+/// than `target_size`. Does not call `flush()`.
+pub async fn write_message_padded<W, M>(mut writer: W, message: M, target_size: usize) -> Result<()>
+    where W: AsyncWrite + Unpin, M: AsOutputSegments
+{
+    let frame_len = serialized_size(&message);
+    if frame_len > target_size {
+        return Err(Error::failed(
+            format!("Message of {} bytes does not fit in a padded frame of {} bytes",
+                    frame_len, target_size)))
+    }
+    write_message(&mut writer, message).await?;
+
+    let zeros = [0u8; 64];
+    let mut remaining = target_size - frame_len;
+    while remaining > 0 {
+        let n = ::std::cmp::min(remaining, zeros.len());
+        writer.write_all(&zeros[..n]).await?;
+        remaining -= n;
+    }
+    Ok(())
+}
+
+/// Reads a message written by `write_message_padded()` with the same `target_size`, and
+/// skips the padding after it. Returns an error if the frame is larger than `target_size`,
+/// or if the padding contains nonzero bytes.
+pub async fn read_message_padded<R>(mut reader: R, options: message::ReaderOptions, target_size: usize)
+                                    -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin
+{
+    let (total_words, segment_slices) = match read_segment_table(&mut reader, options).await? {
+        Some(table) => table.into_parts(),
+        None => return Ok(None),
+    };
+    let frame_len = segment_table_len(segment_slices.len()) + total_words * 8;
+    if frame_len > target_size {
+        return Err(Error::failed(
+            format!("Frame of {} bytes is larger than the padded size of {} bytes",
+                    frame_len, target_size)))
+    }
+    let message = read_segments(&mut reader, total_words, segment_slices, options).await?;
+
+    let mut buf = [0u8; 64];
+    let mut remaining = target_size - frame_len;
+    while remaining > 0 {
+        let n = ::std::cmp::min(remaining, buf.len());
+        read_exact_retrying(&mut reader, &mut buf[..n]).await?;
+        if buf[..n].iter().any(|&b| b != 0) {
+            return Err(Error::failed("Message padding contains nonzero bytes".to_string()))
+        }
+        remaining -= n;
+    }
+    Ok(Some(message))
+}
+
+/// The number of zero bytes needed after stream offset `stream_pos` to reach a multiple of
+/// eight bytes.
+fn alignment_padding(stream_pos: u64) -> usize {
+    ((8 - stream_pos % 8) % 8) as usize
+}
+
+/// Writes the provided message to `writer` so that its frame starts at a stream offset that is
+/// a multiple of eight bytes, for peers that require this. `stream_pos` is the offset that
+/// `writer` is at; zero bytes are written to reach the next such offset, and `stream_pos` is
+/// advanced by the number of bytes written, including on error. Does not call `flush()`.
+///
+/// Unlike `read_message_aligned()`, which aligns segments in memory, this aligns frames in the
+/// stream. Read the messages back with `read_message_offset_aligned()`.
+pub async fn write_message_offset_aligned<W, M>(mut writer: W, message: M, stream_pos: &mut u64)
+                                                -> Result<()>
+    where W: AsyncWrite + Unpin, M: AsOutputSegments
+{
+    let zeros = [0u8; 8];
+    let padding = alignment_padding(*stream_pos);
+    writer.write_all(&zeros[..padding]).await?;
+    *stream_pos += padding as u64;
+    write_message_teed(writer, message, |bytes: &[u8]| *stream_pos += bytes.len() as u64).await
+}
+
+/// Reads a message written by `write_message_offset_aligned()`, skipping the zero bytes
+/// before the frame. `stream_pos` is the offset that `reader` is at, and is advanced by the
+/// number of bytes consumed, including on error. Returns `None` if the stream ends cleanly
+/// before the padding. Returns an error if the padding contains nonzero bytes, or if the
+/// stream ends anywhere after the first byte of padding.
+pub async fn read_message_offset_aligned<R>(mut reader: R,
+                                            options: message::ReaderOptions,
+                                            stream_pos: &mut u64)
+                                            -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin
+{
+    let mut buf = [0u8; 8];
+    let padding = alignment_padding(*stream_pos);
+    let mut filled = 0;
+    while filled < padding {
+        let n = read_retrying(&mut reader, &mut buf[filled..padding]).await?;
+        if n == 0 {
+            if filled == 0 {
+                return Ok(None)
+            }
+            return Err(Error::failed("Premature end of stream in alignment padding".to_string()))
+        }
+        filled += n;
+        *stream_pos += n as u64;
+    }
+    if buf[..padding].iter().any(|&b| b != 0) {
+        return Err(Error::failed("Alignment padding contains nonzero bytes".to_string()))
+    }
+    match read_message_teed(reader, options, |bytes: &[u8]| *stream_pos += bytes.len() as u64).await? {
+        None if padding > 0 =>
+            Err(Error::failed("Premature end of stream after alignment padding".to_string())),
+        result => Ok(result),
+    }
+}
+
+/// Writes the provided message to `writer`, preceded by its segment count encoded as an
+/// unsigned LEB128 varint. Does not call `flush()`.
+pub async fn write_message_varint_prefixed<W, M>(mut writer: W, message: M) -> Result<()>
+    where W: AsyncWrite + Unpin, M: AsOutputSegments
+{
+    let mut prefix = [0u8; 5];
+    let mut len = 0;
+    let mut value = ::std::cmp::max(message.as_output_segments().len(), 1) as u32;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            prefix[len] = byte;
+            len += 1;
+            break;
+        }
+        prefix[len] = byte | 0x80;
+        len += 1;
+    }
+    writer.write_all(&prefix[..len]).await?;
+    write_message(writer, message).await
+}
+
+/// Reads a message written by `write_message_varint_prefixed()`. Returns an error if the
+/// varint prefix does not match the segment count in the message's segment table.
+pub async fn read_message_varint_prefixed<R>(mut reader: R, options: message::ReaderOptions)
+                                             -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin
+{
+    let mut prefix: u64 = 0;
+    let mut idx = 0;
+    loop {
+        let mut byte = [0u8];
+        if read_retrying(&mut reader, &mut byte).await? == 0 {
+            if idx == 0 {
+                return Ok(None)
+            }
+            return Err(Error::failed("Premature end of stream in a varint prefix".to_string()))
+        }
+        prefix |= ((byte[0] & 0x7f) as u64) << (7 * idx);
+        idx += 1;
+        if byte[0] & 0x80 == 0 {
+            break;
+        } else if idx == 5 {
+            return Err(Error::failed("Varint prefix is too long".to_string()))
+        }
+    }
+
+    let (total_words, segment_slices) = match read_segment_table(&mut reader, options).await? {
+        Some(table) => table.into_parts(),
+        None => return Err(Error::failed("Premature end of stream after a varint prefix".to_string())),
+    };
+    if prefix != segment_slices.len() as u64 {
+        return Err(Error::failed(
+            format!("Varint prefix says {} segments, but the segment table has {}",
+                    prefix, segment_slices.len())))
+    }
+    Ok(Some(read_segments(reader, total_words, segment_slices, options).await?))
+}
+
+/// Writes the [canonical form](https://capnproto.org/encoding.html#canonicalization) of the
+/// provided message to `writer`, as a single segment. Does not call `flush()`.
+///
+/// To canonicalize the message, its segments are first interpreted as a message with the
+/// default `message::ReaderOptions`, so the message must be valid and within the default
+/// traversal and nesting limits. Unlike `write_message()`, this copies the whole message.
+///
+/// The bytes written depend only on the logical content of the message, not on how its
+/// segments were allocated, so this is the function to use for hashing or content addressing.
+/// Writing the original segments in some sorted order would not work: far pointers refer to
+/// their targets by segment id, so reordering the segments would leave them dangling.
+pub async fn write_message_canonical<W, M>(writer: W, message: M) -> Result<()>
+    where W: AsyncWrite + Unpin, M: AsOutputSegments
+{
+    let words = {
+        let segments = message.as_output_segments();
+        message::Reader::new(message::SegmentArray::new(&segments[..]),
+                             message::ReaderOptions::new()).canonicalize()?
+    };
+    let segments: &[&[Word]] = &[&words[..]];
+    write_message(writer, segments).await
+}
+
+/// Writes each of `messages` to `writer`, then flushes `writer` once at the end.
+///
+/// Returns the number of messages that were completely written, along with the result of the
+/// batch. If writing a message fails, the remaining messages are skipped and `writer` is not
+/// flushed.
+pub async fn write_message_batch<W, I>(mut writer: W, messages: I) -> (usize, Result<()>)
+    where W: AsyncWrite + Unpin, I: IntoIterator, I::Item: AsOutputSegments
+{
+    let mut count = 0;
+    for message in messages {
+        if let Err(e) = write_message(&mut writer, message).await {
+            return (count, Err(e))
+        }
+        count += 1;
+    }
+    (count, writer.flush().await.map_err(Error::from))
+}
+
+/// Like `write_message()`, but passes a copy of the bytes of the message's segments to
+/// `transform` before writing them, e.g. to encrypt them. The segment table is not
+/// transformed. `transform` works in place, so it must preserve the length of the data.
+/// Does not call `flush()`.
+pub async fn write_message_with_transform<W, M, F>(mut writer: W, message: M, transform: F) -> Result<()>
+    where W: AsyncWrite + Unpin, M: AsOutputSegments, F: FnOnce(&mut [u8]) -> Result<()>
+{
+    let (table, mut body) = {
+        let segments = message.as_output_segments();
+        let mut body = Vec::with_capacity(segments.iter().map(|segment| segment.len() * 8).sum());
+        for segment in segments.iter() {
+            body.extend_from_slice(Word::words_to_bytes(segment));
+        }
+        (segment_table_bytes(&segments[..])?, body)
+    };
+    transform(&mut body[..])?;
+    writer.write_all(&table).await?;
+    writer.write_all(&body).await?;
+    Ok(())
+}
+
+/// Seeks `writer` to byte `offset` and writes the provided message there. Returns the length
+/// of the frame in bytes. Does not call `flush()`.
+///
+/// If the seek fails, the returned error's description starts with "Failed to seek", so that
+/// it can be told apart from a failure to write.
+pub async fn write_message_at<W, M>(mut writer: W, offset: u64, message: M) -> Result<usize>
+    where W: AsyncWrite + AsyncSeek + Unpin, M: AsOutputSegments
+{
+    if let Err(e) = writer.seek(::std::io::SeekFrom::Start(offset)).await {
+        let e = Error::from(e);
+        return Err(Error { kind: e.kind,
+                           description: format!("Failed to seek to offset {}: {}",
+                                                offset, e.description) })
+    }
+    let frame_len = serialized_size(&message);
+    write_message(writer, message).await?;
+    Ok(frame_len)
+}
+
+/// Writes `messages` to `writer` as a bundle: a little-endian u32 count of messages, followed
+/// by each message with the standard framing. This is a custom container format, readable
+/// with `read_message_bundle()`, and not part of the standard stream framing. Does not call
+/// `flush()`.
+pub async fn write_message_bundle<W, M>(mut writer: W, messages: &[M]) -> Result<()>
+    where W: AsyncWrite + Unpin, M: AsOutputSegments
+{
+    if messages.len() > ::std::u32::MAX as usize {
+        return Err(Error::failed(format!("Too many messages for a bundle: {}", messages.len())))
+    }
+    writer.write_all(&(messages.len() as u32).to_le_bytes()).await?;
+    for message in messages {
+        write_message(&mut writer, message).await?;
+    }
+    Ok(())
+}
+
+/// Reads a bundle written by `write_message_bundle()`. Returns `None` if `reader` was already
+/// at end of stream, and an error if the stream ends before the counted number of messages
+/// has been read. Any file format made of a u32 batch header followed by that many frames
+/// can be read this way.
+///
+/// The read is all-or-nothing: if any message in the bundle is truncated or malformed, none
+/// of the bundle's messages are returned. A bundle can therefore serve as an atomic group of
+/// messages.
+pub async fn read_message_bundle<R>(mut reader: R, options: message::ReaderOptions)
+                                    -> Result<Option<Vec<message::Reader<OwnedSegments>>>>
+    where R: AsyncRead + Unpin
+{
+    let mut count: [u8; 4] = [0; 4];
+    let n = read_retrying(&mut reader, &mut count[..]).await?;
+    if n == 0 {
+        return Ok(None)
+    }
+    read_exact_retrying(&mut reader, &mut count[n..]).await?;
+    let count = u32::from_le_bytes(count) as usize;
+
+    // Don't trust `count` for preallocation.
+    let mut messages = Vec::new();
+    while messages.len() < count {
+        match read_message(&mut reader, options).await? {
+            Some(m) => messages.push(m),
+            None => {
+                return Err(Error::failed(
+                    format!("Premature end of stream after {} of {} bundled messages",
+                            messages.len(), count)))
+            }
+        }
+    }
+    Ok(Some(messages))
+}
+
+/// Writes the provided message to `writer`, then flushes and closes `writer`. This is useful
+/// for half-closing the write side of a duplex stream after sending a final message.
+pub async fn write_final_message<W, M>(mut writer: W, message: M) -> Result<()>
+    where W: AsyncWrite + Unpin, M: AsOutputSegments
+{
+    write_message(&mut writer, message).await?;
+    writer.flush().await?;
+    writer.close().await?;
+    Ok(())
+}
+
+/// Something that can be consumed to produce segments ready to be written out.
+///
+/// Every `AsOutputSegments` is trivially `IntoOutputSegments`. Implementing this trait
+/// directly allows a type to hand over ownership of its segment storage when written with
+/// `write_message_owned()`.
+pub trait IntoOutputSegments {
+    type Segments: AsOutputSegments;
+    fn into_output_segments(self) -> Self::Segments;
+}
+
+impl <M> IntoOutputSegments for M where M: AsOutputSegments {
+    type Segments = M;
+    fn into_output_segments(self) -> M {
+        self
+    }
+}
+
+/// Writes the provided message to `writer`, consuming it. Does not call `flush()`.
+pub async fn write_message_owned<W, M>(writer: W, message: M) -> Result<()>
+    where W: AsyncWrite + Unpin, M: IntoOutputSegments
+{
+    write_message(writer, message.into_output_segments()).await
+}
+
+/// Options controlling how frames are written to a stream.
+#[derive(Clone, Copy, Debug)]
+pub struct WriteFraming {
+    /// If true, the writer is flushed after the segment table and after each segment. This is
+    /// needed by writers that treat each flush as the end of a logical chunk, such as some
+    /// framed encryptors.
+    pub flush_between_segments: bool,
+}
+
+pub const DEFAULT_WRITE_FRAMING: WriteFraming =
+    WriteFraming { flush_between_segments: false };
+
+impl Default for WriteFraming {
+    fn default() -> WriteFraming {
+        DEFAULT_WRITE_FRAMING
+    }
+}
+
+impl WriteFraming {
+    pub fn new() -> WriteFraming { DEFAULT_WRITE_FRAMING }
+
+    pub fn flush_between_segments<'a>(&'a mut self, value: bool) -> &'a mut WriteFraming {
+        self.flush_between_segments = value;
+        self
+    }
+}
+
+/// Like `write_message()`, but writes according to `framing`.
+pub async fn write_message_with_framing<W, M>(mut writer: W, message: M, framing: WriteFraming)
+                                              -> Result<()>
+    where W: AsyncWrite + Unpin, M: AsOutputSegments
+{
+    if !framing.flush_between_segments {
+        return write_message(writer, message).await
+    }
+    let segments = message.as_output_segments();
+    write_segment_table(&mut writer, &segments[..]).await?;
+    writer.flush().await?;
+    for bytes in segment_bytes(&segments[..]) {
+        writer.write_all(bytes).await?;
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+/// Writes the provided message to `writer`. Does not call `flush()`.
+///
+/// The returned future is `Send` if `W` and `M` are `Send`.
+pub async fn write_message<W,M>(mut writer: W, message: M) -> Result<()>
+    where W: AsyncWrite + Unpin, M: AsOutputSegments
+{
+    let segments = message.as_output_segments();
+    #[cfg(feature = "debug-roundtrip")]
+    {
+        if cfg!(debug_assertions) {
+            return write_message_checked(writer, &segments[..]).await
+        }
+    }
+    write_segment_table(&mut writer, &segments[..]).await?;
+    write_segments(writer, &segments[..]).await?;
+    Ok(())
+}
+
+/// Like `write_message()`, but converts any error into the caller's error type `E`, saving a
+/// `map_err()` at every call site.
+pub async fn write_message_mapped<W, M, E>(writer: W, message: M) -> ::std::result::Result<(), E>
+    where W: AsyncWrite + Unpin, M: AsOutputSegments, E: From<Error>
+{
+    write_message(writer, message).await.map_err(E::from)
+}
+
+/// Serializes `segments` to a buffer, asserts that reading the buffer's segment table back
+/// yields the lengths of `segments`, and then writes the buffer to `writer`.
+#[cfg(feature = "debug-roundtrip")]
+async fn write_message_checked<W>(mut writer: W, segments: &[&[Word]]) -> Result<()>
+    where W: AsyncWrite + Unpin
+{
+    let mut buf = segment_table_bytes(segments)?;
+    for bytes in segment_bytes(segments) {
+        buf.extend_from_slice(bytes);
+    }
+
+    let mut options = message::ReaderOptions::new();
+    options.traversal_limit_in_words(::std::u64::MAX);
+    let (total_words, segment_slices) =
+        read_segment_table(futures::io::Cursor::new(&buf[..]), options).await
+            .expect("written segment table can be read")
+            .expect("segment table was written")
+            .into_parts();
+    let segments = if segments.is_empty() { EMPTY_MESSAGE } else { segments };
+    debug_assert_eq!(segments.len(), segment_slices.len());
+    for (&(start, end), segment) in segment_slices.iter().zip(segments.iter()) {
+        debug_assert_eq!(segment.len(), end - start);
+    }
+    debug_assert_eq!(buf.len(), segment_table_len(segments.len()) + total_words * 8);
+
+    writer.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Segment tables with more entries than this are streamed out in chunks of this many entries,
+/// rather than built in full before being written.
+const SEGMENT_TABLE_CHUNK_ENTRIES: usize = 64;
+
+async fn write_segment_table<W>(mut write: W, segments: &[&[Word]]) -> Result<()>
+    where W: AsyncWrite + Unpin
+{
+    if segments.len() < 4 {
+        // small enough that the table fits in a buffer on the stack
+        let mut buf: [u8; 16] = [0; 16];
+        let len = write_segment_table_into(&mut buf, segments)?;
+        write.write_all(&buf[..len]).await?;
+    } else if segment_table_len(segments.len()) / 4 <= SEGMENT_TABLE_CHUNK_ENTRIES {
+        write.write_all(&segment_table_bytes(segments)?).await?;
+    } else {
+        write_segment_table_chunked(write, segments).await?;
+    }
+    Ok(())
+}
+
+/// Like `write_segment_table()`, but encodes the table into a fixed-size buffer on the stack,
+/// one chunk at a time, so that memory use does not grow with the number of segments.
+async fn write_segment_table_chunked<W>(mut write: W, segments: &[&[Word]]) -> Result<()>
+    where W: AsyncWrite + Unpin
+{
+    let segment_len = |idx: usize| segments[idx].len();
+    let entries = segment_table_len(segments.len()) / 4;
+
+    // Check every entry first, so that an error never leaves a partial table behind.
+    for idx in 0..entries {
+        segment_table_entry(segments.len(), &segment_len, idx)?;
+    }
+    let mut buf = [0u8; SEGMENT_TABLE_CHUNK_ENTRIES * 4];
+    let mut start = 0;
+    while start < entries {
+        let end = ::std::cmp::min(start + SEGMENT_TABLE_CHUNK_ENTRIES, entries);
+        for idx in start..end {
+            let offset = (idx - start) * 4;
+            buf[offset..offset + 4].copy_from_slice(
+                &segment_table_entry(segments.len(), &segment_len, idx)?.to_le_bytes());
+        }
+        write.write_all(&buf[..(end - start) * 4]).await?;
+        start = end;
+    }
+    Ok(())
+}
+
+/// The bytes of each of `segments`, in the order in which they follow the segment table.
+fn segment_bytes<'a>(segments: &'a [&'a [Word]]) -> impl Iterator<Item=&'a [u8]> + 'a {
+    segments.iter().map(|segment| Word::words_to_bytes(segment))
+}
+
+/// Writes segments to `write`.
+async fn write_segments<W>(mut write: W, segments: &[&[Word]]) -> Result<()>
+    where W: AsyncWrite + Unpin
+{
+    for bytes in segment_bytes(segments) {
+        write.write_all(bytes).await?;
+    }
+    Ok(())
+}
+
+/// Writes the provided message to the synchronous `writer`, using the same framing as
+/// `write_message()`. Does not call `flush()`.
+pub fn write_message_sync<W, M>(mut writer: W, message: M) -> Result<()>
+    where W: ::std::io::Write, M: AsOutputSegments
+{
+    let segments = message.as_output_segments();
+    writer.write_all(&segment_table_bytes(&segments[..])?)?;
+    for bytes in segment_bytes(&segments[..]) {
+        writer.write_all(bytes)?;
+    }
+    Ok(())
+}
+
+/// Wraps an IO object, recording the time at which it first transfers any bytes.
+struct FirstByteTimer<T, C> where C: FnMut() -> Instant {
+    inner: T,
+    clock: C,
+    start: Option<Instant>,
+}
+
+impl <T, C> Unpin for FirstByteTimer<T, C> where C: FnMut() -> Instant {}
+
+impl <T, C> FirstByteTimer<T, C> where C: FnMut() -> Instant {
+    fn new(inner: T, clock: C) -> Self {
+        FirstByteTimer { inner: inner, clock: clock, start: None }
+    }
+
+    fn record(&mut self, result: &Poll<::std::io::Result<usize>>) {
+        if let Poll::Ready(Ok(n)) = result {
+            if *n > 0 && self.start.is_none() {
+                self.start = Some((self.clock)());
+            }
+        }
+    }
+
+    /// Time elapsed since the first byte was transferred, or zero if none have been.
+    fn elapsed(&mut self) -> Duration {
+        match self.start {
+            Some(start) => (self.clock)().checked_duration_since(start).unwrap_or_default(),
+            None => Duration::default(),
+        }
+    }
+}
+
+impl <T, C> AsyncRead for FirstByteTimer<T, C> where T: AsyncRead + Unpin, C: FnMut() -> Instant {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+                 -> Poll<::std::io::Result<usize>>
+    {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.record(&result);
+        result
+    }
+}
+
+impl <T, C> AsyncWrite for FirstByteTimer<T, C> where T: AsyncWrite + Unpin, C: FnMut() -> Instant {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+                  -> Poll<::std::io::Result<usize>>
+    {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.record(&result);
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<::std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<::std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Like `read_message()`, but also returns the time taken to read the message, measured with
+/// `clock` from the arrival of its first byte until the read completes. The time is zero if
+/// no bytes were read.
+pub async fn read_message_timed<R, C>(reader: R, options: message::ReaderOptions, clock: C)
+                                      -> (Result<Option<message::Reader<OwnedSegments>>>, Duration)
+    where R: AsyncRead + Unpin, C: FnMut() -> Instant
+{
+    let mut timer = FirstByteTimer::new(reader, clock);
+    let result = read_message(&mut timer, options).await;
+    (result, timer.elapsed())
+}
+
+/// Like `write_message()`, but also returns the time taken to write the message, measured
+/// with `clock` from when its first byte is accepted by `writer` until the write completes.
+pub async fn write_message_timed<W, M, C>(writer: W, message: M, clock: C) -> (Result<()>, Duration)
+    where W: AsyncWrite + Unpin, M: AsOutputSegments, C: FnMut() -> Instant
+{
+    let mut timer = FirstByteTimer::new(writer, clock);
+    let result = write_message(&mut timer, message).await;
+    (result, timer.elapsed())
+}
+
+/// Wraps an IO object, passing each chunk of bytes that it transfers to a sink.
+struct Tee<T, F> where F: FnMut(&[u8]) {
+    inner: T,
+    sink: F,
+}
+
+impl <T, F> Unpin for Tee<T, F> where F: FnMut(&[u8]) {}
+
+impl <T, F> AsyncRead for Tee<T, F> where T: AsyncRead + Unpin, F: FnMut(&[u8]) {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+                 -> Poll<::std::io::Result<usize>>
+    {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            if n > 0 { (self.sink)(&buf[..n]); }
+        }
+        result
+    }
+}
+
+impl <T, F> AsyncWrite for Tee<T, F> where T: AsyncWrite + Unpin, F: FnMut(&[u8]) {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+                  -> Poll<::std::io::Result<usize>>
+    {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            if n > 0 { (self.sink)(&buf[..n]); }
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<::std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<::std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Like `read_message()`, but also passes every chunk of raw bytes consumed from `reader`
+/// to `sink`, in order. Useful for mirroring the exact wire bytes, e.g. to a capture file.
+pub async fn read_message_teed<R, F>(reader: R, options: message::ReaderOptions, sink: F)
+                                     -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin, F: FnMut(&[u8])
+{
+    read_message(Tee { inner: reader, sink: sink }, options).await
+}
+
+/// Like `read_message()`, but also returns the number of bytes consumed from `reader`.
+///
+/// On error, this tells the caller whether the stream can still be used: if no bytes were
+/// consumed, the error happened at a frame boundary and the stream may be read from again
+/// (or closed cleanly). Otherwise, the error happened mid-frame and the stream is
+/// desynchronized.
+pub async fn read_message_counted<R>(reader: R, options: message::ReaderOptions)
+                                     -> (Result<Option<message::Reader<OwnedSegments>>>, u64)
+    where R: AsyncRead + Unpin
+{
+    let mut consumed = 0;
+    let result = read_message_teed(reader, options, |bytes: &[u8]| consumed += bytes.len() as u64).await;
+    (result, consumed)
+}
+
+/// Like `write_message()`, but also passes every chunk of raw bytes accepted by `writer`
+/// to `sink`, in order.
+pub async fn write_message_teed<W, M, F>(writer: W, message: M, sink: F) -> Result<()>
+    where W: AsyncWrite + Unpin, M: AsOutputSegments, F: FnMut(&[u8])
+{
+    write_message(Tee { inner: writer, sink: sink }, message).await
+}
+
+/// The tag of the checksum segment written by `write_message_with_checksum()`: "CRC1" as a
+/// little-endian u32.
+const CHECKSUM_SEGMENT_TAG: u32 = 0x3143_5243;
+
+/// Updates the CRC-32 (IEEE) `crc` with `bytes`.
+fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// The CRC-32 of a frame whose segments are `segments`, followed by a checksum segment: the
+/// segment table, which includes the checksum segment, and then every segment but that one.
+fn frame_checksum(segments: &[&[Word]]) -> Result<u32> {
+    let mut crc = crc32(0, &segment_table_bytes(segments)?);
+    for segment in &segments[..segments.len() - 1] {
+        crc = crc32(crc, Word::words_to_bytes(segment));
+    }
+    Ok(crc)
+}
+
+/// If the last segment of `segments` is a checksum segment, returns whether it matches the
+/// rest of the frame. Returns `None` if there is no checksum segment.
+pub(crate) fn check_frame_checksum(segments: &[&[Word]]) -> Option<bool> {
+    let last = match segments.last() {
+        Some(last) if segments.len() > 1 && last.len() == 1 => Word::words_to_bytes(last),
+        _ => return None,
+    };
+    if u32::from_le_bytes(last[0..4].try_into().unwrap()) != CHECKSUM_SEGMENT_TAG {
+        return None
+    }
+    // Segments that were read from a segment table can always be framed again.
+    Some(frame_checksum(segments).ok() == Some(u32::from_le_bytes(last[4..8].try_into().unwrap())))
+}
+
+/// Like `write_message()`, but appends to the frame an extra one-word segment holding the
+/// CRC-32 of the frame. Does not call `flush()`.
+///
+/// The checksum segment covers the segment table and all of the other segments. Since no
+/// pointer in the message refers to it, readers that don't know about it ignore it, and
+/// read the same message as if it weren't there. The segment holds a tag identifying it as a
+/// checksum, as a little-endian u32, followed by the checksum, also as a little-endian u32.
+/// Read the message back with `read_message_with_checksum()`.
+pub async fn write_message_with_checksum<W, M>(writer: W, message: M) -> Result<()>
+    where W: AsyncWrite + Unpin, M: AsOutputSegments
+{
+    let message_segments = message.as_output_segments();
+    let mut segments: Vec<&[Word]> = message_segments.iter().cloned().collect();
+    if segments.is_empty() {
+        // A message with no segments is framed as a single empty segment, so that the
+        // checksum segment is never the only one.
+        segments.push(&[]);
+    }
+    if segments.len() >= 511 {
+        return Err(Error::failed(
+            format!("Message has {} segments, leaving no room for a checksum segment", segments.len())))
+    }
+
+    // The checksum doesn't cover the contents of the checksum segment, only its length.
+    let placeholder = [capnp::word(0, 0, 0, 0, 0, 0, 0, 0)];
+    segments.push(&placeholder[..]);
+    let crc = frame_checksum(&segments)?;
+    segments.pop();
+
+    let mut checksum = [capnp::word(0, 0, 0, 0, 0, 0, 0, 0)];
+    {
+        let bytes = Word::words_to_bytes_mut(&mut checksum[..]);
+        bytes[0..4].copy_from_slice(&CHECKSUM_SEGMENT_TAG.to_le_bytes());
+        bytes[4..8].copy_from_slice(&crc.to_le_bytes());
+    }
+    segments.push(&checksum[..]);
+    write_message(writer, &segments[..]).await
+}
+
+/// Reads a message, checking the checksum segment appended by `write_message_with_checksum()`,
+/// if there is one. The checksum segment is not included in the returned segments.
+///
+/// If `verify` is true, a message without a checksum segment, or whose checksum does not
+/// match, is an error. If `verify` is false, a final segment that carries the checksum tag but
+/// does not match is returned as part of the message, since it may just be ordinary data.
+pub async fn read_message_with_checksum<R>(reader: R, options: message::ReaderOptions, verify: bool)
+                                           -> Result<Option<message::Reader<OwnedSegments>>>
+    where R: AsyncRead + Unpin
+{
+    let mut segments = match read_message(reader, options).await? {
+        Some(message) => message.into_segments(),
+        None => return Ok(None),
+    };
+    match check_frame_checksum(&segments.segment_slices()) {
+        Some(true) => {
+            if let Some((start, _)) = segments.segment_slices.pop() {
+                segments.owned_space.truncate(start);
+            }
+        }
+        Some(false) if verify => return Err(Error::failed("Checksum mismatch".to_string())),
+        None if verify => return Err(Error::failed("Message has no checksum segment".to_string())),
+        _ => (),
+    }
+    Ok(Some(message::Reader::new(segments, options)))
+}
+
+/// Like `write_message()`, but also feeds every byte of the frame to `hasher` as it is
+/// written, and returns the final hash. Does not call `flush()`.
+///
+/// The bytes may reach `hasher` in chunks of any size, so to get the same hash when rehashing
+/// the frame later, use a hasher whose result does not depend on how its input is split
+/// across calls to `write()`. If the hash is stored, e.g. alongside the frame on disk, also
+/// use a hasher with a fixed algorithm, such as SipHash-1-3 from the `siphasher` crate:
+/// the algorithm of `std::collections::hash_map::DefaultHasher` may change between Rust
+/// releases, so its hashes are only comparable within one build.
+pub async fn write_message_hashed<W, M, H>(writer: W, message: M, mut hasher: H) -> Result<u64>
+    where W: AsyncWrite + Unpin, M: AsOutputSegments, H: ::std::hash::Hasher
+{
+    write_message_teed(writer, message, |bytes: &[u8]| hasher.write(bytes)).await?;
+    Ok(hasher.finish())
+}
+
+/// Constructs the segment table for `segments`, including any padding. Returns an error if a
+/// segment is too large to be framed.
+pub(crate) fn segment_table_bytes(segments: &[&[Word]]) -> Result<Vec<u8>> {
+    let mut buf = vec![0; segment_table_len(segments.len())];
+    write_segment_table_into(&mut buf, segments)?;
+    Ok(buf)
+}
+
+/// Writes the segment table for `segments`, including any padding, to the start of `buf`.
+/// Returns the number of bytes written, or an error if `buf` is too small. Does not allocate.
+pub fn write_segment_table_into(buf: &mut [u8], segments: &[&[Word]]) -> Result<usize> {
+    encode_segment_table(buf, segments.len(), |idx| segments[idx].len())
+}
+
+/// Lays out the whole frame for `message`, segment table and segments, at the start of `buf`.
+/// Returns the number of words used, or an error if `buf` is too small. Does not allocate,
+/// so a caller can serialize messages entirely in static memory and then write the first
+/// words of `buf` however it likes.
+pub fn serialize_into_words<M>(buf: &mut [Word], message: M) -> Result<usize>
+    where M: AsOutputSegments
+{
+    let total_words = serialized_size(&message) / 8;
+    if buf.len() < total_words {
+        return Err(Error::failed(
+            format!("Message needs {} words, but the buffer only has {}", total_words, buf.len())))
+    }
+    let segments = message.as_output_segments();
+    let table_words = write_segment_table_into(Word::words_to_bytes_mut(&mut buf[..]), &segments[..])? / 8;
+    let mut offset = table_words;
+    for segment in &segments[..] {
+        buf[offset..offset + segment.len()].copy_from_slice(segment);
+        offset += segment.len();
+    }
+    Ok(offset)
+}
+
+/// Writes the segment table for a message whose segments have the given lengths, in words.
+/// The caller is then responsible for writing the segments themselves. An empty
+/// `segment_lengths` is treated as a single empty segment. Does not call `flush()`.
+pub async fn write_segment_table_for_lengths<W>(mut writer: W, segment_lengths: &[usize]) -> Result<()>
+    where W: AsyncWrite + Unpin
+{
+    let mut buf = vec![0; segment_table_len(segment_lengths.len())];
+    encode_segment_table(&mut buf, segment_lengths.len(), |idx| segment_lengths[idx])?;
+    writer.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Writes a segment table that declares exactly `declared_lengths`, with no checks and no
+/// relation to any actual segments. Does not call `flush()`.
+///
+/// **This is a fault-injection hook.** It can easily produce frames that do not match the
+/// bodies written after them, or that no reader will accept: an empty `declared_lengths`, for
+/// example, is written as a table with zero segments. It is intended for testing how readers
+/// handle malformed input, and for advanced framing schemes; use
+/// `write_segment_table_for_lengths()` for ordinary hand-built frames.
+pub async fn write_segment_table_raw<W>(mut writer: W, declared_lengths: &[u32]) -> Result<()>
+    where W: AsyncWrite + Unpin
+{
+    let mut buf = vec![0; segment_table_len(declared_lengths.len())];
+    buf[0..4].copy_from_slice(&(declared_lengths.len() as u32).wrapping_sub(1).to_le_bytes());
+    for (idx, length) in declared_lengths.iter().enumerate() {
+        buf[(idx + 1) * 4..(idx + 2) * 4].copy_from_slice(&length.to_le_bytes());
+    }
+    writer.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Encodes the segment table for `segment_count` segments, where segment `idx` has
+/// `segment_len(idx)` words, to the start of `buf`. Returns the number of bytes written.
+fn encode_segment_table<F>(buf: &mut [u8], segment_count: usize, segment_len: F) -> Result<usize>
+    where F: Fn(usize) -> usize
+{
+    let len = segment_table_len(segment_count);
+    if buf.len() < len {
+        return Err(Error::failed(
+            format!("Segment table needs {} bytes, but the buffer only has {}", len, buf.len())))
+    }
+    for idx in 0..len / 4 {
+        buf[idx * 4..(idx + 1) * 4].copy_from_slice(
+            &segment_table_entry(segment_count, &segment_len, idx)?.to_le_bytes());
+    }
+    Ok(len)
+}
+
+/// The four-byte entry at index `idx` of the segment table for a message with `segment_count`
+/// segments, whose lengths are given by `segment_len`: the segment count minus one, then the
+/// length of each segment, then padding.
+fn segment_table_entry<F>(segment_count: usize, segment_len: &F, idx: usize) -> Result<u32>
+    where F: Fn(usize) -> usize
+{
+    if idx == 0 {
+        // A message with no segments is framed as a single empty segment.
+        Ok(::std::cmp::max(segment_count, 1) as u32 - 1)
+    } else if idx <= segment_count {
+        let len = segment_len(idx - 1);
+        if len > ::std::u32::MAX as usize {
+            return Err(Error::failed(format!("Segment {} is too large: {} words", idx - 1, len)))
+        }
+        Ok(len as u32)
+    } else {
+        Ok(0) // padding
+    }
+}
+
+/// A resumable write of a single message.
+///
+/// Unlike the future returned by `write_message()`, a `MessageWriter` keeps its position
+/// in the segment table and segments as explicit state, so each poll picks up exactly where
+/// the previous one stopped and `bytes_written()` reports how far the write has gotten.
+/// Does not call `flush()`.
+///
+/// If the message can't be framed, e.g. because a segment is too large, the first poll
+/// returns an error without writing anything.
+///
+/// A `MessageWriter` is `Send` if `W` and `M` are `Send`.
+#[must_use = "futures do nothing unless polled"]
+pub struct MessageWriter<W, M> where W: AsyncWrite + Unpin, M: AsOutputSegments {
+    writer: W,
+    message: M,
+    table: Vec<u8>,
+
+    /// The error from encoding the segment table, if any, to be returned by the first poll.
+    error: Option<Error>,
+
+    /// Zero for the segment table, `i + 1` for segment `i`.
+    piece: usize,
+
+    /// Number of bytes of the current piece that have been written.
+    offset: usize,
+
+    bytes_written: usize,
+}
+
+impl <W, M> Unpin for MessageWriter<W, M> where W: AsyncWrite + Unpin, M: AsOutputSegments {}
+
+impl <W, M> MessageWriter<W, M> where W: AsyncWrite + Unpin, M: AsOutputSegments {
+    pub fn new(writer: W, message: M) -> Self {
+        let (table, error) = match segment_table_bytes(&message.as_output_segments()) {
+            Ok(table) => (table, None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
+        MessageWriter { writer: writer, message: message, table: table, error: error,
+                        piece: 0, offset: 0, bytes_written: 0 }
+    }
+
+    /// The number of bytes, including the segment table, that have been written so far.
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written
+    }
+
+    /// The total number of bytes that this writer will write.
+    pub fn total_bytes(&self) -> usize {
+        self.table.len() + self.message.as_output_segments().iter()
+            .map(|segment| segment.len() * 8).sum::<usize>()
+    }
+
+    /// Returns the wrapped writer and message. Any unwritten part of the message is discarded.
+    pub fn into_inner(self) -> (W, M) {
+        (self.writer, self.message)
+    }
+}
+
+impl <W, M> Future for MessageWriter<W, M> where W: AsyncWrite + Unpin, M: AsOutputSegments {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        if let Some(e) = this.error.take() {
+            return Poll::Ready(Err(e))
+        }
+        let segments = this.message.as_output_segments();
+        loop {
+            let buf = if this.piece == 0 {
+                &this.table[..]
+            } else if this.piece <= segments.len() {
+                Word::words_to_bytes(segments[this.piece - 1])
+            } else {
+                return Poll::Ready(Ok(()))
+            };
+
+            if this.offset == buf.len() {
+                this.piece += 1;
+                this.offset = 0;
+                continue;
+            }
+
+            match Pin::new(&mut this.writer).poll_write(cx, &buf[this.offset..]) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(Error::failed(
+                        "failed to write whole message: writer returned zero bytes".to_string())))
+                }
+                Poll::Ready(Ok(n)) => {
+                    this.offset += n;
+                    this.bytes_written += n;
+                }
+            }
+        }
+    }
+}
+
+/// Writes a message in two separately awaited steps: first its segment table, with
+/// `write_table()`, and then its segments, with `write_body()`. Lets a caller interleave
+/// other work between the two. Does not call `flush()`.
+pub struct MessagePartsWriter<W, M> where W: AsyncWrite + Unpin, M: AsOutputSegments {
+    writer: W,
+    message: M,
+    table_written: bool,
+    body_written: bool,
+}
+
+impl <W, M> MessagePartsWriter<W, M> where W: AsyncWrite + Unpin, M: AsOutputSegments {
+    pub fn new(writer: W, message: M) -> Self {
+        MessagePartsWriter { writer: writer, message: message,
+                             table_written: false, body_written: false }
+    }
+
+    /// Writes the segment table. Returns an error if it has already been written.
+    pub async fn write_table(&mut self) -> Result<()> {
+        if self.table_written {
+            return Err(Error::failed("Segment table has already been written".to_string()))
+        }
+        write_segment_table(&mut self.writer, &self.message.as_output_segments()[..]).await?;
+        self.table_written = true;
+        Ok(())
+    }
+
+    /// Writes the segments. Returns an error if the segment table has not been written yet,
+    /// or if the segments have already been written.
+    pub async fn write_body(&mut self) -> Result<()> {
+        if !self.table_written {
+            return Err(Error::failed("Segment table must be written before the body".to_string()))
+        } else if self.body_written {
+            return Err(Error::failed("Message body has already been written".to_string()))
+        }
+        write_segments(&mut self.writer, &self.message.as_output_segments()[..]).await?;
+        self.body_written = true;
+        Ok(())
+    }
+
+    /// Returns the wrapped writer and message.
+    pub fn into_inner(self) -> (W, M) {
+        (self.writer, self.message)
+    }
+}
+
+
+
+
+#[cfg(test)]
+pub mod test {
+    use std::cmp;
+    use std::io::{self, Read, Write};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures::{AsyncRead, AsyncWrite, Future};
+    use futures::io::Cursor;
+
+    use quickcheck::{quickcheck, TestResult};
+
+    use capnp::{Word, message, OutputSegments};
+    use capnp::message::ReaderSegments;
+
+    use super::{
+        AsOutputSegments,
+        MetaHeader,
+        MessagePartsWriter,
+        MessageWriter,
+        OwnedSegments,
+        ReadFraming,
+        SegmentTable,
+        WriteFraming,
+        crc32,
+        is_cancelled,
+        is_truncated,
+        output_segments_equal,
+        read_all_messages,
+        read_all_messages_recycling,
+        read_all_typed,
+        read_message,
+        read_message_aligned,
+        read_message_bundle,
+        read_message_cancellable,
+        read_message_canonical,
+        read_message_counted,
+        read_message_fair,
+        read_message_fixed_layout,
+        read_message_from_pool,
+        read_message_padded,
+        read_message_idle_timeout,
+        read_message_instrumented,
+        read_message_limited_reader,
+        read_message_mapped,
+        read_message_offset_aligned,
+        read_message_reusing,
+        read_message_stream_indexed,
+        read_message_streaming,
+        read_message_striped,
+        read_message_teed,
+        read_message_timed,
+        read_message_u32_prefixed,
+        read_message_varint_prefixed,
+        read_message_with_transform,
+        read_message_with_framing,
+        read_message_with_length_hint,
+        read_message_with_metadata,
+        read_message_verbose,
+        read_message_with_checksum,
+        read_message_with_progress,
+        read_message_with_retry,
+        read_messages_within,
+        read_segment_table,
+        read_segments_only,
+        reframe_single_segment,
+        read_stream_header,
+        validate_frames,
+        read_versioned_message,
+        serialized_size,
+        segment_len_from_u32,
+        segment_table_bytes,
+        serialize_into_words,
+        write_final_message,
+        write_message_at,
+        write_message,
+        write_message_batch,
+        write_message_bundle,
+        write_message_canonical,
+        write_message_hashed,
+        write_message_mapped,
+        write_message_offset_aligned,
+        write_message_with_checksum,
+        write_message_chunked,
+        write_message_padded,
+        write_message_sync,
+        write_message_striped,
+        write_message_teed,
+        write_message_timed,
+        write_message_u32_prefixed,
+        write_message_varint_prefixed,
+        write_message_with_framing,
+        write_message_with_metadata,
+        write_message_with_transform,
+        write_segment_table_for_lengths,
+        write_segment_table_into,
+        write_segment_table_raw,
+        write_stream_header,
+        write_versioned_message,
+    };
+
+    #[test]
+    fn test_read_segment_table() {
+        let mut exec = futures::executor::LocalPool::new();
+        let mut buf = vec![];
+
+        buf.extend([0,0,0,0, // 1 segments
+                    0,0,0,0] // 0 length
+                    .iter().cloned());
+        let (words, segment_slices) = exec.run_until(read_segment_table(Cursor::new(&buf[..]),
+                                                                        message::ReaderOptions::new())).unwrap().unwrap().into_parts();
+        assert_eq!(0, words);
+        assert_eq!(vec![(0,0)], segment_slices);
+        buf.clear();
 
         buf.extend([0,0,0,0, // 1 segments
                     1,0,0,0] // 1 length
                    .iter().cloned());
 
-        let (words, segment_slices) = exec.run_until(read_segment_table(&mut Cursor::new(&buf[..]),
-                                                                        message::ReaderOptions::new())).unwrap().unwrap();
-        assert_eq!(1, words);
-        assert_eq!(vec![(0,1)], segment_slices);
-        buf.clear();
+        let (words, segment_slices) = exec.run_until(read_segment_table(&mut Cursor::new(&buf[..]),
+                                                                        message::ReaderOptions::new())).unwrap().unwrap().into_parts();
+        assert_eq!(1, words);
+        assert_eq!(vec![(0,1)], segment_slices);
+        buf.clear();
+
+        buf.extend([1,0,0,0, // 2 segments
+                    1,0,0,0, // 1 length
+                    1,0,0,0, // 1 length
+                    0,0,0,0] // padding
+                    .iter().cloned());
+        let (words, segment_slices) = exec.run_until(read_segment_table(&mut Cursor::new(&buf[..]),
+                                                                        message::ReaderOptions::new())).unwrap().unwrap().into_parts();
+        assert_eq!(2, words);
+        assert_eq!(vec![(0,1), (1, 2)], segment_slices);
+        buf.clear();
+
+        buf.extend([2,0,0,0, // 3 segments
+                    1,0,0,0, // 1 length
+                    1,0,0,0, // 1 length
+                    0,1,0,0] // 256 length
+                    .iter().cloned());
+        let (words, segment_slices) = exec.run_until(read_segment_table(&mut Cursor::new(&buf[..]),
+                                                                        message::ReaderOptions::new())).unwrap().unwrap().into_parts();
+        assert_eq!(258, words);
+        assert_eq!(vec![(0,1), (1, 2), (2, 258)], segment_slices);
+        buf.clear();
+
+        buf.extend([3,0,0,0,  // 4 segments
+                    77,0,0,0, // 77 length
+                    23,0,0,0, // 23 length
+                    1,0,0,0,  // 1 length
+                    99,0,0,0, // 99 length
+                    0,0,0,0]  // padding
+                    .iter().cloned());
+        let (words, segment_slices) = exec.run_until(read_segment_table(&mut Cursor::new(&buf[..]),
+                                                                        message::ReaderOptions::new())).unwrap().unwrap().into_parts();
+        assert_eq!(200, words);
+        assert_eq!(vec![(0,77), (77, 100), (100, 101), (101, 200)], segment_slices);
+        buf.clear();
+    }
+
+    #[test]
+    fn segment_table_accessors() {
+        let buf = [2,0,0,0, // 3 segments
+                   1,0,0,0, // 1 length
+                   0,0,0,0, // 0 length
+                   4,0,0,0]; // 4 length
+        let table: SegmentTable = futures::executor::block_on(
+            read_segment_table(Cursor::new(&buf[..]), message::ReaderOptions::new())).unwrap().unwrap();
+        assert_eq!(3, table.segment_count());
+        assert_eq!(5, table.total_words());
+        assert_eq!(Some(1), table.segment_len(0));
+        assert_eq!(Some(0), table.segment_len(1));
+        assert_eq!(Some(4), table.segment_len(2));
+        assert_eq!(None, table.segment_len(3));
+        assert_eq!(vec![0..1, 1..1, 1..5], table.iter_ranges().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn raw_segment_table_is_written_as_declared() {
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_segment_table_raw(&mut buf, &[5, 0x0102])).unwrap();
+        assert_eq!(vec![1,0,0,0, 5,0,0,0, 2,1,0,0, 0,0,0,0], buf);
+
+        // A frame whose declared first segment is longer than its body.
+        buf.clear();
+        futures::executor::block_on(write_segment_table_raw(&mut buf, &[3])).unwrap();
+        buf.extend_from_slice(&[0; 8]);
+        assert!(futures::executor::block_on(
+            read_message(Cursor::new(&buf[..]), message::ReaderOptions::new())).is_err());
+
+        buf.clear();
+        futures::executor::block_on(write_segment_table_raw(&mut buf, &[])).unwrap();
+        assert_eq!(vec![255,255,255,255, 0,0,0,0], buf);
+        assert!(futures::executor::block_on(
+            read_segment_table(Cursor::new(&buf[..]), message::ReaderOptions::new())).is_err());
+    }
+
+    #[test]
+    fn segment_table_takes_at_most_two_reads() {
+        struct CountingRead<'a> {
+            read: &'a [u8],
+            reads: usize,
+        }
+
+        impl <'a> AsyncRead for CountingRead<'a> {
+            fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+                self.reads += 1;
+                let n = self.read.read(buf)?;
+                Poll::Ready(Ok(n))
+            }
+        }
+
+        // (segment count, expected number of reads)
+        for &(segment_count, expected) in &[(1, 1), (2, 2), (3, 2), (4, 2), (9, 2)] {
+            let segments: Vec<Vec<Word>> =
+                (0..segment_count).map(|_| vec![capnp::word(1,0,0,0,0,0,0,0); 1]).collect();
+            let mut buf = Vec::new();
+            futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+            let mut reader = CountingRead { read: &buf[..], reads: 0 };
+            let table = futures::executor::block_on(
+                read_segment_table(&mut reader, message::ReaderOptions::new())).unwrap().unwrap();
+            assert_eq!(segment_count, table.segment_count());
+            assert_eq!(expected, reader.reads);
+        }
+    }
+
+    #[test]
+    fn test_read_invalid_segment_table() {
+        let mut exec = futures::executor::LocalPool::new();
+        let mut buf = vec![];
+
+        buf.extend([0,2,0,0].iter().cloned()); // 513 segments
+        buf.extend([0; 513 * 4].iter().cloned());
+        assert!(exec.run_until(read_segment_table(Cursor::new(&buf[..]),
+                                                  message::ReaderOptions::new())).is_err());
+        buf.clear();
+
+        buf.extend([0,0,0,0].iter().cloned()); // 1 segments
+        assert!(exec.run_until(read_segment_table(Cursor::new(&buf[..]),
+                                                  message::ReaderOptions::new())).is_err());
+
+        buf.clear();
+
+        buf.extend([0,0,0,0].iter().cloned()); // 1 segments
+        buf.extend([0; 3].iter().cloned());
+        assert!(exec.run_until(read_segment_table(Cursor::new(&buf[..]),
+                                                  message::ReaderOptions::new())).is_err());
+        buf.clear();
+
+        buf.extend([255,255,255,255].iter().cloned()); // 0 segments
+        assert!(exec.run_until(read_segment_table(Cursor::new(&buf[..]),
+                                                  message::ReaderOptions::new())).is_err());
+        buf.clear();
+    }
+
+    fn construct_segment_table(segments: &[&[Word]]) -> Vec<u8> {
+        let mut exec = futures::executor::LocalPool::new();
+        let mut buf = vec![];
+        exec.run_until(super::write_segment_table(&mut buf, segments)).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_construct_segment_table() {
+
+        let segment_0: [Word; 0] = [];
+        let segment_1 = [capnp::word(1,0,0,0,0,0,0,0); 1];
+        let segment_199 = [capnp::word(199,0,0,0,0,0,0,0); 199];
+
+        let buf = construct_segment_table(&[&segment_0]);
+        assert_eq!(&[0,0,0,0,  // 1 segments
+                     0,0,0,0], // 0 length
+                   &buf[..]);
+
+        let buf = construct_segment_table(&[&segment_1]);
+        assert_eq!(&[0,0,0,0,  // 1 segments
+                     1,0,0,0], // 1 length
+                   &buf[..]);
+
+        let buf = construct_segment_table(&[&segment_199]);
+        assert_eq!(&[0,0,0,0,    // 1 segments
+                     199,0,0,0], // 199 length
+                   &buf[..]);
+
+        let buf = construct_segment_table(&[&segment_0, &segment_1]);
+        assert_eq!(&[1,0,0,0,  // 2 segments
+                     0,0,0,0,  // 0 length
+                     1,0,0,0,  // 1 length
+                     0,0,0,0], // padding
+                   &buf[..]);
+
+        let buf = construct_segment_table(&[&segment_199, &segment_1, &segment_199, &segment_0]);
+        assert_eq!(&[3,0,0,0,   // 4 segments
+                     199,0,0,0, // 199 length
+                     1,0,0,0,   // 1 length
+                     199,0,0,0, // 199 length
+                     0,0,0,0,   // 0 length
+                     0,0,0,0],  // padding
+                   &buf[..]);
+
+        let buf = construct_segment_table(
+            &[&segment_199, &segment_1, &segment_199, &segment_0, &segment_1]);
+        assert_eq!(&[4,0,0,0,   // 5 segments
+                     199,0,0,0, // 199 length
+                     1,0,0,0,   // 1 length
+                     199,0,0,0, // 199 length
+                     0,0,0,0,   // 0 length
+                     1,0,0,0],  // 1 length
+                   &buf[..]);
+    }
+
+    impl AsOutputSegments for Vec<Vec<Word>> {
+        fn as_output_segments<'a>(&'a self) -> OutputSegments<'a> {
+            if self.len() == 0 {
+                OutputSegments::SingleSegment([&[]])
+            } else if self.len() == 1 {
+                OutputSegments::SingleSegment([&self[0][..]])
+            } else {
+                OutputSegments::MultiSegment(self.iter()
+                                             .map(|segment| &segment[..])
+                                             .collect::<Vec<_>>())
+            }
+        }
+    }
+
+    /// Wraps a `Read` instance and introduces blocking.
+    struct BlockingRead<R> where R: Read {
+        /// The wrapped reader
+        read: R,
+
+        /// Number of bytes to read before blocking
+        frequency: usize,
+
+        /// Number of bytes read since last blocking
+        idx: usize,
+    }
+
+    impl <R> BlockingRead<R> where R: Read {
+        fn new(read: R, frequency: usize) -> BlockingRead<R> {
+            BlockingRead { read: read, frequency: frequency, idx: 0 }
+        }
+    }
+
+    impl <R> AsyncRead for BlockingRead<R> where R: Read + Unpin {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            if self.idx == 0 {
+                self.idx = self.frequency;
+                cx.waker().clone().wake();
+                Poll::Pending
+            } else {
+                let len = cmp::min(self.idx, buf.len());
+                let bytes_read = match self.read.read(&mut buf[..len]) {
+                    Err(e) => return Poll::Ready(Err(e)),
+                    Ok(n) => n,
+                };
+                self.idx -= bytes_read;
+                Poll::Ready(Ok(bytes_read))
+            }
+        }
+    }
+
+    /// Wraps a `Read` instance, returning `Poll::Pending` (after waking itself) `pendings`
+    /// times before each byte, and then one byte at a time. Counts how often it is polled.
+    struct PendingRead<R> where R: Read {
+        read: R,
+        pendings: usize,
+        pending_left: usize,
+        polls: usize,
+    }
+
+    impl <R> PendingRead<R> where R: Read {
+        fn new(read: R, pendings: usize) -> PendingRead<R> {
+            PendingRead { read: read, pendings: pendings, pending_left: pendings, polls: 0 }
+        }
+    }
+
+    impl <R> AsyncRead for PendingRead<R> where R: Read + Unpin {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            self.polls += 1;
+            if self.pending_left > 0 {
+                self.pending_left -= 1;
+                cx.waker().wake_by_ref();
+                return Poll::Pending
+            }
+            self.pending_left = self.pendings;
+            let len = cmp::min(1, buf.len());
+            Poll::Ready(self.read.read(&mut buf[..len]))
+        }
+    }
+
+    /// Wraps a `Write` instance and introduces blocking.
+    struct BlockingWrite<W> where W: Write {
+        /// The wrapped writer
+        writer: W,
+
+        /// Number of bytes to write before blocking
+        frequency: usize,
+
+        /// Number of bytes written since last blocking
+        idx: usize,
+    }
+
+    impl <W> BlockingWrite<W> where W: Write {
+        fn new(writer: W, frequency: usize) -> BlockingWrite<W> {
+            BlockingWrite { writer: writer, frequency: frequency, idx: 0 }
+        }
+        fn into_writer(self) -> W {
+            self.writer
+        }
+    }
+
+    impl <W> AsyncWrite for BlockingWrite<W> where W: Write + Unpin {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+            if self.idx == 0 {
+                self.idx = self.frequency;
+                cx.waker().clone().wake();
+                Poll::Pending
+            } else {
+                let len = cmp::min(self.idx, buf.len());
+                let bytes_written = match self.writer.write(&buf[..len]) {
+                    Err(e) => return Poll::Ready(Err(e)),
+                    Ok(n) => n,
+                };
+                self.idx -= bytes_written;
+                Poll::Ready(Ok(bytes_written))
+            }
+        }
+        fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(self.writer.flush())
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn check_round_trip_async() {
+        fn round_trip(read_block_frequency: usize,
+                      write_block_frequency: usize,
+                      segments: Vec<Vec<Word>>) -> TestResult
+        {
+            if segments.len() == 0 || read_block_frequency == 0 || write_block_frequency == 0 {
+                return TestResult::discard();
+            }
+
+            let (mut read, segments) = {
+                let cursor = std::io::Cursor::new(Vec::new());
+                let mut writer = BlockingWrite::new(cursor, write_block_frequency);
+                futures::executor::block_on(Box::pin(write_message(&mut writer, &segments))).expect("writing");
+
+                let mut cursor = writer.into_writer();
+                cursor.set_position(0);
+                (BlockingRead::new(cursor, read_block_frequency), segments)
+            };
+
+            let message =
+                futures::executor::block_on(Box::pin(read_message(&mut read, Default::default()))).expect("reading").unwrap();
+            let message_segments = message.into_segments();
+
+            TestResult::from_bool(segments.iter().enumerate().all(|(i, segment)| {
+                &segment[..] == message_segments.get_segment(i as u32).unwrap()
+            }))
+        }
+
+        quickcheck(round_trip as fn(usize, usize, Vec<Vec<Word>>) -> TestResult);
+    }
+
+    #[test]
+    fn message_writer_resumes_where_it_stopped() {
+        let segments = vec![vec![capnp::word(1,2,3,4,5,6,7,8); 3],
+                            vec![capnp::word(9,0,0,0,0,0,0,0); 5]];
+        let mut expected = Vec::new();
+        futures::executor::block_on(write_message(&mut expected, &segments)).expect("writing");
+
+        let writer = BlockingWrite::new(std::io::Cursor::new(Vec::new()), 3);
+        let mut message_writer = MessageWriter::new(writer, &segments);
+        assert_eq!(expected.len(), message_writer.total_bytes());
+
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let mut previous = 0;
+        loop {
+            match Pin::new(&mut message_writer).poll(&mut cx) {
+                Poll::Ready(result) => { result.expect("writing"); break }
+                Poll::Pending => {
+                    let written = message_writer.bytes_written();
+                    assert!(written >= previous && written - previous <= 3);
+                    previous = written;
+                }
+            }
+        }
+        assert_eq!(expected.len(), message_writer.bytes_written());
+
+        let (writer, _) = message_writer.into_inner();
+        assert_eq!(expected, writer.into_writer().into_inner());
+    }
+
+    #[test]
+    fn owned_segments_from_bytes() {
+        let bytes: Vec<u8> = (0..24).collect();
+        let segments = OwnedSegments::from_bytes(&bytes[1..], vec![(0, 1), (1, 3)]);
+        assert!(segments.is_err());
+
+        let segments = OwnedSegments::from_bytes(&bytes[..16], vec![(0, 1), (1, 3)]);
+        assert!(segments.is_err());
+
+        let segments = OwnedSegments::from_bytes(&bytes[..], vec![(0, 1), (1, 3)]).expect("copying");
+        assert_eq!(&bytes[0..8], Word::words_to_bytes(segments.get_segment(0).unwrap()));
+        assert_eq!(&bytes[8..24], Word::words_to_bytes(segments.get_segment(1).unwrap()));
+        assert!(segments.get_segment(2).is_none());
+        assert_eq!(3, segments.total_words());
+
+        let mut buf = Vec::new();
+        let segment_slices: &[&[Word]] = &segments.segment_slices();
+        futures::executor::block_on(write_message(&mut buf, segment_slices)).unwrap();
+        let message = futures::executor::block_on(read_message(Cursor::new(&buf[..]),
+                                                               message::ReaderOptions::new()))
+            .unwrap().unwrap();
+        assert_eq!(3, message.into_segments().total_words());
+    }
+
+    #[test]
+    fn read_message_streaming_visits_each_segment() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2],
+                            vec![],
+                            vec![capnp::word(3,0,0,0,0,0,0,0); 7]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).expect("writing");
+
+        let mut read = Cursor::new(&buf[..]);
+        let mut visited: Vec<(u32, Vec<Word>)> = Vec::new();
+        let found = futures::executor::block_on(read_message_streaming(
+            &mut read, message::ReaderOptions::new(),
+            |idx, segment| { visited.push((idx, segment.to_vec())); Ok(()) })).expect("reading");
+        assert!(found);
+        assert_eq!(vec![(0, segments[0].clone()), (1, segments[1].clone()), (2, segments[2].clone())],
+                   visited);
+
+        let found = futures::executor::block_on(read_message_streaming(
+            &mut read, message::ReaderOptions::new(), |_, _| panic!("no more segments"))).expect("reading");
+        assert!(!found);
+    }
+
+    #[test]
+    fn read_message_cancellable_races_cancel() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).expect("writing");
+
+        let message = futures::executor::block_on(read_message_cancellable(
+            Cursor::new(&buf[..]), message::ReaderOptions::new(), futures::future::pending::<()>()));
+        assert!(message.expect("reading").is_some());
+
+        // BlockingRead is not ready on its first poll, so the cancellation wins.
+        let read = BlockingRead::new(std::io::Cursor::new(buf), 8);
+        let result = futures::executor::block_on(read_message_cancellable(
+            read, message::ReaderOptions::new(), futures::future::ready(())));
+        match result {
+            Err(e) => assert!(is_cancelled(&e)),
+            Ok(_) => panic!("expected the read to be cancelled"),
+        }
+    }
+
+    #[test]
+    fn write_slice_of_segments() {
+        let segment_0 = [capnp::word(1,0,0,0,0,0,0,0); 2];
+        let segment_1 = [capnp::word(2,0,0,0,0,0,0,0); 3];
+        let segments: [&[Word]; 2] = [&segment_0, &segment_1];
+
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments[..])).expect("writing");
+
+        let message = futures::executor::block_on(read_message(Cursor::new(&buf[..]),
+                                                               message::ReaderOptions::new()))
+            .expect("reading").unwrap();
+        let message_segments = message.into_segments();
+        assert_eq!(&segment_0[..], message_segments.get_segment(0).unwrap());
+        assert_eq!(&segment_1[..], message_segments.get_segment(1).unwrap());
+    }
+
+    #[test]
+    fn length_hint_rejects_truncated_body() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 4]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).expect("writing");
+
+        let message = futures::executor::block_on(read_message_with_length_hint(
+            Cursor::new(&buf[..]), message::ReaderOptions::new())).expect("reading");
+        assert!(message.is_some());
+
+        let result = futures::executor::block_on(read_message_with_length_hint(
+            Cursor::new(&buf[..buf.len() - 8]), message::ReaderOptions::new()));
+        match result {
+            Err(e) => assert!(e.description.contains("exceeds"), "{}", e),
+            Ok(_) => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn check_sync_and_async_writes_agree() {
+        fn agree(segments: Vec<Vec<Word>>) -> TestResult {
+            if segments.len() == 0 {
+                return TestResult::discard();
+            }
+            let mut async_buf = Vec::new();
+            futures::executor::block_on(write_message(&mut async_buf, &segments)).expect("writing");
+            let mut sync_buf = Vec::new();
+            write_message_sync(&mut sync_buf, &segments).expect("writing");
+            TestResult::from_bool(async_buf == sync_buf)
+        }
+
+        quickcheck(agree as fn(Vec<Vec<Word>>) -> TestResult);
+    }
+
+    #[test]
+    fn timed_read_and_write() {
+        use std::cell::Cell;
+        use std::time::{Duration, Instant};
+
+        let base = Instant::now();
+        let ticks = Cell::new(0);
+        let clock = || {
+            ticks.set(ticks.get() + 1);
+            base + Duration::from_millis(10 * ticks.get())
+        };
+
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 4]];
+        let mut buf = Vec::new();
+        let (result, elapsed) =
+            futures::executor::block_on(write_message_timed(&mut buf, &segments, &clock));
+        result.expect("writing");
+        assert_eq!(Duration::from_millis(10), elapsed);
+
+        let (result, elapsed) = futures::executor::block_on(
+            read_message_timed(Cursor::new(&buf[..]), message::ReaderOptions::new(), &clock));
+        assert!(result.expect("reading").is_some());
+        assert_eq!(Duration::from_millis(10), elapsed);
+
+        let (result, elapsed) = futures::executor::block_on(
+            read_message_timed(Cursor::new(&buf[..0]), message::ReaderOptions::new(), &clock));
+        assert!(result.expect("reading").is_none());
+        assert_eq!(Duration::from_millis(0), elapsed);
+    }
+
+    #[test]
+    fn teed_read_and_write() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 4], vec![capnp::word(2,0,0,0,0,0,0,0); 1]];
+        let mut buf = Vec::new();
+        let mut written = Vec::new();
+        futures::executor::block_on(
+            write_message_teed(BlockingWrite::new(&mut buf, 5), &segments,
+                               |bytes: &[u8]| written.extend_from_slice(bytes))).expect("writing");
+        assert_eq!(buf, written);
+
+        let mut read = Vec::new();
+        let message = futures::executor::block_on(
+            read_message_teed(BlockingRead::new(&buf[..], 3), message::ReaderOptions::new(),
+                              |bytes: &[u8]| read.extend_from_slice(bytes))).expect("reading");
+        assert!(message.is_some());
+        assert_eq!(buf, read);
+    }
+
+    #[test]
+    fn segment_len_too_large_for_usize() {
+        // Stands in for `usize` on a 16-bit target.
+        assert_eq!(5u16, segment_len_from_u32::<u16>(5).expect("fits"));
+        assert_eq!(0xffffu16, segment_len_from_u32::<u16>(0xffff).expect("fits"));
+        assert!(segment_len_from_u32::<u16>(0x10000).is_err());
+    }
+
+    #[test]
+    fn write_message_in_parts() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2], vec![capnp::word(2,0,0,0,0,0,0,0); 3]];
+        let mut expected = Vec::new();
+        futures::executor::block_on(write_message(&mut expected, &segments)).expect("writing");
+
+        let mut buf = Vec::new();
+        {
+            let mut writer = MessagePartsWriter::new(&mut buf, &segments);
+            assert!(futures::executor::block_on(writer.write_body()).is_err());
+            futures::executor::block_on(writer.write_table()).expect("writing table");
+            assert!(futures::executor::block_on(writer.write_table()).is_err());
+            futures::executor::block_on(writer.write_body()).expect("writing body");
+            assert!(futures::executor::block_on(writer.write_body()).is_err());
+        }
+        assert_eq!(expected, buf);
+    }
+
+    #[test]
+    fn padded_messages() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2], vec![capnp::word(2,0,0,0,0,0,0,0); 1]];
+        let frame_len = 16 + 3 * 8;
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message_padded(&mut buf, &segments, 100)).expect("writing");
+        futures::executor::block_on(write_message_padded(&mut buf, &segments, frame_len)).expect("writing");
+        assert_eq!(100 + frame_len, buf.len());
+        assert!(futures::executor::block_on(write_message_padded(&mut buf, &segments, frame_len - 1)).is_err());
+        assert_eq!(100 + frame_len, buf.len());
+
+        let mut reader = Cursor::new(&buf[..]);
+        let message = futures::executor::block_on(
+            read_message_padded(&mut reader, message::ReaderOptions::new(), 100)).expect("reading");
+        let message_segments = message.expect("message").into_segments();
+        assert_eq!(&segments[0][..], message_segments.get_segment(0).unwrap());
+        assert_eq!(&segments[1][..], message_segments.get_segment(1).unwrap());
+        assert_eq!(100, reader.position());
+        let message = futures::executor::block_on(
+            read_message_padded(&mut reader, message::ReaderOptions::new(), frame_len)).expect("reading");
+        assert!(message.is_some());
+        assert!(futures::executor::block_on(
+            read_message_padded(&mut reader, message::ReaderOptions::new(), 100)).expect("reading").is_none());
+
+        assert!(futures::executor::block_on(
+            read_message_padded(Cursor::new(&buf[..]), message::ReaderOptions::new(), frame_len - 1)).is_err());
+        let mut corrupted = buf.clone();
+        corrupted[99] = 1;
+        assert!(futures::executor::block_on(
+            read_message_padded(Cursor::new(&corrupted[..]), message::ReaderOptions::new(), 100)).is_err());
+    }
+
+    #[test]
+    fn versioned_messages() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_versioned_message(&mut buf, 3, &segments)).expect("writing");
+        futures::executor::block_on(write_versioned_message(&mut buf, 7, &segments)).expect("writing");
+
+        let mut reader = BlockingRead::new(&buf[..], 5);
+        for &expected in &[3, 7] {
+            let (version, message) = futures::executor::block_on(
+                read_versioned_message(&mut reader, message::ReaderOptions::new())).expect("reading").unwrap();
+            assert_eq!(expected, version);
+            assert_eq!(&segments[0][..], message.into_segments().get_segment(0).unwrap());
+        }
+        assert!(futures::executor::block_on(
+            read_versioned_message(&mut reader, message::ReaderOptions::new())).expect("reading").is_none());
+
+        assert!(futures::executor::block_on(
+            read_versioned_message(Cursor::new(&buf[..1]), message::ReaderOptions::new())).is_err());
+    }
+
+    #[test]
+    fn counted_read_reports_bytes_consumed() {
+        struct FailingRead;
+        impl AsyncRead for FailingRead {
+            fn poll_read(self: Pin<&mut Self>, _cx: &mut Context, _buf: &mut [u8]) -> Poll<io::Result<usize>> {
+                Poll::Ready(Err(io::Error::new(io::ErrorKind::ConnectionReset, "failing read")))
+            }
+        }
+
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).expect("writing");
+
+        let (result, consumed) = futures::executor::block_on(
+            read_message_counted(Cursor::new(&buf[..]), message::ReaderOptions::new()));
+        assert!(result.expect("reading").is_some());
+        assert_eq!(buf.len() as u64, consumed);
+
+        let (result, consumed) = futures::executor::block_on(
+            read_message_counted(FailingRead, message::ReaderOptions::new()));
+        assert!(result.is_err());
+        assert_eq!(0, consumed);
+
+        let (result, consumed) = futures::executor::block_on(
+            read_message_counted(Cursor::new(&buf[..buf.len() - 3]), message::ReaderOptions::new()));
+        assert!(result.is_err());
+        assert_eq!(buf.len() as u64 - 3, consumed);
+    }
+
+    #[test]
+    fn chunked_write() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 5], vec![capnp::word(2,0,0,0,0,0,0,0); 1]];
+        let mut expected = Vec::new();
+        futures::executor::block_on(write_message(&mut expected, &segments)).expect("writing");
+        assert_eq!(expected.len(), serialized_size(&segments));
+
+        let mut scratch = Vec::new();
+        for &chunk_size in &[1, 7, 8, expected.len(), expected.len() + 1] {
+            let mut buf = Vec::new();
+            futures::executor::block_on(
+                write_message_chunked(&mut buf, &segments, chunk_size, &mut scratch)).expect("writing");
+            assert_eq!(expected, buf);
+        }
+        assert!(futures::executor::block_on(
+            write_message_chunked(&mut Vec::new(), &segments, 0, &mut scratch)).is_err());
+    }
+
+    #[test]
+    fn messages_with_metadata() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2]];
+        let metadata = MetaHeader { content_type: "application/x-example".to_string(), timestamp: 1_234_567 };
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message_with_metadata(&mut buf, &metadata, &segments)).unwrap();
+        futures::executor::block_on(
+            write_message_with_metadata(&mut buf, &MetaHeader::default(), &segments)).unwrap();
+
+        let mut reader = BlockingRead::new(&buf[..], 4);
+        for expected in &[metadata, MetaHeader::default()] {
+            let (read_metadata, message) = futures::executor::block_on(
+                read_message_with_metadata(&mut reader, message::ReaderOptions::new())).unwrap().unwrap();
+            assert_eq!(expected, &read_metadata);
+            assert_eq!(&segments[0][..], message.into_segments().get_segment(0).unwrap());
+        }
+        assert!(futures::executor::block_on(
+            read_message_with_metadata(&mut reader, message::ReaderOptions::new())).unwrap().is_none());
+
+        let mut bad_version = buf.clone();
+        bad_version[0] = 2;
+        assert!(futures::executor::block_on(
+            read_message_with_metadata(Cursor::new(&bad_version[..]), message::ReaderOptions::new())).is_err());
+
+        let too_long = MetaHeader { content_type: "x".repeat(70_000), timestamp: 0 };
+        assert!(futures::executor::block_on(
+            write_message_with_metadata(&mut Vec::new(), &too_long, &segments)).is_err());
+    }
+
+    #[test]
+    fn many_pendings_between_bytes() {
+        let segments = vec![vec![capnp::word(1,2,3,4,5,6,7,8); 3], vec![capnp::word(9,0,0,0,0,0,0,0); 2]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        let pendings = 100;
+
+        // Every poll either makes progress or is one of the reader's pendings, so the reader
+        // is polled exactly once per pending and once per byte.
+        let mut reader = PendingRead::new(&buf[..], pendings);
+        let message = futures::executor::block_on(read_message(&mut reader, message::ReaderOptions::new()))
+            .unwrap().unwrap();
+        let message_segments = message.into_segments();
+        assert_eq!(&segments[0][..], message_segments.get_segment(0).unwrap());
+        assert_eq!(&segments[1][..], message_segments.get_segment(1).unwrap());
+        assert_eq!((pendings + 1) * buf.len(), reader.polls);
+
+        let mut reader = crate::MessageReader::new(PendingRead::new(&buf[..], pendings),
+                                                   message::ReaderOptions::new());
+        let message = futures::executor::block_on(reader.read_message()).unwrap().unwrap();
+        let message_segments = message.into_segments();
+        assert_eq!(&segments[0][..], message_segments.get_segment(0).unwrap());
+        assert_eq!(&segments[1][..], message_segments.get_segment(1).unwrap());
+        assert_eq!((pendings + 1) * buf.len(), reader.get_ref().polls);
+    }
+
+    #[test]
+    fn serialize_into_word_buffer() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2], vec![capnp::word(2,0,0,0,0,0,0,0); 1]];
+        let mut expected = Vec::new();
+        futures::executor::block_on(write_message(&mut expected, &segments)).unwrap();
+
+        let mut buf = vec![capnp::word(0xff,0xff,0xff,0xff,0xff,0xff,0xff,0xff); 10];
+        let used = serialize_into_words(&mut buf, &segments).unwrap();
+        assert_eq!(expected.len() / 8, used);
+        assert_eq!(&expected[..], Word::words_to_bytes(&buf[..used]));
+
+        assert!(serialize_into_words(&mut buf[..used - 1], &segments).is_err());
+    }
+
+    #[test]
+    fn trailing_zeros_are_eof() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 1]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        let frame_len = buf.len();
+        let mut framing = ReadFraming::new();
+        framing.trailing_zero_is_eof(true);
+
+        // (number of trailing zero bytes, whether a nonzero byte follows them)
+        for &(zeros, garbage) in &[(0, false), (3, false), (8, false), (4096 + 5, false),
+                                   (3, true), (8, true), (100, true)] {
+            let mut padded = buf.clone();
+            padded.resize(frame_len + zeros, 0);
+            if garbage {
+                padded.push(1);
+            }
+            let mut reader = BlockingRead::new(&padded[..], 7);
+            let message = futures::executor::block_on(
+                read_message_with_framing(&mut reader, message::ReaderOptions::new(), framing));
+            assert!(message.unwrap().is_some());
+            let result = futures::executor::block_on(
+                read_message_with_framing(&mut reader, message::ReaderOptions::new(), framing));
+            if garbage {
+                assert!(result.is_err(), "{} zeros", zeros);
+            } else {
+                assert!(result.unwrap().is_none(), "{} zeros", zeros);
+            }
+
+            // Without the option, trailing zeros are read as an empty frame or an error.
+            if zeros >= 8 {
+                let message = futures::executor::block_on(
+                    read_message(Cursor::new(&padded[frame_len..]), message::ReaderOptions::new()));
+                assert!(message.unwrap().is_some());
+            }
+        }
+
+        // A tiny frame that isn't all zero is still read.
+        let tiny = [0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let message = futures::executor::block_on(
+            read_message_with_framing(Cursor::new(&tiny[..]), message::ReaderOptions::new(), framing));
+        assert!(message.unwrap().is_some());
+    }
+
+    #[test]
+    fn reject_empty_first_segment() {
+        let segments = vec![vec![], vec![capnp::word(1,0,0,0,0,0,0,0); 1]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).expect("writing");
+
+        let message = futures::executor::block_on(read_message_with_framing(
+            Cursor::new(&buf[..]), message::ReaderOptions::new(), ReadFraming::new()));
+        assert!(message.expect("reading").is_some());
+
+        let message = futures::executor::block_on(read_message_with_framing(
+            Cursor::new(&buf[..]), message::ReaderOptions::new(),
+            *ReadFraming::new().reject_empty_first_segment(true)));
+        assert!(message.is_err());
+    }
+
+    #[test]
+    fn rewrap_owned_segments() {
+        let mut builder = message::Builder::new_default();
+        builder.init_root::<capnp::any_pointer::Builder>().set_as("hello").unwrap();
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &builder)).expect("writing");
+
+        let message = futures::executor::block_on(read_message(Cursor::new(&buf[..]),
+                                                               message::ReaderOptions::new()))
+            .expect("reading").unwrap();
+        let segments = message.into_segments();
+        let slices = segments.segment_slices();
+        assert_eq!(Some(slices[0]), segments.get_segment(0));
+
+        let mut options = message::ReaderOptions::new();
+        options.traversal_limit_in_words(1024);
+        let rewrapped = message::Reader::new(message::SegmentArray::new(&slices), options);
+        assert_eq!("hello", rewrapped.get_root::<capnp::text::Reader>().unwrap());
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum WriteEvent {
+        Write(usize),
+        Flush,
+        Close,
+    }
+
+    /// An `AsyncWrite` that records the calls made to it.
+    #[derive(Default)]
+    struct RecordingWrite {
+        bytes: Vec<u8>,
+        events: Vec<WriteEvent>,
+    }
+
+    impl AsyncWrite for RecordingWrite {
+        fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+            self.bytes.extend_from_slice(buf);
+            self.events.push(WriteEvent::Write(buf.len()));
+            Poll::Ready(Ok(buf.len()))
+        }
+        fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            self.events.push(WriteEvent::Flush);
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            self.events.push(WriteEvent::Close);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn final_message_closes_after_flush() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2]];
+        let mut writer = RecordingWrite::default();
+        futures::executor::block_on(write_final_message(&mut writer, &segments)).expect("writing");
+
+        let mut expected = Vec::new();
+        futures::executor::block_on(write_message(&mut expected, &segments)).expect("writing");
+        assert_eq!(expected, writer.bytes);
+        let n = writer.events.len();
+        assert_eq!(&[WriteEvent::Flush, WriteEvent::Close], &writer.events[n - 2..]);
+        assert!(writer.events[..n - 2].iter().all(|e| matches!(e, WriteEvent::Write(_))));
+    }
+
+    #[test]
+    fn read_all_messages_with_and_without_recycling() {
+        let texts = ["first", "the second message", "3"];
+        let mut buf = Vec::new();
+        for text in &texts {
+            let mut builder = message::Builder::new_default();
+            builder.init_root::<capnp::any_pointer::Builder>().set_as(*text).unwrap();
+            futures::executor::block_on(write_message(&mut buf, &builder)).expect("writing");
+        }
+
+        let read = futures::executor::block_on(read_all_messages(Cursor::new(&buf[..]),
+                                                                 message::ReaderOptions::new()))
+            .expect("reading");
+        let read: Vec<&str> =
+            read.iter().map(|m| m.get_root::<capnp::text::Reader>().unwrap()).collect();
+        assert_eq!(&texts[..], &read[..]);
+
+        let mut read = Vec::new();
+        let count = futures::executor::block_on(read_all_messages_recycling(
+            Cursor::new(&buf[..]), message::ReaderOptions::new(), |message| {
+                read.push(message.get_root::<capnp::text::Reader>()?.to_string());
+                Ok(())
+            })).expect("reading");
+        assert_eq!(texts.len(), count);
+        assert_eq!(&texts[..], &read[..]);
+    }
+
+    #[test]
+    fn batch_flushes_once() {
+        let messages = vec![vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2]],
+                            vec![vec![capnp::word(2,0,0,0,0,0,0,0); 1]],
+                            vec![vec![capnp::word(3,0,0,0,0,0,0,0); 3]]];
+        let mut writer = RecordingWrite::default();
+        let (count, result) = futures::executor::block_on(write_message_batch(&mut writer, &messages));
+        result.expect("writing");
+        assert_eq!(3, count);
+
+        let mut expected = Vec::new();
+        for message in &messages {
+            futures::executor::block_on(write_message(&mut expected, message)).expect("writing");
+        }
+        assert_eq!(expected, writer.bytes);
+        assert_eq!(1, writer.events.iter().filter(|e| **e == WriteEvent::Flush).count());
+        assert_eq!(Some(&WriteEvent::Flush), writer.events.last());
+    }
+
+    #[test]
+    fn segment_table_is_little_endian_on_any_host() {
+        // Build the table from big-endian encodings reversed by hand, so that the expected bytes
+        // don't depend on the byte order of the host running the test.
+        fn le(value: u32) -> [u8; 4] {
+            let mut bytes = value.to_be_bytes();
+            bytes.reverse();
+            bytes
+        }
+        let lengths = [0x0000_0102u32, 0x0003_0000, 0x0000_0007];
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&le(lengths.len() as u32 - 1));
+        for &length in &lengths {
+            buf.extend_from_slice(&le(length));
+        }
+        assert_eq!(&[2, 0, 0, 0, 2, 1, 0, 0], &buf[..8]);
+
+        let (words, segment_slices) = futures::executor::block_on(
+            read_segment_table(Cursor::new(&buf[..]), message::ReaderOptions::new())).unwrap().unwrap().into_parts();
+        assert_eq!(0x0003_0109, words);
+        assert_eq!(vec![(0, 0x102), (0x102, 0x3_0102), (0x3_0102, 0x3_0109)], segment_slices);
+
+        // Writing the table back out produces the same little-endian bytes.
+        let segment_0 = vec![capnp::word(0,0,0,0,0,0,0,0); 0x102];
+        let segment_1 = vec![capnp::word(0,0,0,0,0,0,0,0); 0x3_0000];
+        let segment_2 = vec![capnp::word(0,0,0,0,0,0,0,0); 0x7];
+        assert_eq!(buf, construct_segment_table(&[&segment_0, &segment_1, &segment_2]));
+    }
+
+    #[test]
+    fn segment_table_into_fixed_buffer() {
+        let segment_0 = [capnp::word(1,0,0,0,0,0,0,0); 3];
+        let segment_1 = [capnp::word(2,0,0,0,0,0,0,0); 5];
+        let segments: &[&[Word]] = &[&segment_0, &segment_1];
+
+        // Start from a dirty buffer to check that the padding gets cleared.
+        let mut buf = [0xffu8; 20];
+        assert_eq!(16, write_segment_table_into(&mut buf, segments).unwrap());
+        assert_eq!(&construct_segment_table(segments)[..], &buf[..16]);
+        assert_eq!(&[0xff; 4], &buf[16..]);
+
+        assert!(write_segment_table_into(&mut buf[..15], segments).is_err());
+    }
+
+    #[test]
+    fn read_canonical_flattens_multi_segment_messages() {
+        let text = "long enough that it does not fit in the first segment";
+        let mut builder = message::Builder::new(
+            message::HeapAllocator::new()
+                .first_segment_words(1)
+                .allocation_strategy(message::AllocationStrategy::FixedSize));
+        builder.init_root::<capnp::any_pointer::Builder>().set_as(text).unwrap();
+        assert!(builder.get_segments_for_output().len() > 1);
+        let mut multi = Vec::new();
+        futures::executor::block_on(write_message(&mut multi, &builder)).expect("writing");
+
+        assert!(futures::executor::block_on(
+            read_message_canonical(Cursor::new(&multi[..]), message::ReaderOptions::new(), false)).is_err());
+
+        let message = futures::executor::block_on(
+            read_message_canonical(Cursor::new(&multi[..]), message::ReaderOptions::new(), true))
+            .expect("reading").unwrap();
+        assert_eq!(text, message.get_root::<capnp::text::Reader>().unwrap());
+        let segments = message.into_segments();
+        assert!(segments.get_segment(0).is_some());
+        assert!(segments.get_segment(1).is_none());
+
+        // A single-segment message passes through unchanged.
+        let single = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &single)).expect("writing");
+        let message = futures::executor::block_on(
+            read_message_canonical(Cursor::new(&buf[..]), message::ReaderOptions::new(), false))
+            .expect("reading").unwrap();
+        assert_eq!(&single[0][..], message.into_segments().get_segment(0).unwrap());
+    }
+
+    #[test]
+    fn varint_prefixed_round_trip() {
+        let segments: Vec<Vec<Word>> =
+            (0..200).map(|i| vec![capnp::word(i as u8,0,0,0,0,0,0,0); 1]).collect();
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message_varint_prefixed(&mut buf, &segments)).unwrap();
+        assert_eq!(&[0xc8, 0x01], &buf[..2]);
+
+        let mut cursor = Cursor::new(&buf[..]);
+        let message = futures::executor::block_on(
+            read_message_varint_prefixed(&mut cursor, message::ReaderOptions::new())).unwrap().unwrap();
+        let message_segments = message.into_segments();
+        for (idx, segment) in segments.iter().enumerate() {
+            assert_eq!(&segment[..], message_segments.get_segment(idx as u32).unwrap());
+        }
+        assert!(futures::executor::block_on(
+            read_message_varint_prefixed(&mut cursor, message::ReaderOptions::new())).unwrap().is_none());
+
+        // A prefix that disagrees with the segment table.
+        buf[0] = 0xc7;
+        assert!(futures::executor::block_on(
+            read_message_varint_prefixed(Cursor::new(&buf[..]), message::ReaderOptions::new())).is_err());
+    }
+
+    #[test]
+    fn fixed_layout_reuses_buffer() {
+        let messages: Vec<Vec<Vec<Word>>> = (1..4).map(|i| {
+            vec![vec![capnp::word(i,0,0,0,0,0,0,0); 2], vec![capnp::word(i,1,0,0,0,0,0,0); 1]]
+        }).collect();
+        let mut buf = Vec::new();
+        for segments in &messages {
+            futures::executor::block_on(write_message(&mut buf, segments)).unwrap();
+        }
+
+        let mut cursor = Cursor::new(&buf[..]);
+        let mut layout = OwnedSegments::from_bytes(&[0; 24], vec![(0, 2), (2, 3)]).unwrap();
+        for segments in &messages {
+            let message = futures::executor::block_on(
+                read_message_fixed_layout(&mut cursor, layout, message::ReaderOptions::new()))
+                .unwrap().unwrap();
+            layout = message.into_segments();
+            assert_eq!(&segments[0][..], layout.get_segment(0).unwrap());
+            assert_eq!(&segments[1][..], layout.get_segment(1).unwrap());
+        }
+        assert!(futures::executor::block_on(
+            read_message_fixed_layout(&mut cursor, layout, message::ReaderOptions::new()))
+                .unwrap().is_none());
+
+        let layout = OwnedSegments::from_bytes(&[0; 24], vec![(0, 1), (1, 3)]).unwrap();
+        assert!(futures::executor::block_on(
+            read_message_fixed_layout(Cursor::new(&buf[..]), layout, message::ReaderOptions::new()))
+                .is_err());
+    }
+
+    #[test]
+    fn stream_header_precedes_messages() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 1]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_stream_header(&mut buf, b"CAPN")).unwrap();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+
+        let mut cursor = Cursor::new(&buf[..]);
+        futures::executor::block_on(read_stream_header(&mut cursor, b"CAPN")).unwrap();
+        let message = futures::executor::block_on(
+            read_message(&mut cursor, message::ReaderOptions::new())).unwrap().unwrap();
+        assert_eq!(&segments[0][..], message.into_segments().get_segment(0).unwrap());
+
+        assert!(futures::executor::block_on(read_stream_header(Cursor::new(&buf[..]), b"CAPX")).is_err());
+        assert!(futures::executor::block_on(read_stream_header(Cursor::new(&buf[..2]), b"CAPN")).is_err());
+    }
+
+    /// Wraps a `Read` instance and fails one read with `ErrorKind::Interrupted` once
+    /// `interrupt_at` bytes have been read.
+    struct InterruptingRead<R> where R: Read {
+        read: R,
+        interrupt_at: Option<usize>,
+        idx: usize,
+    }
+
+    impl <R> AsyncRead for InterruptingRead<R> where R: Read + Unpin {
+        fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            if let Some(interrupt_at) = self.interrupt_at {
+                if self.idx == interrupt_at {
+                    self.interrupt_at = None;
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted")))
+                }
+            }
+            let len = match self.interrupt_at {
+                Some(interrupt_at) => cmp::min(interrupt_at - self.idx, buf.len()),
+                None => buf.len(),
+            };
+            let n = self.read.read(&mut buf[..len])?;
+            self.idx += n;
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    #[test]
+    fn read_retries_interrupted_reads() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 3]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+
+        for interrupt_at in 0..buf.len() {
+            let reader = InterruptingRead { read: &buf[..], interrupt_at: Some(interrupt_at), idx: 0 };
+            let message = futures::executor::block_on(read_message(reader, message::ReaderOptions::new()))
+                .expect("reading").unwrap();
+            assert_eq!(&segments[0][..], message.into_segments().get_segment(0).unwrap());
+        }
+    }
+
+    #[test]
+    fn read_messages_within_byte_budget() {
+        use futures::stream::StreamExt;
+
+        let messages: Vec<Vec<Vec<Word>>> =
+            (1..4).map(|i| vec![vec![capnp::word(i,0,0,0,0,0,0,0); i as usize]]).collect();
+        let mut buf = Vec::new();
+        let mut frame_ends = Vec::new();
+        for segments in &messages {
+            futures::executor::block_on(write_message(&mut buf, segments)).unwrap();
+            frame_ends.push(buf.len() as u64);
+        }
+
+        // (budget, number of messages expected)
+        for &(budget, expected) in &[(0, 0), (frame_ends[0], 1), (frame_ends[2], 3),
+                                     (frame_ends[2] + 100, 3)] {
+            let read: Vec<_> = futures::executor::block_on(
+                read_messages_within(Cursor::new(&buf[..]), message::ReaderOptions::new(), budget)
+                    .collect());
+            assert_eq!(expected, read.len());
+            for (message, segments) in read.into_iter().zip(messages.iter()) {
+                let message_segments = message.unwrap().into_segments();
+                assert_eq!(&segments[0][..], message_segments.get_segment(0).unwrap());
+            }
+        }
+
+        // A frame cut off by the budget is an error, whether or not its segment table fits.
+        for &(budget, expected) in &[(frame_ends[0] + 4, 1), (frame_ends[1] + 8, 2)] {
+            let read: Vec<_> = futures::executor::block_on(
+                read_messages_within(Cursor::new(&buf[..]), message::ReaderOptions::new(), budget)
+                    .collect());
+            assert_eq!(expected + 1, read.len());
+            assert!(read[..expected].iter().all(|message| message.is_ok()));
+            assert!(read[expected].is_err());
+        }
+    }
+
+    #[test]
+    fn indexed_message_stream() {
+        use futures::stream::StreamExt;
+
+        let mut buf = Vec::new();
+        for i in 0..3 {
+            let segments = vec![vec![capnp::word(i,0,0,0,0,0,0,0); 1]];
+            futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        }
+
+        let read: Vec<_> = futures::executor::block_on(
+            read_message_stream_indexed(Cursor::new(&buf[..]), message::ReaderOptions::new()).collect());
+        assert_eq!(3, read.len());
+        for (i, item) in read.into_iter().enumerate() {
+            let (idx, message) = item.unwrap();
+            assert_eq!(i, idx);
+            assert_eq!(&[capnp::word(i as u8,0,0,0,0,0,0,0)][..],
+                       message.into_segments().get_segment(0).unwrap());
+        }
+
+        let read: Vec<_> = futures::executor::block_on(
+            read_message_stream_indexed(Cursor::new(&buf[..buf.len() - 1]), message::ReaderOptions::new())
+                .collect());
+        assert_eq!(3, read.len());
+        assert_eq!(1, read[1].as_ref().unwrap().0);
+        assert!(read[2].is_err());
+    }
+
+    #[test]
+    fn limited_reader_reports_limit() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 3]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        let frame_len = buf.len() as u64;
+
+        for &max_bytes in &[frame_len, frame_len + 10] {
+            let message = futures::executor::block_on(
+                read_message_limited_reader(Cursor::new(&buf[..]), message::ReaderOptions::new(), max_bytes));
+            assert!(message.expect("reading").is_some());
+        }
+        assert!(futures::executor::block_on(
+            read_message_limited_reader(Cursor::new(&buf[..0]), message::ReaderOptions::new(), 10))
+                .expect("reading").is_none());
+
+        for &max_bytes in &[0, 4, frame_len - 1] {
+            let error = futures::executor::block_on(
+                read_message_limited_reader(Cursor::new(&buf[..]), message::ReaderOptions::new(), max_bytes))
+                .err().expect("limit error");
+            assert!(error.description.starts_with("Reached the limit"), "{}", error.description);
+        }
+
+        // Other errors are not mistaken for the limit, even if they leave none of it.
+        let mut bad_table = vec![0xff; 8];
+        bad_table.extend_from_slice(&buf[..]);
+        let error = futures::executor::block_on(
+            read_message_limited_reader(Cursor::new(&bad_table[..]), message::ReaderOptions::new(), 8))
+            .err().expect("malformed table");
+        assert!(!error.description.starts_with("Reached the limit"), "{}", error.description);
+
+        // A stream that ends before the limit gets the usual error.
+        let error = futures::executor::block_on(
+            read_message_limited_reader(Cursor::new(&buf[..buf.len() - 1]), message::ReaderOptions::new(), 1000))
+            .err().expect("premature end");
+        assert!(!error.description.starts_with("Reached the limit"), "{}", error.description);
+    }
+
+    #[test]
+    fn validate_frames_counts_frames() {
+        let mut buf = Vec::new();
+        for i in 1..4 {
+            let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); i], vec![capnp::word(2,0,0,0,0,0,0,0); 1]];
+            futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        }
+        let options = message::ReaderOptions::new();
+        assert_eq!(3, futures::executor::block_on(validate_frames(Cursor::new(&buf[..]), options)).unwrap());
+        assert_eq!(0, futures::executor::block_on(validate_frames(Cursor::new(&buf[..0]), options)).unwrap());
+
+        let error = futures::executor::block_on(
+            validate_frames(Cursor::new(&buf[..buf.len() - 8]), options)).err().unwrap();
+        assert!(error.description.starts_with("Message 2: "), "{}", error.description);
+
+        let mut bad = buf.clone();
+        bad.extend_from_slice(&[0, 2, 0, 0, 0, 0, 0, 0]);
+        let error = futures::executor::block_on(validate_frames(Cursor::new(&bad[..]), options)).err().unwrap();
+        assert!(error.description.starts_with("Message 3: "), "{}", error.description);
+    }
+
+    #[test]
+    fn striped_messages() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2],
+                            vec![capnp::word(2,0,0,0,0,0,0,0); 1],
+                            vec![capnp::word(3,0,0,0,0,0,0,0); 3]];
+        let round_robin = |idx: usize| idx % 2;
+        let mut sinks = vec![Vec::new(), Vec::new()];
+        let written = futures::executor::block_on(
+            write_message_striped(&mut sinks, &segments, round_robin)).expect("writing");
+        assert_eq!(vec![16 + 5 * 8, 8], written);
+        assert_eq!(vec![sinks[0].len(), sinks[1].len()], written);
+
+        let mut sources = vec![Cursor::new(&sinks[0][..]), Cursor::new(&sinks[1][..])];
+        let message = futures::executor::block_on(
+            read_message_striped(&mut sources, message::ReaderOptions::new(), round_robin)).expect("reading");
+        let message_segments = message.expect("message").into_segments();
+        for (idx, segment) in segments.iter().enumerate() {
+            assert_eq!(&segment[..], message_segments.get_segment(idx as u32).unwrap());
+        }
+        assert!(futures::executor::block_on(
+            read_message_striped(&mut sources, message::ReaderOptions::new(), round_robin))
+                .expect("reading").is_none());
+
+        assert!(futures::executor::block_on(
+            write_message_striped(&mut sinks, &segments, |idx| idx)).is_err());
+        assert!(futures::executor::block_on(
+            write_message_striped::<Vec<u8>, _, _>(&mut [], &segments, round_robin)).is_err());
+    }
+
+    #[test]
+    fn read_with_progress() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 10_000], vec![capnp::word(2,0,0,0,0,0,0,0); 10_000]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+
+        let mut calls = Vec::new();
+        let message = futures::executor::block_on(
+            read_message_with_progress(BlockingRead::new(&buf[..], 1000), message::ReaderOptions::new(),
+                                       |read, total| calls.push((read, total)))).unwrap();
+        assert!(message.is_some());
+        assert_eq!(vec![(0, 160_000), (65_536, 160_000), (131_072, 160_000), (160_000, 160_000)], calls);
+
+        let mut calls = Vec::new();
+        assert!(futures::executor::block_on(
+            read_message_with_progress(Cursor::new(&buf[..100]), message::ReaderOptions::new(),
+                                       |read, total| calls.push((read, total)))).is_err());
+        assert_eq!(vec![(0, 160_000)], calls);
+    }
+
+    #[test]
+    fn fair_read_yields_between_chunks() {
+        let segments = vec![(0..100_000).map(|i| capnp::word(i as u8,0,0,0,0,0,0,0)).collect::<Vec<_>>(),
+                            vec![capnp::word(9,0,0,0,0,0,0,0); 31_072]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+
+        // The body is exactly 16 chunks, so the read should yield 15 times.
+        let mut read = Box::pin(read_message_fair(Cursor::new(&buf[..]), message::ReaderOptions::new()));
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let mut pendings = 0;
+        let message = loop {
+            match read.as_mut().poll(&mut cx) {
+                Poll::Pending => pendings += 1,
+                Poll::Ready(result) => break result.unwrap().unwrap(),
+            }
+        };
+        assert_eq!(15, pendings);
+        let message_segments = message.into_segments();
+        assert_eq!(&segments[0][..], message_segments.get_segment(0).unwrap());
+        assert_eq!(&segments[1][..], message_segments.get_segment(1).unwrap());
+    }
+
+    #[test]
+    fn aligned_segments() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 3],
+                            vec![capnp::word(2,0,0,0,0,0,0,0); 1],
+                            vec![],
+                            vec![capnp::word(3,0,0,0,0,0,0,0); 5]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+
+        for &align in &[1, 8, 16, 32, 64, 4096] {
+            let message = futures::executor::block_on(
+                read_message_aligned(Cursor::new(&buf[..]), message::ReaderOptions::new(), align))
+                .unwrap().unwrap();
+            let message_segments = message.into_segments();
+            for (idx, segment) in segments.iter().enumerate() {
+                let read = message_segments.get_segment(idx as u32).unwrap();
+                assert_eq!(&segment[..], read);
+                assert_eq!(0, read.as_ptr() as usize % align, "segment {} with alignment {}", idx, align);
+            }
+            assert_eq!(9, message_segments.total_words());
+        }
+        for &align in &[24, 8192, 1 << (::std::mem::size_of::<usize>() * 8 - 1)] {
+            assert!(futures::executor::block_on(
+                read_message_aligned(Cursor::new(&buf[..]), message::ReaderOptions::new(), align)).is_err());
+        }
+    }
+
+    #[test]
+    fn instrumented_segments_count_accesses() {
+        let text = "long enough that it does not fit in the first segment";
+        let mut builder = message::Builder::new(
+            message::HeapAllocator::new()
+                .first_segment_words(1)
+                .allocation_strategy(message::AllocationStrategy::FixedSize));
+        builder.set_root(text).unwrap();
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &builder)).unwrap();
+
+        let (message, counts) = futures::executor::block_on(
+            read_message_instrumented(Cursor::new(&buf[..]), message::ReaderOptions::new())).unwrap().unwrap();
+        assert!(counts.to_vec().len() > 1);
+        assert_eq!(vec![0; counts.to_vec().len()], counts.to_vec());
+
+        assert_eq!(text, message.get_root::<capnp::text::Reader>().unwrap());
+        assert!(counts.get(0) > 0);
+        assert!(counts.get(1) > 0);
+        assert_eq!(0, counts.get(1000));
+    }
+
+    /// Fails with `kind` the first `failures` times that a read reaches `fail_at`.
+    struct TransientErrorRead<R> where R: Read {
+        read: R,
+        fail_at: usize,
+        failures: usize,
+        kind: io::ErrorKind,
+        idx: usize,
+    }
+
+    impl <R> AsyncRead for TransientErrorRead<R> where R: Read + Unpin {
+        fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            if self.idx == self.fail_at && self.failures > 0 {
+                self.failures -= 1;
+                return Poll::Ready(Err(io::Error::new(self.kind, "transient")))
+            }
+            let len = if self.idx < self.fail_at { cmp::min(self.fail_at - self.idx, buf.len()) }
+                      else { buf.len() };
+            let n = self.read.read(&mut buf[..len])?;
+            self.idx += n;
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    #[test]
+    fn read_with_retry_resumes_frame() {
+        use std::time::Duration;
+
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 3]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+
+        let policy = |e: &io::Error, attempt: u32| {
+            if e.kind() == io::ErrorKind::WouldBlock {
+                Some(Duration::from_millis(10 << attempt))
+            } else {
+                None
+            }
+        };
+
+        // WouldBlock twice in the middle of the body.
+        let reader = TransientErrorRead { read: &buf[..], fail_at: 12, failures: 2,
+                                          kind: io::ErrorKind::WouldBlock, idx: 0 };
+        let mut delays = Vec::new();
+        let message = futures::executor::block_on(read_message_with_retry(
+            reader, message::ReaderOptions::new(), policy,
+            |d| { delays.push(d); futures::future::ready(()) })).unwrap().unwrap();
+        assert_eq!(&segments[0][..], message.into_segments().get_segment(0).unwrap());
+        assert_eq!(vec![Duration::from_millis(10), Duration::from_millis(20)], delays);
+
+        // Errors that the policy does not retry are returned immediately.
+        let reader = TransientErrorRead { read: &buf[..], fail_at: 12, failures: 1,
+                                          kind: io::ErrorKind::PermissionDenied, idx: 0 };
+        let mut sleeps = 0;
+        let result = futures::executor::block_on(read_message_with_retry(
+            reader, message::ReaderOptions::new(), policy,
+            |_| { sleeps += 1; futures::future::ready(()) }));
+        assert!(result.is_err());
+        assert_eq!(0, sleeps);
+    }
+
+    #[test]
+    fn read_verbose_reports_limits() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 3],
+                            vec![capnp::word(2,0,0,0,0,0,0,0); 5]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+
+        let mut options = message::ReaderOptions::new();
+        options.traversal_limit_in_words(100);
+        let (message, effective) = futures::executor::block_on(
+            read_message_verbose(Cursor::new(&buf[..]), options)).unwrap().unwrap();
+        assert_eq!(100, effective.options.traversal_limit_in_words);
+        assert_eq!(2, effective.segment_table.segment_count());
+        assert_eq!(8, effective.total_words());
+        assert_eq!(92, effective.traversal_headroom());
+        assert_eq!(&segments[1][..], message.into_segments().get_segment(1).unwrap());
+
+        options.traversal_limit_in_words(7);
+        let error = futures::executor::block_on(
+            read_message_verbose(Cursor::new(&buf[..]), options)).err().expect("over the limit");
+        assert!(error.description.ends_with("(traversal_limit_in_words: 7, nesting_limit: 64)"),
+                "{}", error.description);
+    }
+
+    #[test]
+    fn checksum_segment_roundtrip() {
+        let text = "checked";
+        let mut builder = message::Builder::new_default();
+        builder.set_root(text).unwrap();
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message_with_checksum(&mut buf, &builder)).unwrap();
+
+        assert_eq!(0xcbf4_3926, crc32(0, b"123456789"));
+        for &verify in &[false, true] {
+            let message = futures::executor::block_on(read_message_with_checksum(
+                Cursor::new(&buf[..]), message::ReaderOptions::new(), verify)).unwrap().unwrap();
+            assert_eq!(text, message.get_root::<capnp::text::Reader>().unwrap());
+            let segments = message.into_segments();
+            assert_eq!(1, segments.len());
+            assert_eq!(builder.get_segments_for_output()[0].len(), segments.total_words());
+        }
+
+        // Readers that don't know about the checksum segment see the same message.
+        let mut cursor = Cursor::new(&buf[..]);
+        let message = futures::executor::block_on(
+            read_message(&mut cursor, message::ReaderOptions::new())).unwrap().unwrap();
+        assert_eq!(text, message.get_root::<capnp::text::Reader>().unwrap());
+        assert_eq!(buf.len() as u64, cursor.position());
+
+        // Checking is optional for messages without a checksum segment.
+        let mut plain = Vec::new();
+        futures::executor::block_on(write_message(&mut plain, &builder)).unwrap();
+        assert!(futures::executor::block_on(read_message_with_checksum(
+            Cursor::new(&plain[..]), message::ReaderOptions::new(), false)).unwrap().is_some());
+        assert!(futures::executor::block_on(read_message_with_checksum(
+            Cursor::new(&plain[..]), message::ReaderOptions::new(), true)).is_err());
+
+        // Corrupt the body. Without verification, the checksum segment might be data.
+        buf[20] ^= 1;
+        assert!(futures::executor::block_on(read_message_with_checksum(
+            Cursor::new(&buf[..]), message::ReaderOptions::new(), true)).is_err());
+        let message = futures::executor::block_on(read_message_with_checksum(
+            Cursor::new(&buf[..]), message::ReaderOptions::new(), false)).unwrap().unwrap();
+        assert_eq!(2, message.into_segments().len());
+    }
+
+    #[test]
+    fn checksum_segment_roundtrip_empty_message() {
+        let segments: Vec<Vec<Word>> = vec![];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message_with_checksum(&mut buf, &segments)).unwrap();
+        for &verify in &[false, true] {
+            let message = futures::executor::block_on(read_message_with_checksum(
+                Cursor::new(&buf[..]), message::ReaderOptions::new(), verify)).unwrap().unwrap();
+            let message_segments = message.into_segments();
+            assert_eq!(1, message_segments.len());
+            assert_eq!(0, message_segments.total_words());
+        }
+    }
+
+    #[test]
+    fn unverified_read_keeps_data_that_looks_like_a_checksum() {
+        // The last segment starts with the checksum tag, but is ordinary data.
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2],
+                            vec![capnp::word(0x43,0x52,0x43,0x31,5,6,7,8); 1]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        let message = futures::executor::block_on(read_message_with_checksum(
+            Cursor::new(&buf[..]), message::ReaderOptions::new(), false)).unwrap().unwrap();
+        let message_segments = message.into_segments();
+        assert_eq!(&segments[0][..], message_segments.get_segment(0).unwrap());
+        assert_eq!(&segments[1][..], message_segments.get_segment(1).unwrap());
+    }
+
+    #[test]
+    fn read_segments_only_returns_raw_segments() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 3],
+                            vec![capnp::word(2,0,0,0,0,0,0,0); 1]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+
+        let mut cursor = Cursor::new(&buf[..]);
+        let read = futures::executor::block_on(
+            read_segments_only(&mut cursor, message::ReaderOptions::new())).unwrap().unwrap();
+        assert_eq!(2, read.len());
+        assert_eq!(&segments[0][..], read.get_segment(0).unwrap());
+        assert_eq!(&segments[1][..], read.get_segment(1).unwrap());
+        assert!(futures::executor::block_on(
+            read_segments_only(&mut cursor, message::ReaderOptions::new())).unwrap().is_none());
+    }
+
+    #[test]
+    fn offset_aligned_frames() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2]];
+        let mut buf = vec![0xab; 3];
+        let mut pos = 3;
+        futures::executor::block_on(write_message_offset_aligned(&mut buf, &segments, &mut pos)).unwrap();
+        assert_eq!(8 + 8 + 16, pos);
+        futures::executor::block_on(write_message_offset_aligned(&mut buf, &segments, &mut pos)).unwrap();
+        assert_eq!(32 + 24, pos);
+        assert_eq!(pos as usize, buf.len());
+        assert_eq!(&[0, 0, 0, 0, 0][..], &buf[3..8]);
+
+        let mut cursor = Cursor::new(&buf[3..]);
+        let mut pos = 3;
+        for _ in 0..2 {
+            let message = futures::executor::block_on(
+                read_message_offset_aligned(&mut cursor, message::ReaderOptions::new(), &mut pos))
+                .unwrap().unwrap();
+            assert_eq!(&segments[0][..], message.into_segments().get_segment(0).unwrap());
+            assert_eq!(0, pos % 8);
+        }
+        assert!(futures::executor::block_on(
+            read_message_offset_aligned(&mut cursor, message::ReaderOptions::new(), &mut pos))
+                .unwrap().is_none());
+
+        // The stream ends after the padding, where a frame should start.
+        let mut pos = 3;
+        let error = futures::executor::block_on(
+            read_message_offset_aligned(Cursor::new(&buf[3..8]), message::ReaderOptions::new(), &mut pos))
+                .err().unwrap();
+        assert!(error.description.contains("Premature end"), "{}", error.description);
+        assert_eq!(8, pos);
+
+        buf[4] = 1;
+        let mut pos = 3;
+        assert!(futures::executor::block_on(
+            read_message_offset_aligned(Cursor::new(&buf[3..]), message::ReaderOptions::new(), &mut pos))
+                .is_err());
+    }
+
+    #[test]
+    fn mapped_errors() {
+        #[derive(Debug)]
+        enum AppError { Capnp(capnp::Error) }
+
+        impl From<capnp::Error> for AppError {
+            fn from(e: capnp::Error) -> AppError { AppError::Capnp(e) }
+        }
+
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message_mapped::<_, _, AppError>(&mut buf, &segments)).unwrap();
+        let message = futures::executor::block_on(
+            read_message_mapped::<_, AppError>(Cursor::new(&buf[..]), message::ReaderOptions::new()))
+            .unwrap().unwrap();
+        assert_eq!(&segments[0][..], message.into_segments().get_segment(0).unwrap());
+
+        match futures::executor::block_on(
+            read_message_mapped::<_, AppError>(Cursor::new(&buf[..5]), message::ReaderOptions::new())) {
+            Err(AppError::Capnp(e)) => assert_eq!(capnp::ErrorKind::Failed, e.kind),
+            Ok(_) => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn read_from_pool_reuses_blocks() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 3],
+                            vec![capnp::word(2,0,0,0,0,0,0,0); 2]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+
+        let pool = crate::SlabSegmentPool::new(8, 4);
+        let mut cursor = Cursor::new(&buf[..]);
+        let mut addresses = Vec::new();
+        for _ in 0..2 {
+            let message = futures::executor::block_on(
+                read_message_from_pool(&mut cursor, message::ReaderOptions::new(), &pool)).unwrap().unwrap();
+            let read = message.into_segments();
+            assert_eq!(&segments[1][..], read.get_segment(1).unwrap());
+            addresses.push(read.get_segment(0).unwrap().as_ptr());
+            pool.release(read);
+            assert_eq!(1, pool.free_blocks());
+        }
+        assert_eq!(addresses[0], addresses[1]);
+
+        // A truncated body gives the block back to the pool.
+        let mut cursor = Cursor::new(&buf[..buf.len() / 2 - 8]);
+        let pool = crate::SlabSegmentPool::new(8, 4);
+        assert!(futures::executor::block_on(
+            read_message_from_pool(&mut cursor, message::ReaderOptions::new(), &pool)).is_err());
+        assert_eq!(1, pool.free_blocks());
+    }
+
+    #[test]
+    fn write_hashed_hashes_frame_bytes() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::Hasher;
+
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 3],
+                            vec![capnp::word(2,0,0,0,0,0,0,0); 2]];
+        let mut buf = Vec::new();
+        let hash = futures::executor::block_on(
+            write_message_hashed(BlockingWrite::new(&mut buf, 5), &segments, DefaultHasher::new())).unwrap();
+
+        let mut hasher = DefaultHasher::new();
+        hasher.write(&buf);
+        assert_eq!(hasher.finish(), hash);
+    }
+
+    #[test]
+    fn reframe_multi_segment_message() {
+        let text = "long enough that it does not fit in the first segment";
+        let mut builder = message::Builder::new(
+            message::HeapAllocator::new()
+                .first_segment_words(1)
+                .allocation_strategy(message::AllocationStrategy::FixedSize));
+        builder.set_root(text).unwrap();
+        assert!(builder.get_segments_for_output().len() > 1);
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &builder)).unwrap();
+        let message = futures::executor::block_on(
+            read_message(Cursor::new(&buf[..]), message::ReaderOptions::new())).unwrap().unwrap();
+
+        let words = reframe_single_segment(&message).unwrap();
+        let mut reframed = Vec::new();
+        let segments: &[&[Word]] = &[&words[..]];
+        futures::executor::block_on(write_message(&mut reframed, segments)).unwrap();
+        let message = futures::executor::block_on(
+            read_message(Cursor::new(&reframed[..]), message::ReaderOptions::new())).unwrap().unwrap();
+        assert_eq!(text, message.get_root::<capnp::text::Reader>().unwrap());
+        assert_eq!(1, message.into_segments().len());
+    }
+
+    #[test]
+    fn truncated_body_error() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 3]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+
+        let error = futures::executor::block_on(
+            read_message(Cursor::new(&buf[..buf.len() - 5]), message::ReaderOptions::new())).err().unwrap();
+        assert!(is_truncated(&error), "{}", error.description);
+        assert!(error.description.ends_with("got 19 of 24 bytes (2 of 3 words)"), "{}", error.description);
+
+        // A truncated segment table is a different failure.
+        let error = futures::executor::block_on(
+            read_message(Cursor::new(&buf[..4]), message::ReaderOptions::new())).err().unwrap();
+        assert!(!is_truncated(&error));
+    }
+
+    #[test]
+    fn every_reader_reports_truncated_body() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 3]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        let cut = &buf[..buf.len() - 5];
+        let options = message::ReaderOptions::new();
+        let pool = crate::SlabSegmentPool::new(8, 4);
+
+        let errors = vec![
+            futures::executor::block_on(read_message_fair(Cursor::new(cut), options)).err(),
+            futures::executor::block_on(
+                read_message_with_progress(Cursor::new(cut), options, |_, _| ())).err(),
+            futures::executor::block_on(read_message_aligned(Cursor::new(cut), options, 64)).err(),
+            futures::executor::block_on(
+                read_message_striped(&mut [Cursor::new(cut)], options, |_| 0)).err(),
+            futures::executor::block_on(read_message_from_pool(Cursor::new(cut), options, &pool)).err(),
+            futures::executor::block_on(read_message_padded(Cursor::new(cut), options, 64)).err(),
+            futures::executor::block_on(
+                crate::MessageReader::new(Cursor::new(cut), options).read_message()).err(),
+            futures::executor::block_on(
+                crate::BufferedMessageReader::with_read_size(Cursor::new(cut), options, 8)
+                    .read_message()).err(),
+            futures::executor::block_on(
+                crate::BufferedMessageReader::new(Cursor::new(cut), options).peek_message()).err(),
+        ];
+        for error in errors {
+            let error = error.expect("truncated body");
+            assert!(is_truncated(&error), "{}", error.description);
+            assert!(error.description.contains("got 19 of 24 bytes"), "{}", error.description);
+        }
+    }
+
+    #[test]
+    fn write_large_segment_table_in_chunks() {
+        for &segment_count in &[63, 64, 100, 129, 511] {
+            let segments: Vec<Vec<Word>> =
+                (0..segment_count).map(|i| vec![capnp::word(i as u8,0,0,0,0,0,0,0); i % 3]).collect();
+            let mut buf = Vec::new();
+            futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+
+            let slices: Vec<&[Word]> = segments.iter().map(|s| &s[..]).collect();
+            let table = segment_table_bytes(&slices).unwrap();
+            assert_eq!(&table[..], &buf[..table.len()]);
+            let message = futures::executor::block_on(
+                read_message(Cursor::new(&buf[..]), message::ReaderOptions::new())).unwrap().unwrap();
+            assert_eq!(segment_count, message.into_segments().len());
+        }
+    }
 
-        buf.extend([1,0,0,0, // 2 segments
-                    1,0,0,0, // 1 length
-                    1,0,0,0, // 1 length
-                    0,0,0,0] // padding
-                    .iter().cloned());
-        let (words, segment_slices) = exec.run_until(read_segment_table(&mut Cursor::new(&buf[..]),
-                                                                        message::ReaderOptions::new())).unwrap().unwrap();
-        assert_eq!(2, words);
-        assert_eq!(vec![(0,1), (1, 2)], segment_slices);
-        buf.clear();
+    #[test]
+    fn write_canonical_emits_single_segment() {
+        let text = "long enough that it does not fit in the first segment";
+        let mut builder = message::Builder::new(
+            message::HeapAllocator::new()
+                .first_segment_words(1)
+                .allocation_strategy(message::AllocationStrategy::FixedSize));
+        builder.init_root::<capnp::any_pointer::Builder>().set_as(text).unwrap();
+        assert!(builder.get_segments_for_output().len() > 1);
 
-        buf.extend([2,0,0,0, // 3 segments
-                    1,0,0,0, // 1 length
-                    1,0,0,0, // 1 length
-                    0,1,0,0] // 256 length
-                    .iter().cloned());
-        let (words, segment_slices) = exec.run_until(read_segment_table(&mut Cursor::new(&buf[..]),
-                                                                        message::ReaderOptions::new())).unwrap().unwrap();
-        assert_eq!(258, words);
-        assert_eq!(vec![(0,1), (1, 2), (2, 258)], segment_slices);
-        buf.clear();
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message_canonical(&mut buf, &builder)).expect("writing");
+        assert_eq!(&[0, 0, 0, 0], &buf[..4]);
 
-        buf.extend([3,0,0,0,  // 4 segments
-                    77,0,0,0, // 77 length
-                    23,0,0,0, // 23 length
-                    1,0,0,0,  // 1 length
-                    99,0,0,0, // 99 length
-                    0,0,0,0]  // padding
-                    .iter().cloned());
-        let (words, segment_slices) = exec.run_until(read_segment_table(&mut Cursor::new(&buf[..]),
-                                                                        message::ReaderOptions::new())).unwrap().unwrap();
-        assert_eq!(200, words);
-        assert_eq!(vec![(0,77), (77, 100), (100, 101), (101, 200)], segment_slices);
-        buf.clear();
+        let message = futures::executor::block_on(read_message(Cursor::new(&buf[..]),
+                                                               message::ReaderOptions::new()))
+            .expect("reading").unwrap();
+        assert!(message.is_canonical().unwrap());
+        assert_eq!(text, message.get_root::<capnp::text::Reader>().unwrap());
     }
 
     #[test]
-    fn test_read_invalid_segment_table() {
-        let mut exec = futures::executor::LocalPool::new();
-        let mut buf = vec![];
+    fn write_borrowed_builder() {
+        let mut builder = message::Builder::new_default();
+        builder.init_root::<capnp::any_pointer::Builder>().set_as("borrowed").unwrap();
 
-        buf.extend([0,2,0,0].iter().cloned()); // 513 segments
-        buf.extend([0; 513 * 4].iter().cloned());
-        assert!(exec.run_until(read_segment_table(Cursor::new(&buf[..]),
-                                                  message::ReaderOptions::new())).is_err());
-        buf.clear();
+        let mut by_ref = Vec::new();
+        futures::executor::block_on(write_message(&mut by_ref, &builder)).expect("writing");
+        let mut by_mut = Vec::new();
+        futures::executor::block_on(write_message(&mut by_mut, &mut builder)).expect("writing");
+        assert_eq!(by_ref, by_mut);
+    }
 
-        buf.extend([0,0,0,0].iter().cloned()); // 1 segments
-        assert!(exec.run_until(read_segment_table(Cursor::new(&buf[..]),
-                                                  message::ReaderOptions::new())).is_err());
+    #[test]
+    fn idle_timeout_applies_only_before_first_byte() {
+        use std::time::Duration;
 
-        buf.clear();
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 3]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
 
-        buf.extend([0,0,0,0].iter().cloned()); // 1 segments
-        buf.extend([0; 3].iter().cloned());
-        assert!(exec.run_until(read_segment_table(Cursor::new(&buf[..]),
-                                                  message::ReaderOptions::new())).is_err());
-        buf.clear();
+        // The timer never fires, and the reader blocks after every byte.
+        let reader = BlockingRead::new(&buf[..], 1);
+        let message = futures::executor::block_on(read_message_idle_timeout(
+            reader, message::ReaderOptions::new(), Duration::from_secs(1),
+            |_| futures::future::pending::<()>())).expect("reading").unwrap();
+        assert_eq!(&segments[0][..], message.into_segments().get_segment(0).unwrap());
 
-        buf.extend([255,255,255,255].iter().cloned()); // 0 segments
-        assert!(exec.run_until(read_segment_table(Cursor::new(&buf[..]),
-                                                  message::ReaderOptions::new())).is_err());
-        buf.clear();
+        // The timer fires immediately, before the first byte arrives.
+        let reader = BlockingRead::new(&buf[..], 1);
+        let error = futures::executor::block_on(read_message_idle_timeout(
+            reader, message::ReaderOptions::new(), Duration::from_secs(1),
+            |_| futures::future::ready(()))).err().unwrap();
+        assert_eq!(capnp::ErrorKind::Overloaded, error.kind);
+
+        assert!(futures::executor::block_on(read_message_idle_timeout(
+            Cursor::new(&[][..]), message::ReaderOptions::new(), Duration::from_secs(1),
+            |_| futures::future::pending::<()>())).unwrap().is_none());
     }
 
-    fn construct_segment_table(segments: &[&[Word]]) -> Vec<u8> {
-        let mut exec = futures::executor::LocalPool::new();
-        let mut buf = vec![];
-        exec.run_until(super::write_segment_table(&mut buf, segments)).unwrap();
-        buf
+    #[test]
+    fn segment_table_from_lengths() {
+        let segment_0 = [capnp::word(1,0,0,0,0,0,0,0); 3];
+        let segment_1 = [capnp::word(2,0,0,0,0,0,0,0); 5];
+        let segment_2 = [capnp::word(3,0,0,0,0,0,0,0); 1];
+
+        // Write the table from lengths alone, then stream the bodies separately.
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_segment_table_for_lengths(&mut buf, &[3, 5, 1])).unwrap();
+        assert_eq!(construct_segment_table(&[&segment_0, &segment_1, &segment_2]), buf);
+        for segment in &[&segment_0[..], &segment_1[..], &segment_2[..]] {
+            buf.extend_from_slice(Word::words_to_bytes(segment));
+        }
+        let message = futures::executor::block_on(read_message(Cursor::new(&buf[..]),
+                                                               message::ReaderOptions::new()))
+            .unwrap().unwrap();
+        assert_eq!(&segment_1[..], message.into_segments().get_segment(1).unwrap());
+
+        let mut empty = Vec::new();
+        futures::executor::block_on(write_segment_table_for_lengths(&mut empty, &[])).unwrap();
+        assert_eq!(vec![0; 8], empty);
+    }
+
+    /// A writer that fails every write with `kind`.
+    struct FailingWrite {
+        kind: io::ErrorKind,
+    }
+
+    impl AsyncWrite for FailingWrite {
+        fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, _buf: &[u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(Err(io::Error::new(self.kind, "failing write")))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
     }
 
     #[test]
-    fn test_construct_segment_table() {
+    fn write_errors_keep_their_kind() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 1]];
+        for &(io_kind, kind) in &[(io::ErrorKind::BrokenPipe, capnp::ErrorKind::Disconnected),
+                                  (io::ErrorKind::TimedOut, capnp::ErrorKind::Overloaded),
+                                  (io::ErrorKind::Other, capnp::ErrorKind::Failed)] {
+            let error = futures::executor::block_on(
+                write_message(FailingWrite { kind: io_kind }, &segments)).err().unwrap();
+            assert_eq!(kind, error.kind);
+            assert!(error.description.contains("failing write"));
 
-        let segment_0: [Word; 0] = [];
-        let segment_1 = [capnp::word(1,0,0,0,0,0,0,0); 1];
-        let segment_199 = [capnp::word(199,0,0,0,0,0,0,0); 199];
+            let error = futures::executor::block_on(
+                MessageWriter::new(FailingWrite { kind: io_kind }, &segments)).err().unwrap();
+            assert_eq!(kind, error.kind);
+        }
+    }
 
-        let buf = construct_segment_table(&[&segment_0]);
-        assert_eq!(&[0,0,0,0,  // 1 segments
-                     0,0,0,0], // 0 length
-                   &buf[..]);
+    #[test]
+    fn read_all_typed_binds_each_root() {
+        let texts = ["first", "second"];
+        let mut buf = Vec::new();
+        for text in &texts {
+            let mut builder = message::Builder::new_default();
+            builder.init_root::<capnp::any_pointer::Builder>().set_as(*text).unwrap();
+            futures::executor::block_on(write_message(&mut buf, &builder)).expect("writing");
+        }
 
-        let buf = construct_segment_table(&[&segment_1]);
-        assert_eq!(&[0,0,0,0,  // 1 segments
-                     1,0,0,0], // 1 length
-                   &buf[..]);
+        let read = futures::executor::block_on(
+            read_all_typed::<_, capnp::text::Owned>(Cursor::new(&buf[..]), message::ReaderOptions::new()))
+            .expect("reading");
+        let read: Vec<&str> = read.iter().map(|m| m.get().unwrap()).collect();
+        assert_eq!(&texts[..], &read[..]);
 
-        let buf = construct_segment_table(&[&segment_199]);
-        assert_eq!(&[0,0,0,0,    // 1 segments
-                     199,0,0,0], // 199 length
-                   &buf[..]);
+        // A data root has no NUL terminator, so it can't be read as text.
+        let mut builder = message::Builder::new_default();
+        builder.init_root::<capnp::any_pointer::Builder>()
+            .set_as::<capnp::data::Builder, _>(&b"abc"[..]).unwrap();
+        futures::executor::block_on(write_message(&mut buf, &builder)).expect("writing");
+        let error = futures::executor::block_on(
+            read_all_typed::<_, capnp::text::Owned>(Cursor::new(&buf[..]), message::ReaderOptions::new()))
+            .err().unwrap();
+        assert!(error.description.starts_with("Message 2:"), "{}", error.description);
+    }
 
-        let buf = construct_segment_table(&[&segment_0, &segment_1]);
-        assert_eq!(&[1,0,0,0,  // 2 segments
-                     0,0,0,0,  // 0 length
-                     1,0,0,0,  // 1 length
-                     0,0,0,0], // padding
-                   &buf[..]);
+    #[test]
+    fn compare_output_segments() {
+        let a = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2], vec![capnp::word(2,0,0,0,0,0,0,0); 1]];
+        let b = a.clone();
+        assert!(output_segments_equal(&a, &b));
 
-        let buf = construct_segment_table(&[&segment_199, &segment_1, &segment_199, &segment_0]);
-        assert_eq!(&[3,0,0,0,   // 4 segments
-                     199,0,0,0, // 199 length
-                     1,0,0,0,   // 1 length
-                     199,0,0,0, // 199 length
-                     0,0,0,0,   // 0 length
-                     0,0,0,0],  // padding
-                   &buf[..]);
+        let mut c = a.clone();
+        c[1][0] = capnp::word(3,0,0,0,0,0,0,0);
+        assert!(!output_segments_equal(&a, &c));
 
-        let buf = construct_segment_table(
-            &[&segment_199, &segment_1, &segment_199, &segment_0, &segment_1]);
-        assert_eq!(&[4,0,0,0,   // 5 segments
-                     199,0,0,0, // 199 length
-                     1,0,0,0,   // 1 length
-                     199,0,0,0, // 199 length
-                     0,0,0,0,   // 0 length
-                     1,0,0,0],  // 1 length
-                   &buf[..]);
+        // Same words, different segment boundaries.
+        let d = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2]];
+        let e = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 1], vec![capnp::word(1,0,0,0,0,0,0,0); 1]];
+        assert!(!output_segments_equal(&d, &e));
     }
 
-    impl AsOutputSegments for Vec<Vec<Word>> {
+    #[test]
+    fn u32_prefixed_round_trip() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2], vec![capnp::word(2,0,0,0,0,0,0,0); 1]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message_u32_prefixed(&mut buf, &segments)).unwrap();
+        assert_eq!(&[40, 0, 0, 0], &buf[..4]);
+        assert_eq!(44, buf.len());
+
+        let mut cursor = Cursor::new(&buf[..]);
+        let message = futures::executor::block_on(
+            read_message_u32_prefixed(&mut cursor, message::ReaderOptions::new())).unwrap().unwrap();
+        let message_segments = message.into_segments();
+        assert_eq!(&segments[0][..], message_segments.get_segment(0).unwrap());
+        assert_eq!(&segments[1][..], message_segments.get_segment(1).unwrap());
+        assert!(futures::executor::block_on(
+            read_message_u32_prefixed(&mut cursor, message::ReaderOptions::new())).unwrap().is_none());
+
+        buf[0] = 39;
+        assert!(futures::executor::block_on(
+            read_message_u32_prefixed(Cursor::new(&buf[..]), message::ReaderOptions::new())).is_err());
+    }
+
+    /// A message with no segments at all.
+    struct NoSegments;
+
+    impl AsOutputSegments for NoSegments {
         fn as_output_segments<'a>(&'a self) -> OutputSegments<'a> {
-            if self.len() == 0 {
-                OutputSegments::SingleSegment([&[]])
-            } else if self.len() == 1 {
-                OutputSegments::SingleSegment([&self[0][..]])
-            } else {
-                OutputSegments::MultiSegment(self.iter()
-                                             .map(|segment| &segment[..])
-                                             .collect::<Vec<_>>())
-            }
+            OutputSegments::MultiSegment(Vec::new())
         }
     }
 
-    /// Wraps a `Read` instance and introduces blocking.
-    struct BlockingRead<R> where R: Read {
-        /// The wrapped reader
-        read: R,
+    #[test]
+    fn write_message_with_no_segments() {
+        let expected = [0u8; 8];
 
-        /// Number of bytes to read before blocking
-        frequency: usize,
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, NoSegments)).unwrap();
+        assert_eq!(&expected[..], &buf[..]);
 
-        /// Number of bytes read since last blocking
-        idx: usize,
-    }
+        let mut sync_buf = Vec::new();
+        write_message_sync(&mut sync_buf, NoSegments).unwrap();
+        assert_eq!(&expected[..], &sync_buf[..]);
 
-    impl <R> BlockingRead<R> where R: Read {
-        fn new(read: R, frequency: usize) -> BlockingRead<R> {
-            BlockingRead { read: read, frequency: frequency, idx: 0 }
-        }
+        let mut writer_buf = Vec::new();
+        futures::executor::block_on(MessageWriter::new(&mut writer_buf, NoSegments)).unwrap();
+        assert_eq!(&expected[..], &writer_buf[..]);
+
+        let mut varint_buf = Vec::new();
+        futures::executor::block_on(write_message_varint_prefixed(&mut varint_buf, NoSegments)).unwrap();
+        assert!(futures::executor::block_on(
+            read_message_varint_prefixed(Cursor::new(&varint_buf[..]), message::ReaderOptions::new()))
+                .unwrap().is_some());
+
+        let message = futures::executor::block_on(read_message(Cursor::new(&buf[..]),
+                                                               message::ReaderOptions::new()))
+            .unwrap().unwrap();
+        let segments = message.into_segments();
+        assert_eq!(0, segments.get_segment(0).unwrap().len());
+        assert!(segments.get_segment(1).is_none());
+
+        let empty: Vec<Vec<Word>> = Vec::new();
+        assert!(output_segments_equal(NoSegments, &empty));
     }
 
-    impl <R> AsyncRead for BlockingRead<R> where R: Read + Unpin {
-        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
-            if self.idx == 0 {
-                self.idx = self.frequency;
-                cx.waker().clone().wake();
-                Poll::Pending
-            } else {
-                let len = cmp::min(self.idx, buf.len());
-                let bytes_read = match self.read.read(&mut buf[..len]) {
-                    Err(e) => return Poll::Ready(Err(e)),
-                    Ok(n) => n,
-                };
-                self.idx -= bytes_read;
-                Poll::Ready(Ok(bytes_read))
+    #[test]
+    fn transform_body_but_not_table() {
+        fn xor(bytes: &mut [u8]) -> capnp::Result<()> {
+            for byte in bytes.iter_mut() {
+                *byte ^= 0x5a;
             }
+            Ok(())
         }
+
+        let segments = vec![vec![capnp::word(1,2,3,4,5,6,7,8); 2], vec![capnp::word(9,0,0,0,0,0,0,0); 1]];
+        let mut plain = Vec::new();
+        futures::executor::block_on(write_message(&mut plain, &segments)).unwrap();
+        let mut encrypted = Vec::new();
+        futures::executor::block_on(write_message_with_transform(&mut encrypted, &segments, xor)).unwrap();
+
+        assert_eq!(plain.len(), encrypted.len());
+        assert_eq!(&plain[..16], &encrypted[..16]);
+        assert_eq!(plain[16] ^ 0x5a, encrypted[16]);
+
+        let message = futures::executor::block_on(
+            read_message_with_transform(Cursor::new(&encrypted[..]), message::ReaderOptions::new(), xor))
+            .unwrap().unwrap();
+        let message_segments = message.into_segments();
+        assert_eq!(&segments[0][..], message_segments.get_segment(0).unwrap());
+        assert_eq!(&segments[1][..], message_segments.get_segment(1).unwrap());
     }
 
-    /// Wraps a `Write` instance and introduces blocking.
-    struct BlockingWrite<W> where W: Write {
-        /// The wrapped writer
-        writer: W,
+    #[cfg(feature = "debug-roundtrip")]
+    #[test]
+    fn debug_roundtrip_writes_same_bytes() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2], vec![capnp::word(2,0,0,0,0,0,0,0); 1]];
+        let mut checked = Vec::new();
+        futures::executor::block_on(write_message(&mut checked, &segments)).unwrap();
+        let mut unchecked = Vec::new();
+        write_message_sync(&mut unchecked, &segments).unwrap();
+        assert_eq!(unchecked, checked);
+    }
 
-        /// Number of bytes to write before blocking
-        frequency: usize,
+    #[test]
+    fn flush_between_segments() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2], vec![capnp::word(2,0,0,0,0,0,0,0); 1]];
+        let mut expected = Vec::new();
+        futures::executor::block_on(write_message(&mut expected, &segments)).expect("writing");
 
-        /// Number of bytes written since last blocking
-        idx: usize,
+        let mut writer = RecordingWrite::default();
+        futures::executor::block_on(
+            write_message_with_framing(&mut writer, &segments, WriteFraming::new())).expect("writing");
+        assert_eq!(expected, writer.bytes);
+        assert!(!writer.events.contains(&WriteEvent::Flush));
+
+        let mut writer = RecordingWrite::default();
+        futures::executor::block_on(write_message_with_framing(
+            &mut writer, &segments, *WriteFraming::new().flush_between_segments(true))).expect("writing");
+        assert_eq!(expected, writer.bytes);
+        assert_eq!(vec![WriteEvent::Write(16), WriteEvent::Flush,
+                        WriteEvent::Write(16), WriteEvent::Flush,
+                        WriteEvent::Write(8), WriteEvent::Flush],
+                   writer.events);
     }
 
-    impl <W> BlockingWrite<W> where W: Write {
-        fn new(writer: W, frequency: usize) -> BlockingWrite<W> {
-            BlockingWrite { writer: writer, frequency: frequency, idx: 0 }
+    #[test]
+    fn max_segments() {
+        let segments: Vec<Vec<Word>> = (0..5).map(|_| vec![capnp::word(1,0,0,0,0,0,0,0); 1]).collect();
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+
+        assert!(futures::executor::block_on(read_message_with_framing(
+            Cursor::new(&buf[..]), message::ReaderOptions::new(),
+            *ReadFraming::new().max_segments(5))).unwrap().is_some());
+
+        // Only the first word of the segment table should be consumed.
+        let mut cursor = Cursor::new(&buf[..]);
+        assert!(futures::executor::block_on(read_message_with_framing(
+            &mut cursor, message::ReaderOptions::new(),
+            *ReadFraming::new().max_segments(4))).is_err());
+        assert_eq!(8, cursor.position());
+    }
+
+    #[test]
+    fn read_message_reusing_keeps_allocation() {
+        let messages = vec![vec![vec![capnp::word(1,0,0,0,0,0,0,0); 8]],
+                            vec![vec![capnp::word(2,0,0,0,0,0,0,0); 2], vec![capnp::word(3,0,0,0,0,0,0,0); 3]]];
+        let mut buf = Vec::new();
+        for segments in &messages {
+            futures::executor::block_on(write_message(&mut buf, segments)).unwrap();
         }
-        fn into_writer(self) -> W {
-            self.writer
+
+        let mut cursor = Cursor::new(&buf[..]);
+        let mut recycled = OwnedSegments::default();
+        let mut buffer_ptr = None;
+        for segments in &messages {
+            let message = futures::executor::block_on(
+                read_message_reusing(&mut cursor, message::ReaderOptions::new(), recycled))
+                .unwrap().unwrap();
+            recycled = message.into_segments();
+            for (idx, segment) in segments.iter().enumerate() {
+                assert_eq!(&segment[..], recycled.get_segment(idx as u32).unwrap());
+            }
+            assert!(recycled.get_segment(segments.len() as u32).is_none());
+
+            // The second message is smaller, so it fits in the first one's buffer.
+            let ptr = recycled.get_segment(0).unwrap().as_ptr();
+            assert_eq!(*buffer_ptr.get_or_insert(ptr), ptr);
         }
+        assert!(futures::executor::block_on(
+            read_message_reusing(&mut cursor, message::ReaderOptions::new(), recycled)).unwrap().is_none());
     }
 
-    impl <W> AsyncWrite for BlockingWrite<W> where W: Write + Unpin {
-        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
-            if self.idx == 0 {
-                self.idx = self.frequency;
-                cx.waker().clone().wake();
-                Poll::Pending
-            } else {
-                let len = cmp::min(self.idx, buf.len());
-                let bytes_written = match self.writer.write(&buf[..len]) {
-                    Err(e) => return Poll::Ready(Err(e)),
-                    Ok(n) => n,
-                };
-                self.idx -= bytes_written;
-                Poll::Ready(Ok(bytes_written))
+    #[test]
+    fn write_messages_at_offsets() {
+        let first = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2]];
+        let second = vec![vec![capnp::word(2,0,0,0,0,0,0,0); 1], vec![capnp::word(3,0,0,0,0,0,0,0); 1]];
+
+        // Write the second message first, leaving room for the first.
+        let mut file = Cursor::new(Vec::new());
+        let second_len = futures::executor::block_on(write_message_at(&mut file, 24, &second)).unwrap();
+        assert_eq!(32, second_len);
+        let first_len = futures::executor::block_on(write_message_at(&mut file, 0, &first)).unwrap();
+        assert_eq!(24, first_len);
+
+        let buf = file.into_inner();
+        assert_eq!(56, buf.len());
+        let mut cursor = Cursor::new(&buf[..]);
+        for segments in &[first, second] {
+            let message = futures::executor::block_on(read_message(&mut cursor, message::ReaderOptions::new()))
+                .unwrap().unwrap();
+            let message_segments = message.into_segments();
+            for (idx, segment) in segments.iter().enumerate() {
+                assert_eq!(&segment[..], message_segments.get_segment(idx as u32).unwrap());
             }
         }
-        fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
-            Poll::Ready(self.writer.flush())
-        }
+    }
 
-        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
-            Poll::Ready(Ok(()))
+    #[test]
+    fn total_words_bounds() {
+        let mut buf = Vec::new();
+        for &len in &[2, 3, 4] {
+            futures::executor::block_on(
+                write_message(&mut buf, &vec![vec![capnp::word(1,0,0,0,0,0,0,0); len]])).unwrap();
         }
+
+        let mut framing = ReadFraming::new();
+        framing.min_total_words(3).max_total_words(3);
+        let mut cursor = Cursor::new(&buf[..]);
+        let error = futures::executor::block_on(read_message_with_framing(
+            &mut cursor, message::ReaderOptions::new(), framing)).err().unwrap();
+        assert!(error.description.contains("has 2 words"), "{}", error.description);
+
+        // Skip the body of the rejected message.
+        cursor.set_position(24);
+        assert!(futures::executor::block_on(read_message_with_framing(
+            &mut cursor, message::ReaderOptions::new(), framing)).unwrap().is_some());
+        let error = futures::executor::block_on(read_message_with_framing(
+            &mut cursor, message::ReaderOptions::new(), framing)).err().unwrap();
+        assert!(error.description.contains("has 4 words"), "{}", error.description);
     }
 
     #[test]
-    fn check_round_trip_async() {
-        fn round_trip(read_block_frequency: usize,
-                      write_block_frequency: usize,
-                      segments: Vec<Vec<Word>>) -> TestResult
-        {
-            if segments.len() == 0 || read_block_frequency == 0 || write_block_frequency == 0 {
-                return TestResult::discard();
-            }
+    fn empty_message() {
+        let segments: Vec<Vec<Word>> = vec![Vec::new()];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message(&mut buf, &segments)).unwrap();
+        assert_eq!(8, buf.len());
 
-            let (mut read, segments) = {
-                let cursor = std::io::Cursor::new(Vec::new());
-                let mut writer = BlockingWrite::new(cursor, write_block_frequency);
-                futures::executor::block_on(Box::pin(write_message(&mut writer, &segments))).expect("writing");
+        // By default, an empty message round-trips.
+        let message = futures::executor::block_on(
+            read_message(Cursor::new(&buf[..]), message::ReaderOptions::new())).unwrap().unwrap();
+        assert_eq!(0, message.into_segments().get_segment(0).unwrap().len());
 
-                let mut cursor = writer.into_writer();
-                cursor.set_position(0);
-                (BlockingRead::new(cursor, read_block_frequency), segments)
-            };
+        assert!(futures::executor::block_on(read_message_with_framing(
+            Cursor::new(&buf[..]), message::ReaderOptions::new(),
+            *ReadFraming::new().reject_empty_message(true))).is_err());
+    }
 
-            let message =
-                futures::executor::block_on(Box::pin(read_message(&mut read, Default::default()))).expect("reading").unwrap();
-            let message_segments = message.into_segments();
+    #[test]
+    fn bundle_round_trip() {
+        let messages = vec![vec![vec![capnp::word(1,0,0,0,0,0,0,0); 1]],
+                            vec![vec![capnp::word(2,0,0,0,0,0,0,0); 2], vec![capnp::word(3,0,0,0,0,0,0,0); 1]]];
+        let mut buf = Vec::new();
+        futures::executor::block_on(write_message_bundle(&mut buf, &messages)).unwrap();
+        assert_eq!(&[2, 0, 0, 0], &buf[..4]);
 
-            TestResult::from_bool(segments.iter().enumerate().all(|(i, segment)| {
-                &segment[..] == message_segments.get_segment(i as u32).unwrap()
-            }))
+        let mut cursor = Cursor::new(&buf[..]);
+        let bundle = futures::executor::block_on(
+            read_message_bundle(&mut cursor, message::ReaderOptions::new())).unwrap().unwrap();
+        assert_eq!(messages.len(), bundle.len());
+        for (message, segments) in bundle.into_iter().zip(messages.iter()) {
+            let message_segments = message.into_segments();
+            for (idx, segment) in segments.iter().enumerate() {
+                assert_eq!(&segment[..], message_segments.get_segment(idx as u32).unwrap());
+            }
         }
+        assert!(futures::executor::block_on(
+            read_message_bundle(&mut cursor, message::ReaderOptions::new())).unwrap().is_none());
 
-        quickcheck(round_trip as fn(usize, usize, Vec<Vec<Word>>) -> TestResult);
+        buf[0] = 3;
+        assert!(futures::executor::block_on(
+            read_message_bundle(Cursor::new(&buf[..]), message::ReaderOptions::new())).is_err());
     }
 }
-
@@ -0,0 +1,116 @@
+// Copyright (c) 2013-2016 Sandstorm Development Group, Inc. and contributors
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+use std::sync::Mutex;
+
+use capnp::Word;
+
+use crate::serialize::OwnedSegments;
+
+/// A pool of fixed-size message buffers, recycled through a free list, for servers that read
+/// many small messages. See `serialize::read_message_from_pool()`.
+///
+/// Every buffer that the pool hands out for a message of at most `block_words` words has a
+/// capacity of exactly `block_words` words. Once released, such a buffer is kept for reuse,
+/// so that in a steady state reading a message needs no allocation for its body. Larger
+/// messages get buffers of their own, which are freed normally when released.
+#[derive(Debug)]
+pub struct SlabSegmentPool {
+    block_words: usize,
+
+    /// The maximum number of released blocks to keep in `free`.
+    max_free_blocks: usize,
+
+    free: Mutex<Vec<Vec<Word>>>,
+}
+
+impl SlabSegmentPool {
+    /// Creates a pool of blocks of `block_words` words, which keeps at most `max_free_blocks`
+    /// released blocks for reuse. No blocks are allocated up front.
+    pub fn new(block_words: usize, max_free_blocks: usize) -> Self {
+        SlabSegmentPool {
+            block_words: block_words,
+            max_free_blocks: max_free_blocks,
+            free: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The size of each block, in words.
+    pub fn block_words(&self) -> usize {
+        self.block_words
+    }
+
+    /// The number of released blocks that are waiting to be reused.
+    pub fn free_blocks(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+
+    /// Returns empty segments whose buffer can hold `total_words` words without reallocating.
+    /// If `total_words` fits in a block, the buffer is a free block, or a newly allocated one
+    /// if none is free.
+    pub fn acquire(&self, total_words: usize) -> OwnedSegments {
+        let space = if total_words <= self.block_words {
+            match self.free.lock().unwrap().pop() {
+                Some(block) => block,
+                None => Vec::with_capacity(self.block_words),
+            }
+        } else {
+            Vec::with_capacity(total_words)
+        };
+        OwnedSegments::new(space, Vec::new())
+    }
+
+    /// Gives the buffer of `segments` back to the pool. It is kept for reuse if it is one of
+    /// the pool's blocks and fewer than `max_free_blocks` blocks are free; otherwise it is freed.
+    pub fn release(&self, segments: OwnedSegments) {
+        let mut space = segments.into_owned_space();
+        if space.capacity() != self.block_words {
+            return
+        }
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.max_free_blocks {
+            space.clear();
+            free.push(space);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SlabSegmentPool;
+
+    #[test]
+    fn recycles_blocks() {
+        let pool = SlabSegmentPool::new(16, 1);
+        let a = pool.acquire(10);
+        let b = pool.acquire(16);
+        let big = pool.acquire(17);
+        pool.release(a);
+        pool.release(b);
+        pool.release(big);
+        assert_eq!(1, pool.free_blocks());
+
+        let c = pool.acquire(3);
+        assert_eq!(0, pool.free_blocks());
+        assert_eq!(0, c.total_words());
+        pool.release(c);
+        assert_eq!(1, pool.free_blocks());
+    }
+}
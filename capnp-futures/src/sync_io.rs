@@ -0,0 +1,103 @@
+// Copyright (c) 2013-2016 Sandstorm Development Group, Inc. and contributors
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::{AsyncRead, AsyncWrite};
+
+/// Wraps a synchronous `std::io::Read` and/or `std::io::Write`, such as a
+/// `std::io::Cursor<Vec<u8>>`, as an `AsyncRead` and/or `AsyncWrite` whose operations are
+/// always ready.
+///
+/// Each operation calls straight through to the wrapped object, so this is only suitable for
+/// objects that never block, like in-memory buffers in tests. Wrapping a socket or a pipe would
+/// block the executor.
+pub struct AsyncCursor<T> {
+    inner: T,
+}
+
+/// Wraps `inner` as an `AsyncCursor`.
+pub fn sync_to_async<T>(inner: T) -> AsyncCursor<T> {
+    AsyncCursor { inner: inner }
+}
+
+impl <T> AsyncCursor<T> {
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl <T> Unpin for AsyncCursor<T> {}
+
+impl <T> AsyncRead for AsyncCursor<T> where T: Read {
+    fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.inner.read(buf))
+    }
+}
+
+impl <T> AsyncWrite for AsyncCursor<T> where T: Write {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.inner.write(buf))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(self.inner.flush())
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(self.inner.flush())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use capnp::message;
+    use capnp::message::ReaderSegments;
+
+    use crate::serialize::{read_message, write_message};
+    use super::sync_to_async;
+
+    #[test]
+    fn round_trip_through_std_cursor() {
+        let segments = vec![vec![capnp::word(1,0,0,0,0,0,0,0); 2]];
+        let mut writer = sync_to_async(::std::io::Cursor::new(Vec::new()));
+        futures::executor::block_on(write_message(&mut writer, &segments)).unwrap();
+
+        let mut cursor = writer.into_inner();
+        cursor.set_position(0);
+        let mut reader = sync_to_async(cursor);
+        let message = futures::executor::block_on(read_message(&mut reader, message::ReaderOptions::new()))
+            .unwrap().unwrap();
+        assert_eq!(&segments[0][..], message.into_segments().get_segment(0).unwrap());
+        assert!(futures::executor::block_on(read_message(&mut reader, message::ReaderOptions::new()))
+                .unwrap().is_none());
+    }
+}
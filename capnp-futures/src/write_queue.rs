@@ -24,7 +24,7 @@ use futures::{AsyncWrite, AsyncWriteExt, StreamExt, TryFutureExt};
 
 use capnp::{Error};
 
-use crate::serialize::{AsOutputSegments};
+use crate::serialize::{AsOutputSegments, MessageWriter};
 
 enum Item<M> where M: AsOutputSegments {
     Message(M, oneshot::Sender<M>),
@@ -42,6 +42,8 @@ impl <M> Clone for Sender<M> where M: AsOutputSegments {
 }
 
 /// Creates a new WriteQueue that wraps the given writer.
+///
+/// The returned future is `Send` if `W` and `M` are `Send`.
 pub fn write_queue<W, M>(mut writer: W) -> (Sender<M>, impl Future<Output=Result<(),Error>> + 'static)
     where W: AsyncWrite + Unpin + 'static, M: AsOutputSegments + 'static
 {
@@ -53,7 +55,11 @@ pub fn write_queue<W, M>(mut writer: W) -> (Sender<M>, impl Future<Output=Result
         while let Some(item) = rx.next().await {
             match item {
                 Item::Message(m, returner) => {
-                    crate::serialize::write_message(&mut writer, &m).await?;
+                    // Hand `m` to the MessageWriter by value rather than by reference, so that
+                    // this future is `Send` whenever `M` is, even if `M` is not `Sync`.
+                    let mut message_writer = MessageWriter::new(&mut writer, m);
+                    (&mut message_writer).await?;
+                    let (_, m) = message_writer.into_inner();
                     writer.flush().await?;
                     let _ = returner.send(m);
                 }
@@ -0,0 +1,20 @@
+use capnp::message;
+use capnp_futures::serialize::{read_message, write_message, MessageWriter};
+use futures::io::Cursor;
+
+fn assert_send<T: Send>(_: T) {}
+
+#[test]
+fn futures_are_send() {
+    let mut builder = message::Builder::new_default();
+    builder.init_root::<capnp::any_pointer::Builder>();
+    assert_send(read_message(Cursor::new(Vec::<u8>::new()), message::ReaderOptions::new()));
+    assert_send(write_message(Vec::<u8>::new(), builder));
+
+    let mut builder = message::Builder::new_default();
+    builder.init_root::<capnp::any_pointer::Builder>();
+    assert_send(MessageWriter::new(Vec::<u8>::new(), builder));
+
+    let (_, queue) = capnp_futures::write_queue::<_, message::Builder<message::HeapAllocator>>(Vec::<u8>::new());
+    assert_send(queue);
+}